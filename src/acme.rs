@@ -1,18 +1,15 @@
 use crate::*;
 
 use async_recursion::async_recursion;
-use chrono::{Months, prelude::*};
-use hyperacme::{
-    Account, Certificate, Directory, DirectoryUrl, Error,
-    api::ApiProblem,
-    create_p384_key,
-    order::{CsrOrder, NewOrder},
-};
+use chrono::prelude::*;
+use hyperacme::{Directory, DirectoryUrl, Error, api::ApiIdentifier, create_p384_key};
 use openssl::{
-    ec::EcKey,
+    ec::{EcGroup, EcKey},
+    nid::Nid,
     pkey::{PKey, Private},
+    x509::X509,
 };
-use std::{os::unix::fs::PermissionsExt, path::Path};
+use std::{net::IpAddr, os::unix::fs::PermissionsExt, path::Path};
 use tokio::{
     fs::File,
     io::AsyncWriteExt,
@@ -20,176 +17,110 @@ use tokio::{
 };
 
 
-#[instrument(skip(config))]
-pub async fn get_cert(config: &Config, domain: &str) -> Result<(), Error> {
-    request_certificate(config, domain, false, 1).await
-}
-
-
-#[instrument(skip(config))]
-pub async fn get_cert_wildcard(config: &Config, domain: &str) -> Result<(), Error> {
-    request_certificate(config, domain, true, 1).await
+#[instrument(skip(config, dns_backends))]
+pub async fn get_cert(
+    config: &Config,
+    dns_backends: &DnsBackends,
+    domain: &str,
+) -> Result<Renewal, Error> {
+    let renewal = request_certificate(config, dns_backends, domain, false, 1).await?;
+    match staple_ocsp_response(config, dns_backends, domain, false).await? {
+        Renewal::Renewed => Ok(Renewal::Renewed),
+        Renewal::Skipped => Ok(renewal),
+    }
 }
 
 
-#[instrument(skip(config, ord_new, domain))]
-#[async_recursion]
-async fn await_csr(
+#[instrument(skip(config, dns_backends))]
+pub async fn get_cert_wildcard(
     config: &Config,
-    mut ord_new: NewOrder,
+    dns_backends: &DnsBackends,
     domain: &str,
-    status: &str,
-    order_attempts: usize,
-) -> Result<CsrOrder, Error> {
-    if let Some(ord_csr) = ord_new.confirm_validations().await {
-        info!("Order confirmed.");
-        return Ok(ord_csr);
+) -> Result<Renewal, Error> {
+    let renewal = request_certificate(config, dns_backends, domain, true, 1).await?;
+    match staple_ocsp_response(config, dns_backends, domain, true).await? {
+        Renewal::Renewed => Ok(Renewal::Renewed),
+        Renewal::Skipped => Ok(renewal),
     }
+}
 
-    if order_attempts > DEFAULT_MAX_ATTEMPTS {
-        let api_problem = ApiProblem {
-            detail: Some(format!(
-                "Failed to order a Certificate within the {DEFAULT_MAX_ATTEMPTS} max confirmation attempts."
-            )),
-            subproblems: None,
-            _type: String::from("ApiProblem"),
-        };
-        return Err(Error::ApiProblem(api_problem));
-    }
 
-    if status == "pending" {
-        info!("Awaiting");
-        sleep(Duration::from_millis(DEFAULT_ACME_POLL_PAUSE_MS)).await;
-        ord_new.refresh().await?;
-        return await_csr(config, ord_new, domain, status, order_attempts + 1).await;
-    }
+#[instrument(skip(config, ip_account))]
+pub async fn get_cert_ip(config: &Config, ip_account: &IpAccount) -> Result<Renewal, Error> {
+    request_ip_certificate(config, ip_account, 1).await
+}
 
-    // Get the possible authorizations
-    let auths = ord_new.authorizations().await?;
-    let auth = &auths[0]; // only a single wildcard per domain
-    if auth.need_challenge().await {
-        info!("Pending the domain registration");
-        match auth.dns_challenge().await {
-            Some(challenge) => {
-                debug!("Deleting any previous DNS entries for domain: {domain}");
-                match delete_acme_dns_txt_entries(config, domain).await {
-                    Ok(_) => info!("DNS TXT record destroyed for domain: {domain}"),
-                    Err(err) => {
-                        let error_msg =
-                            &format!("Failed to destroy DNS TXT record. Error: {err:?}");
-                        error!(error_msg);
-                        notify_failure(config, domain, error_msg)
-                            .await
-                            .unwrap_or_default();
-                    }
-                }
-
-                let proof_code = challenge.dns_proof().await?;
-                match create_txt_record(config, domain, &proof_code).await {
-                    Ok(_) => info!("DNS TXT record created for domain: {domain}"),
-                    Err(err) => {
-                        let error_msg =
-                            &format!("Failed to create DNS TXT record. Error: {err:?}");
-                        error!(error_msg);
-                        notify_failure(config, domain, error_msg)
-                            .await
-                            .unwrap_or_default();
-                    }
-                }
-                ord_new.refresh().await?;
-
-                info!(
-                    "Waiting {}s",
-                    DEFAULT_ACME_CHALLENGE_VALIDATION_PAUSE_MS / 1000
-                );
-                tokio::time::sleep(Duration::from_millis(
-                    DEFAULT_ACME_CHALLENGE_VALIDATION_PAUSE_MS,
-                ))
-                .await;
-                ord_new.refresh().await?;
-
-                // The order at ACME will change status to either
-                // confirm ownership of the domain, or fail due to the
-                // not finding the proof. To see the change, we poll
-                // the API with pause between.
-                match challenge
-                    .validate(Duration::from_millis(
-                        DEFAULT_ACME_CHALLENGE_VALIDATION_PAUSE_MS,
-                    ))
-                    .await
-                {
-                    Ok(_) => {
-                        info!("Challenge validated.");
-                    }
-                    Err(e) => {
-                        error!("Failed validation. Error {e:?}");
-                    }
-                }
-                ord_new.refresh().await?;
-
-                // delete the DNS TXT _acme entries
-                match delete_acme_dns_txt_entries(config, domain).await {
-                    Ok(_) => info!("DNS TXT record destroyed for domain: {domain}"),
-                    Err(err) => {
-                        let error_msg =
-                            &format!("Failed to destroy DNS TXT record. Error: {err:?}");
-                        error!(error_msg);
-                        notify_failure(config, domain, error_msg)
-                            .await
-                            .unwrap_or_default();
-                    }
-                }
-            }
-            None => {
-                error!("Challenge is None!")
-            }
-        }
+
+/// Directory holding the key and certificates of the domain (or its wildcard)
+pub fn domain_dir_of(domain: &str, wildcard: bool) -> String {
+    if wildcard {
+        format!("wild_{domain}")
     } else {
-        info!("Challenge not required.");
-        ord_new.refresh().await?;
+        domain.to_string()
     }
+}
 
-    let status = &auth
-        .api_auth()
-        .await
-        .to_owned()
-        .status
-        .unwrap_or("unknown".to_string());
-    info!("Order status: {status:?}");
-
-    if status == "invalid" {
-        let api_problem = ApiProblem{
-            detail: Some("Invalid status means that something went wrong with the LE API. Will try again later.".to_string()),
-            subproblems: None,
-            _type: String::from("ApiProblem")
-        };
-        return Err(Error::ApiProblem(api_problem));
-    }
 
-    // Call recursively until we get what we want
-    await_csr(config, ord_new, domain, status, order_attempts + 1).await
+/// Refreshes the OCSP response of the current certificate.
+/// Reissues the certificate if the responder reports it as revoked.
+#[instrument(skip(config, dns_backends, domain, wildcard))]
+async fn staple_ocsp_response(
+    config: &Config,
+    dns_backends: &DnsBackends,
+    domain: &str,
+    wildcard: bool,
+) -> Result<Renewal, Error> {
+    let domain_dir = domain_dir_of(domain, wildcard);
+    match refresh_ocsp_response(&domain_dir).await {
+        Ok(OcspState::Revoked) => {
+            retire_revoked_certificate(&domain_dir)
+                .await
+                .map_err(|err| Error::GeneralError(err.to_string()))?;
+            notify_revoked(config, domain, wildcard)
+                .await
+                .unwrap_or_default();
+            let renewal =
+                request_certificate(config, dns_backends, domain, wildcard, 1).await?;
+            if let Err(err) = refresh_ocsp_response(&domain_dir).await {
+                error!("Failed to fetch the OCSP response of the reissued certificate: {err}");
+            }
+            return Ok(renewal);
+        }
+        Ok(_) => (),
+        Err(err) => {
+            let error_msg = &format!("Failed to refresh the OCSP response. Error: {err}");
+            error!(error_msg);
+            notify_failure(config, domain, error_msg)
+                .await
+                .unwrap_or_default();
+            remove_stale_ocsp_response(&domain_dir)
+                .await
+                .unwrap_or_else(|err| {
+                    error!("Failed to remove the stale OCSP response: {err}")
+                });
+        }
+    }
+    Ok(Renewal::Skipped)
 }
 
 
-#[instrument(skip(dir))]
-async fn load_or_generate_new_account(
-    contact: &Vec<String>,
-    dir: &Directory,
-) -> Result<Account, Error> {
-    let account_key_file_name = "account.key";
+/// Reads the account key (EC P-256 or RSA), or generates a new EC P-256 one.
+/// The AcmeSession registers the account of the new key.
+#[instrument]
+async fn load_or_generate_account_key(account_key_file_name: &str) -> Result<String, Error> {
     if Path::new(account_key_file_name).exists() {
         info!("Account key is present.");
-        let account_str = tokio::fs::read_to_string(account_key_file_name).await?;
-        dir.load_account(&account_str, contact.to_owned()).await
+        Ok(tokio::fs::read_to_string(account_key_file_name).await?)
     } else {
         info!("No account key present. Registering new account.");
-        let new_account = dir.register_account(contact.to_owned()).await?;
-
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let account_key_pem =
+            String::from_utf8(EcKey::generate(&group)?.private_key_to_pem()?)
+                .map_err(|err| Error::GeneralError(err.to_string()))?;
         let mut account_file = File::create(account_key_file_name).await?;
-        let pkey = new_account.acme_private_key_pem().await?;
-        account_file.write_all(pkey.as_bytes()).await?;
+        account_file.write_all(account_key_pem.as_bytes()).await?;
         set_private_key_permissions(account_key_file_name).await?;
-        Ok(new_account)
+        Ok(account_key_pem)
     }
 }
 
@@ -220,50 +151,290 @@ async fn load_or_generate_domain_key(
         Ok(new_pkey)
     } else {
         info!("Using previously known {domain_dir}/domain.key");
+        // any key type: the imported (e.g. certbot RSA) keys are reused as they are
         let pkey_str = tokio::fs::read_to_string(domain_key_filename).await?;
-        let ec_key: EcKey<Private> = EcKey::private_key_from_pem(pkey_str.as_bytes())?;
-        Ok(PKey::from_ec_key(ec_key)?)
+        Ok(PKey::private_key_from_pem(pkey_str.as_bytes())?)
+    }
+}
+
+
+/// Reads the domain key, or generates a new one in place of the existing key when the
+/// key rotation is requested. The rotated key isn't stored until its certificate is.
+#[instrument(skip(config, domain_dir))]
+async fn load_or_rotate_domain_key(
+    config: &Config,
+    domain_key_filename: &str,
+    domain_dir: &str,
+) -> Result<(PKey<Private>, bool), Error> {
+    if config.rotate_domain_key().await && Path::new(domain_key_filename).exists() {
+        info!("Rotating the {domain_key_filename}");
+        return Ok((create_p384_key()?, true));
+    }
+    let domain_key = load_or_generate_domain_key(domain_key_filename, domain_dir).await?;
+    Ok((domain_key, false))
+}
+
+
+/// Stores the rotated domain key, keeping a dated copy of the previous one
+/// next to the dated copy of the previous certificate
+#[instrument(skip(domain_key))]
+async fn store_domain_key(
+    domain_key_filename: &str,
+    domain_key: &PKey<Private>,
+) -> Result<(), Error> {
+    let today_date = Local::now().date_naive();
+    if Path::new(domain_key_filename).exists() {
+        info!("Making a copy of the previous key to: {domain_key_filename}-{today_date}");
+        tokio::fs::copy(
+            domain_key_filename,
+            format!("{domain_key_filename}-{today_date}"),
+        )
+        .await?;
     }
+    let mut domain_key_file = File::create(domain_key_filename).await?;
+    domain_key_file
+        .write_all(&domain_key.private_key_to_pem_pkcs8()?)
+        .await?;
+    set_private_key_permissions(domain_key_filename).await?;
+    Ok(())
 }
 
 
 #[instrument]
-async fn read_certificate_expiry_date(
+async fn read_certificate_renewal_due(
     chained_certifcate_file_name: &str,
-    domain_key: &PKey<Private>,
-) -> Result<DateTime<Utc>, Error> {
-    let pkey_string = String::from_utf8(domain_key.private_key_to_pem_pkcs8()?)?;
-    let current_cert_read = Certificate::parse(
-        pkey_string,
-        tokio::fs::read_to_string(chained_certifcate_file_name).await?,
-    )?;
-    current_cert_read.expiry()
+) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+    let current_cert_read =
+        X509::from_pem(&tokio::fs::read(chained_certifcate_file_name).await?)?;
+    let not_before =
+        parse_asn1_time(&current_cert_read.not_before().to_string()).map_err(general_error)?;
+    let not_after =
+        parse_asn1_time(&current_cert_read.not_after().to_string()).map_err(general_error)?;
+    Ok((not_after, renewal_due_of(not_before, not_after)))
+}
+
+
+/// Checks if the current certificate is valid long enough to skip the renewal
+#[instrument]
+async fn is_certificate_fresh(chained_certifcate_file: &str) -> Result<bool, Error> {
+    if !Path::new(chained_certifcate_file).exists() {
+        return Ok(false);
+    }
+    info!("Previous certificate exists: {chained_certifcate_file}.");
+    let (expiry_date, renewal_due) =
+        read_certificate_renewal_due(chained_certifcate_file).await?;
+    if Utc::now() < renewal_due {
+        info!("Certificate expires at: {expiry_date}. No need to renew until: {renewal_due}.");
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+
+/// Stores the new certificate, keeping a dated copy of the previous one
+#[instrument(skip(certificate))]
+async fn store_certificate(
+    chained_certifcate_file: &str,
+    certificate: &str,
+) -> Result<(), Error> {
+    let today_date = Local::now().date_naive();
+    if Path::new(&chained_certifcate_file).exists() {
+        info!(
+            "Making a copy of the previous certificate to: {chained_certifcate_file}-{today_date}"
+        );
+        tokio::fs::copy(
+            &chained_certifcate_file,
+            format!("{}-{}", &chained_certifcate_file, today_date),
+        )
+        .await?;
+    }
+
+    let mut cert_file = File::create(chained_certifcate_file).await?;
+    cert_file.write_all(certificate.as_bytes()).await?;
+    Ok(())
 }
 
 
-// Order a new TLS certificate for a domain.
-#[instrument(skip(account, domain, wildcard))]
-async fn create_new_order(
-    account: &Account,
+/// Verifies the SCTs of the newly issued (not yet stored) certificate, if the CT policy
+/// is configured. The certificates that don't meet the policy are reported, and aren't
+/// deployed.
+#[instrument(skip(config, certificate))]
+async fn check_certificate_transparency(
+    config: &Config,
+    name: &str,
+    certificate: &str,
+) -> Result<(), Error> {
+    let Some(policy) = config.certificate_transparency().await else {
+        return Ok(());
+    };
+    let verified = verify_certificate_transparency(&policy, certificate.as_bytes()).await;
+    let error_msg = match verified {
+        Ok(report) if report.is_compliant(&policy) => {
+            info!(
+                "Certificate of: {name} is logged in {} distinct CT logs: {:?}",
+                report.logs.len(),
+                report.logs
+            );
+            return Ok(());
+        }
+        Ok(report) => {
+            format!(
+                "Certificate of: {name} has valid SCTs from {} distinct CT logs, {} required. Logs: {:?}. Problems: {:?}. Not deploying it.",
+                report.logs.len(),
+                policy.min_distinct_logs,
+                report.logs,
+                report.problems
+            )
+        }
+        Err(err) => {
+            format!(
+                "Couldn't verify the SCTs of the certificate of: {name}: {err}. Not deploying it."
+            )
+        }
+    };
+    error!(error_msg);
+    notify_failure(config, name, &error_msg)
+        .await
+        .unwrap_or_default();
+    Err(Error::GeneralError(error_msg))
+}
+
+
+fn general_error(err: anyhow::Error) -> Error {
+    Error::GeneralError(err.to_string())
+}
+
+
+// Order a new TLS certificate for the names, validated with the DNS-01 challenges,
+// and finalize it with the DER encoded CSR (built from the domain.key, or external).
+#[instrument(skip(config, dns, session, csr_der))]
+async fn issue_dns_certificate(
+    config: &Config,
+    dns: &DnsBackend,
+    session: &AcmeSession,
     domain: &str,
-    wildcard: bool,
-) -> Result<NewOrder, Error> {
-    if wildcard {
-        account.new_order(&format!("*.{domain}"), &[]).await
-    } else {
-        account.new_order(domain, &[]).await
+    names: &[String],
+    csr_der: &[u8],
+) -> Result<String> {
+    let identifiers = names
+        .iter()
+        .map(|name| {
+            ApiIdentifier {
+                _type: String::from("dns"),
+                value: name.to_owned(),
+            }
+        })
+        .collect::<Vec<ApiIdentifier>>();
+    let (order_url, order) = session.new_order(&identifiers, None).await?;
+
+    let mut challenges = vec![];
+    for authorization_url in order.authorizations.unwrap_or_default() {
+        let authorization = session.authorization(&authorization_url).await?;
+        if authorization.is_status_valid() {
+            info!(
+                "Challenge not required for: {}.",
+                authorization.identifier.value
+            );
+            continue;
+        }
+        let challenge = authorization.dns_challenge().ok_or_else(|| {
+            anyhow!(
+                "ACME provider didn't offer the dns-01 challenge for: {}",
+                authorization.identifier.value
+            )
+        })?;
+        challenges.push((
+            authorization_url.to_owned(),
+            authorization.identifier.value.to_owned(),
+            challenge.to_owned(),
+        ));
+    }
+
+    if !challenges.is_empty() {
+        let resolver = config.resolver().await;
+        for (_, name, _) in challenges.iter() {
+            if let Err(err) = dns.delegations().follow_cname(&resolver, name).await {
+                warn!("Couldn't follow the CNAME of the challenge record of: {name}: {err}");
+            }
+        }
+        let validated = async {
+            for (_, name, challenge) in challenges.iter() {
+                let proof_code = session.dns_proof(&challenge.token)?;
+                dns.present(domain, name, &proof_code).await?;
+                info!("DNS TXT record created for: {name}");
+            }
+            info!(
+                "Waiting {}s",
+                DEFAULT_ACME_CHALLENGE_VALIDATION_PAUSE_MS / 1000
+            );
+            sleep(Duration::from_millis(
+                DEFAULT_ACME_CHALLENGE_VALIDATION_PAUSE_MS,
+            ))
+            .await;
+            for (authorization_url, _, challenge) in challenges.iter() {
+                session.respond_to_challenge(&challenge.url).await?;
+                session.await_authorization(authorization_url).await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+
+        // delete the DNS TXT _acme entries
+        match dns.cleanup(domain).await {
+            Ok(_) => info!("DNS TXT record destroyed for domain: {domain}"),
+            Err(err) => {
+                let error_msg = &format!("Failed to destroy DNS TXT record. Error: {err:?}");
+                error!(error_msg);
+                notify_failure(config, domain, error_msg)
+                    .await
+                    .unwrap_or_default();
+            }
+        }
+        validated?;
+    }
+
+    let order = session.await_order_ready(&order_url).await?;
+    session.finalize(&order_url, &order, csr_der).await
+}
+
+
+/// What the order of the domain gets finalized with, chosen before the order is created
+enum CertificateKey {
+    /// The domain.key of the domain dir, the CSR is built from it
+    DomainKey(PKey<Private>),
+
+    /// The externally generated CSR, its private key never leaves the device
+    ExternalCsr(ExternalCsr),
+}
+
+
+impl CertificateKey {
+    /// The names to order and the DER encoded CSR to finalize the order with
+    fn order_of(&self, domain: &str, wildcard: bool) -> Result<(Vec<String>, Vec<u8>)> {
+        match self {
+            CertificateKey::DomainKey(domain_key) => {
+                let names = match wildcard {
+                    true => vec![format!("*.{domain}")],
+                    false => vec![domain.to_string()],
+                };
+                let csr_der = create_csr(domain_key, &names)?;
+                Ok((names, csr_der))
+            }
+            CertificateKey::ExternalCsr(csr) => Ok((csr.names.to_owned(), csr.der.to_owned())),
+        }
     }
 }
 
 
 #[async_recursion]
-#[instrument(skip(config, domain, attempts))]
+#[instrument(skip(config, dns_backends, domain, attempts))]
 async fn request_certificate(
     config: &Config,
+    dns_backends: &DnsBackends,
     domain: &str,
     wildcard: bool,
     attempts: usize,
-) -> Result<(), Error> {
+) -> Result<Renewal, Error> {
     if attempts > DEFAULT_MAX_ATTEMPTS {
         let err = format!(
             "Reached max retry attempts: {DEFAULT_MAX_ATTEMPTS}. Check the API credentials."
@@ -285,92 +456,236 @@ async fn request_certificate(
         .await
         .iter()
         .map(|contact| format!("mailto:{contact}"))
-        .collect();
+        .collect::<Vec<String>>();
 
-    // Generate a account.key if doesn't exist and register an account with your ACME provider:
-    let account = load_or_generate_new_account(&contacts, &dir).await?;
+    // Generate a account.key if doesn't exist, the account gets registered with the session:
+    let account_key_file = config.account_key_file().await;
+    let account_key_pem = load_or_generate_account_key(&account_key_file).await?;
 
-    let domain_dir = if wildcard {
-        format!("wild_{domain}")
-    } else {
-        domain.to_string()
-    };
+    let domain_dir = domain_dir_of(domain, wildcard);
     tokio::fs::create_dir_all(&domain_dir).await?;
 
-    // Read a domain private key or create new for the certificate:
+    // The external CSR replaces the domain.key of the domain (never of its wildcard):
     let domain_key_filename = format!("{domain_dir}/domain.key");
-    let domain_key = load_or_generate_domain_key(&domain_key_filename, &domain_dir).await?;
+    let mut rotated_key = None;
+    let certificate_key = match config.csr_of(domain).await {
+        Some(csr_file) if !wildcard => {
+            info!("Using the external CSR: {csr_file}");
+            let csr = load_csr(&csr_file).await.map_err(general_error)?;
+            validate_csr_names(&csr.names, domain).map_err(general_error)?;
+            CertificateKey::ExternalCsr(csr)
+        }
+        _ => {
+            // Read a domain private key or create new for the certificate:
+            let (domain_key, rotated) =
+                load_or_rotate_domain_key(config, &domain_key_filename, &domain_dir).await?;
+            rotated_key = rotated.then(|| domain_key.clone());
+            CertificateKey::DomainKey(domain_key)
+        }
+    };
 
     // check if the current Certificate is fresh enough
-    let today = Local::now();
     let chained_certifcate_file = format!("{domain_dir}/chained.pem");
-    if Path::new(&chained_certifcate_file).exists() {
-        info!("Previous certificate exists: {chained_certifcate_file}.");
-        let expiry_date =
-            read_certificate_expiry_date(&chained_certifcate_file, &domain_key).await?;
-        let today_plus_n_months = today + Months::new(DEFAULT_MAX_CERT_VALIDITY_IN_MONTHS);
-        if today_plus_n_months < expiry_date {
-            info!("Certificate expires at: {expiry_date}. No need to renew.");
-            return Ok(());
-        }
+    if config.force_renewal().await {
+        info!("Forced renewal of: {chained_certifcate_file}.");
+    } else if is_certificate_fresh(&chained_certifcate_file).await? {
+        return Ok(Renewal::Skipped);
     }
 
-    let ord_new = create_new_order(&account, domain, wildcard)
-        .await
-        .map(|ord_new| await_csr(config, ord_new, domain, "", 1))?
-        .await;
+    // The DNS backend of the account, with its secrets resolved just once
+    let dns = dns_backends.of(domain).await.map_err(general_error)?;
+    let (names, csr_der) = certificate_key
+        .order_of(domain, wildcard)
+        .map_err(general_error)?;
 
     // If the ownership of the domain(s) have already been
     // authorized in a previous order, you might be able to
     // skip validation. The ACME API provider decides.
-    let error_pause = sleep(Duration::from_millis(DEFAULT_ACME_INVALID_STATUS_PAUSE_MS));
-    let ord_csr = match ord_new {
-        Ok(order) => order,
-        Err(Error::ApiProblem(api_problem)) => {
-            let problem = api_problem.detail.unwrap_or_default();
-            warn!("Waiting 30s to retry (attempts: {attempts}). API problem: {problem:?}");
-            error_pause.await;
-            return request_certificate(config, domain, wildcard, attempts + 1).await;
-        }
+    let issued = async {
+        let session = AcmeSession::new(&dir, &account_key_pem, &contacts).await?;
+        issue_dns_certificate(config, dns, &session, domain, &names, &csr_der).await
+    }
+    .await;
+    let certificate = match issued {
+        Ok(certificate) => certificate,
         Err(err) => {
-            warn!("Unhandled error: {err:?}. Waiting 30s to retry (attempts: {attempts})");
-            error_pause.await;
-            return request_certificate(config, domain, wildcard, attempts + 1).await;
+            warn!("Error: {err}. Waiting 30s to retry (attempts: {attempts})");
+            sleep(Duration::from_millis(DEFAULT_ACME_INVALID_STATUS_PAUSE_MS)).await;
+            return request_certificate(config, dns_backends, domain, wildcard, attempts + 1)
+                .await;
         }
     };
 
-    // Submit the CSR. This causes the ACME provider to enter a
-    // state of "processing" that must be polled until the
-    // certificate is either issued or rejected. Again we poll
-    // for the status change.
-    let ord_cert = ord_csr
-        .finalize_pkey(
-            domain_key.to_owned(),
-            Duration::from_millis(DEFAULT_ACME_POLL_PAUSE_MS),
-        )
+    let name = match wildcard {
+        true => format!("*.{domain}"),
+        false => domain.to_string(),
+    };
+    check_certificate_transparency(config, &name, &certificate).await?;
+    if let Some(domain_key) = rotated_key {
+        store_domain_key(&domain_key_filename, &domain_key).await?;
+    }
+    store_certificate(&chained_certifcate_file, &certificate).await?;
+    // The certificate is renewed even if some of its deployments fail, so they all
+    // get reported with the single success notification
+    let mut warnings = vec![];
+    if let Err(err) = write_keystores(config, domain, wildcard).await {
+        warnings.push(format!("Failed to write the keystores of: {name}: {err}."));
+    }
+    if let Err(err) = write_kubernetes_secret(config, domain, wildcard).await {
+        warnings.push(format!(
+            "Failed to write the Kubernetes Secret of: {name}: {err}."
+        ));
+    }
+    if let Err(err) =
+        upload_cloudflare_certificate(config, dns_backends, domain, wildcard).await
+    {
+        warnings.push(format!(
+            "Failed to upload the certificate of: {name} to the CloudFlare: {err}."
+        ));
+    }
+    for warning in warnings.iter() {
+        error!("{warning}");
+    }
+
+    notify_success(config, domain, wildcard, &warnings)
+        .await
+        .unwrap_or_default();
+
+    info!("Ready");
+    Ok(Renewal::Renewed)
+}
+
+
+// Order a new TLS certificate for the IP address (RFC 8738). The hyperacme only
+// knows the "dns" identifiers, so the whole order goes through the AcmeSession.
+#[instrument(skip(dir, account_key_pem, contacts, domain_key))]
+async fn issue_ip_certificate(
+    dir: &Directory,
+    account_key_pem: &str,
+    contacts: &[String],
+    ip_account: &IpAccount,
+    ip: &str,
+    domain_key: &PKey<Private>,
+) -> Result<String> {
+    let session = AcmeSession::new(dir, account_key_pem, contacts).await?;
+    let identifier = ApiIdentifier {
+        _type: String::from("ip"),
+        value: ip.to_string(),
+    };
+    // the IP address certificates are issued just with the short-lived profile
+    let (order_url, order) = session
+        .new_order(&[identifier], Some(DEFAULT_IP_CERTIFICATE_PROFILE))
         .await?;
 
-    let today_date = today.date_naive();
-    if Path::new(&chained_certifcate_file).exists() {
-        info!(
-            "Making a copy of the previous certificate to: {chained_certifcate_file}-{today_date}"
-        );
-        tokio::fs::copy(
-            &chained_certifcate_file,
-            format!("{}-{}", &chained_certifcate_file, today_date),
+    let challenge_type = ip_account.validation.challenge_type();
+    for authorization_url in order.authorizations.unwrap_or_default() {
+        let authorization = session.authorization(&authorization_url).await?;
+        if authorization.is_status_valid() {
+            info!("Challenge not required.");
+            continue;
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge._type == challenge_type)
+            .ok_or_else(|| {
+                anyhow!("ACME provider didn't offer the {challenge_type} challenge")
+            })?;
+        let key_authorization = session.key_authorization(&challenge.token)?;
+        let _responder = ChallengeResponder::start(
+            &ip_account.validation,
+            ip,
+            &challenge.token,
+            &key_authorization,
         )
         .await?;
+        session.respond_to_challenge(&challenge.url).await?;
+        session.await_authorization(&authorization_url).await?;
     }
 
-    // Now download the certificate. Also stores the cert persistently.
-    let cert = ord_cert.download_cert().await?;
-    let mut cert_file = File::create(chained_certifcate_file.to_owned()).await?;
-    cert_file.write_all(cert.certificate().as_bytes()).await?;
+    let order = session.await_order_ready(&order_url).await?;
+    let csr = create_csr(domain_key, &[ip.to_string()])?;
+    session.finalize(&order_url, &order, &csr).await
+}
+
 
-    notify_success(config, domain, wildcard)
+#[async_recursion]
+#[instrument(skip(config, ip_account, attempts))]
+async fn request_ip_certificate(
+    config: &Config,
+    ip_account: &IpAccount,
+    attempts: usize,
+) -> Result<Renewal, Error> {
+    if attempts > DEFAULT_MAX_ATTEMPTS {
+        let err = format!(
+            "Reached max retry attempts: {DEFAULT_MAX_ATTEMPTS}. Check the challenge listener."
+        );
+        error!("{err}");
+        return Err(hyperacme::Error::GeneralError(err));
+    }
+    // canonical textual form, as the ACME provider will report it back
+    let ip = ip_account
+        .ip
+        .parse::<IpAddr>()
+        .map_err(|err| {
+            Error::GeneralError(format!("Invalid IP address: {}: {err}", ip_account.ip))
+        })?
+        .to_string();
+
+    let url = match config.acme_staging().await {
+        true => DirectoryUrl::LetsEncryptStaging,
+        _ => DirectoryUrl::LetsEncrypt,
+    };
+    info!("Using LE url: {url:?}");
+    let dir = Directory::from_url(url).await?;
+    let contacts = ip_account
+        .contacts
+        .iter()
+        .map(|contact| format!("mailto:{contact}"))
+        .collect::<Vec<String>>();
+    let account_key_file = config.account_key_file().await;
+    let account_key_pem = load_or_generate_account_key(&account_key_file).await?;
+
+    let domain_dir = domain_dir_of(&ip, false);
+    tokio::fs::create_dir_all(&domain_dir).await?;
+    let domain_key_filename = format!("{domain_dir}/domain.key");
+    let (domain_key, rotated) =
+        load_or_rotate_domain_key(config, &domain_key_filename, &domain_dir).await?;
+
+    let chained_certifcate_file = format!("{domain_dir}/chained.pem");
+    if config.force_renewal().await {
+        info!("Forced renewal of: {chained_certifcate_file}.");
+    } else if is_certificate_fresh(&chained_certifcate_file).await? {
+        return Ok(Renewal::Skipped);
+    }
+
+    let certificate = match issue_ip_certificate(
+        &dir,
+        &account_key_pem,
+        &contacts,
+        ip_account,
+        &ip,
+        &domain_key,
+    )
+    .await
+    {
+        Ok(certificate) => certificate,
+        Err(err) => {
+            warn!("Error: {err}. Waiting 30s to retry (attempts: {attempts})");
+            sleep(Duration::from_millis(DEFAULT_ACME_INVALID_STATUS_PAUSE_MS)).await;
+            return request_ip_certificate(config, ip_account, attempts + 1).await;
+        }
+    };
+    check_certificate_transparency(config, &ip, &certificate).await?;
+    if rotated {
+        store_domain_key(&domain_key_filename, &domain_key).await?;
+    }
+    store_certificate(&chained_certifcate_file, &certificate).await?;
+
+    notify_success(config, &ip, false, &[])
         .await
         .unwrap_or_default();
 
     info!("Ready");
-    Ok(())
+    Ok(Renewal::Renewed)
 }
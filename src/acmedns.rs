@@ -0,0 +1,286 @@
+use crate::*;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, os::unix::fs::PermissionsExt, sync::Mutex};
+use tokio::io::AsyncWriteExt;
+
+
+/// The acme-dns registration of the single "_acme-challenge" name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcmeDnsAccount {
+    pub username: String,
+    pub password: String,
+    pub fulldomain: String,
+    pub subdomain: String,
+}
+
+
+/// DNS provider updating the acme-dns server (https://github.com/joohoi/acme-dns).
+/// The "_acme-challenge" name of every domain is registered on the first use and
+/// has to be delegated with the CNAME to its full domain at the acme-dns server.
+#[derive(Debug)]
+pub struct AcmeDns {
+    server_url: String,
+
+    /// JSON file with the registrations, by the "_acme-challenge" name
+    credentials_file: String,
+
+    /// CIDR ranges allowed to update the registrations
+    allow_from: Vec<String>,
+
+    /// Values updated by the provider: (domain, full domain at the acme-dns, value)
+    updated_records: Mutex<Vec<(String, String, String)>>,
+    delegations: ChallengeDelegations,
+}
+
+
+impl AcmeDns {
+    pub fn new(server_url: &str, credentials_file: &str, allow_from: &[String]) -> Self {
+        AcmeDns {
+            server_url: server_url.trim_end_matches('/').to_string(),
+            credentials_file: credentials_file.to_string(),
+            allow_from: allow_from.to_vec(),
+            updated_records: Mutex::new(vec![]),
+            delegations: ChallengeDelegations::default(),
+        }
+    }
+
+
+    #[instrument(skip(self))]
+    pub async fn load_accounts(&self) -> Result<BTreeMap<String, AcmeDnsAccount>> {
+        match tokio::fs::read_to_string(&self.credentials_file).await {
+            Ok(accounts) => Ok(serde_json::from_str(&accounts)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(err) => {
+                Err(anyhow!(
+                    "Couldn't read the acme-dns credentials: {}: {err}",
+                    self.credentials_file
+                ))
+            }
+        }
+    }
+
+
+    /// Stores the registrations, readable by the owner only
+    #[instrument(skip(self, accounts))]
+    async fn save_accounts(&self, accounts: &BTreeMap<String, AcmeDnsAccount>) -> Result<()> {
+        let mut credentials_file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.credentials_file)
+            .await?;
+        // the mode applies to the new files only
+        credentials_file
+            .set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+        credentials_file
+            .write_all(serde_json::to_string_pretty(accounts)?.as_bytes())
+            .await?;
+        credentials_file.flush().await?;
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn register(&self) -> Result<AcmeDnsAccount> {
+        let mut request = reqwest::Client::new().post(format!("{}/register", self.server_url));
+        if !self.allow_from.is_empty() {
+            request = request.json(&json!({ "allowfrom": self.allow_from }));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let response = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "acme-dns registration failed with: {status}: {response}"
+            ));
+        }
+        Ok(serde_json::from_str(&response)?)
+    }
+
+
+    #[instrument(skip(self, account))]
+    async fn update(&self, account: &AcmeDnsAccount, content: &str) -> Result<()> {
+        let response = reqwest::Client::new()
+            .post(format!("{}/update", self.server_url))
+            .header("X-Api-User", &account.username)
+            .header("X-Api-Key", &account.password)
+            .json(&json!({ "subdomain": account.subdomain, "txt": content }))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "acme-dns update of: {} failed with: {status}: {}",
+                account.fulldomain,
+                response.text().await?
+            ));
+        }
+        Ok(())
+    }
+}
+
+
+impl DnsProvider for AcmeDns {
+    fn delegations(&self) -> &ChallengeDelegations {
+        &self.delegations
+    }
+
+
+    #[instrument(skip(self))]
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        let challenge_name = challenge_record_name(name);
+        let mut accounts = self.load_accounts().await?;
+        let Some(account) = accounts.get(&challenge_name).cloned() else {
+            let account = self.register().await?;
+            accounts.insert(challenge_name.to_owned(), account.to_owned());
+            self.save_accounts(&accounts).await?;
+            return Err(anyhow!(
+                "Registered: {challenge_name} at the acme-dns. Create the DNS record: \"{challenge_name}. CNAME {}.\" and renew again",
+                account.fulldomain
+            ));
+        };
+        if !names_equal(
+            &self.delegations.present_name_of(domain, name),
+            &account.fulldomain,
+        ) {
+            warn!(
+                "The {challenge_name} doesn't look like a CNAME to: {}. The validation fails until it is.",
+                account.fulldomain
+            );
+        }
+
+        self.update(&account, content).await?;
+        self.updated_records
+            .lock()
+            .map_err(|err| anyhow!("Updated records poisoned: {err}"))?
+            .push((domain.to_string(), account.fulldomain, content.to_string()));
+        Ok(())
+    }
+
+
+    /// The acme-dns keeps just the two latest values of every name, so there's nothing to delete
+    #[instrument(skip(self))]
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        self.updated_records
+            .lock()
+            .map_err(|err| anyhow!("Updated records poisoned: {err}"))?
+            .retain(|(updated_domain, ..)| updated_domain != domain);
+        Ok(())
+    }
+
+
+    /// The values updated by the provider (the acme-dns API can't list them)
+    #[instrument(skip(self))]
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        Ok(self
+            .updated_records
+            .lock()
+            .map_err(|err| anyhow!("Updated records poisoned: {err}"))?
+            .iter()
+            .filter(|(updated_domain, ..)| updated_domain == domain)
+            .map(|(_, fulldomain, content)| {
+                ChallengeRecord {
+                    id: format!("{fulldomain} {content}"),
+                    name: fulldomain.to_owned(),
+                    content: content.to_owned(),
+                }
+            })
+            .collect())
+    }
+}
+
+
+#[tokio::test]
+async fn test_acme_dns_provider() -> Result<()> {
+    use std::sync::Arc;
+
+    // the mock acme-dns: the updated (subdomain, txt) values
+    let updates: Arc<Mutex<Vec<(String, String)>>> = Arc::new(Mutex::new(vec![]));
+    let updated = updates.clone();
+    let server = mock_http_server(move |request| {
+        let (status, response) = match request.path() {
+            "/register" => {
+                (
+                    "201 Created",
+                    json!({
+                        "username": "the-user",
+                        "password": "the-password",
+                        "fulldomain": "d420c923.auth.acme-dns.example",
+                        "subdomain": "d420c923",
+                        "allowfrom": [],
+                    }),
+                )
+            }
+            "/update"
+                if request.header("x-api-user") == "the-user"
+                    && request.header("x-api-key") == "the-password" =>
+            {
+                let update = request.json();
+                updated
+                    .lock()
+                    .map_err(|err| anyhow!("Updates poisoned: {err}"))?
+                    .push((
+                        update["subdomain"].as_str().unwrap_or_default().to_string(),
+                        update["txt"].as_str().unwrap_or_default().to_string(),
+                    ));
+                ("200 OK", json!({ "txt": update["txt"] }))
+            }
+            _ => ("401 Unauthorized", json!({ "error": "forbidden" })),
+        };
+        Ok(MockResponse::json(status, &response))
+    })
+    .await?;
+    let server_url = format!("http://{server}");
+
+    let credentials_file = std::env::temp_dir()
+        .join(format!("certsd-acme-dns-{}.json", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    let provider = ron::from_str::<DnsProviderConfig>(&format!(
+        r#"AcmeDns(server_url: "{server_url}", credentials_file: Some("{credentials_file}"))"#
+    ))?
+    .build()
+    .await?;
+    let domain = "the-acme-dns-domain.com";
+    let registered = provider.present(domain, domain, "proof-1").await;
+    let accounts = AcmeDns::new(&server_url, &credentials_file, &[])
+        .load_accounts()
+        .await;
+    let permissions = tokio::fs::metadata(&credentials_file).await?.permissions();
+    let updated = provider.present(domain, domain, "proof-2").await;
+    let listed = provider.list(domain).await;
+    provider.cleanup(domain).await?;
+    let listed_after_cleanup = provider.list(domain).await;
+    tokio::fs::remove_file(&credentials_file).await?;
+
+    assert!(registered.err().is_some_and(|err| {
+        err.to_string().contains(
+            "\"_acme-challenge.the-acme-dns-domain.com. CNAME d420c923.auth.acme-dns.example.\"",
+        )
+    }));
+    assert_eq!(
+        accounts?
+            .get("_acme-challenge.the-acme-dns-domain.com")
+            .map(|account| account.username.as_str()),
+        Some("the-user")
+    );
+    assert_eq!(permissions.mode() & 0o777, 0o600);
+    updated?;
+    assert_eq!(
+        *updates.lock().unwrap(),
+        [(String::from("d420c923"), String::from("proof-2"))]
+    );
+    assert_eq!(
+        listed?
+            .iter()
+            .map(|record| (record.name.as_str(), record.content.as_str()))
+            .collect::<Vec<_>>(),
+        [("d420c923.auth.acme-dns.example", "proof-2")]
+    );
+    assert!(listed_after_cleanup?.is_empty());
+    Ok(())
+}
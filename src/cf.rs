@@ -2,138 +2,995 @@ use crate::*;
 
 use cloudflare::{
     endpoints::dns::dns::{
-        CreateDnsRecord, CreateDnsRecordParams, DeleteDnsRecord, DeleteDnsRecordResponse,
-        DnsContent, DnsRecord, ListDnsRecords, ListDnsRecordsParams,
+        CreateDnsRecord, CreateDnsRecordParams, DeleteDnsRecord, DnsContent, DnsRecord,
     },
     framework::{
         Environment,
         auth::Credentials,
         client::{ClientConfig, async_api::Client},
-        response::ApiSuccess,
+        endpoint::{Method, serialize_query, spec::EndpointSpec},
+        response::{ApiFailure, ApiResult, ApiSuccess, ResponseConverter},
     },
 };
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+
+/// How certsd authenticates to the CloudFlare API, as configured
+#[derive(Debug, Clone, Deserialize)]
+pub enum CloudflareAuth {
+    /// The API token (recommended, scoped to the zones)
+    ApiToken(SecretSource),
+
+    /// The Global API Key of the account, with its email
+    GlobalApiKey {
+        email: String,
+        api_key: SecretSource,
+    },
+}
+
+
+/// The resolved CloudFlare credentials
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CloudflareCredentials {
+    ApiToken(String),
+    GlobalApiKey { email: String, api_key: String },
+}
+
+
+impl CloudflareCredentials {
+    /// Reads the secrets of the auth, or takes the plaintext API token without it
+    #[instrument(skip(api_token, auth))]
+    pub async fn resolve(api_token: &str, auth: Option<&CloudflareAuth>) -> Result<Self> {
+        match auth {
+            Some(CloudflareAuth::ApiToken(api_token)) => {
+                Ok(CloudflareCredentials::ApiToken(api_token.resolve().await?))
+            }
+            Some(CloudflareAuth::GlobalApiKey {
+                email,
+                api_key,
+            }) => {
+                Ok(CloudflareCredentials::GlobalApiKey {
+                    email: email.to_owned(),
+                    api_key: api_key.resolve().await?,
+                })
+            }
+            None if api_token.is_empty() => {
+                Err(anyhow!("No CloudFlare credentials configured"))
+            }
+            None => Ok(CloudflareCredentials::ApiToken(api_token.to_string())),
+        }
+    }
+
+
+    fn credentials(&self) -> Credentials {
+        match self {
+            CloudflareCredentials::ApiToken(token) => {
+                Credentials::UserAuthToken {
+                    token: token.to_owned(),
+                }
+            }
+            CloudflareCredentials::GlobalApiKey {
+                email,
+                api_key,
+            } => {
+                Credentials::UserAuthKey {
+                    email: email.to_owned(),
+                    key: api_key.to_owned(),
+                }
+            }
+        }
+    }
+}
+
+
+/// The zone, with just the fields certsd needs (the zones without the activation
+/// date fail to deserialize as the Zone of the cloudflare crate)
+#[derive(Debug, Clone, Deserialize)]
+pub struct CloudflareZone {
+    pub id: String,
+    pub name: String,
+
+    /// The permissions of the API token on the zone, like "#dns_records:edit"
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+impl ApiResult for CloudflareZone {}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct CloudflareZones(pub Vec<CloudflareZone>);
+
+impl ApiResult for CloudflareZones {}
+
+
+/// GET /zones?name=…, the zones of the name visible to the API token
+#[derive(Debug, Serialize)]
+struct ListZoneIds {
+    name: String,
+    per_page: u32,
+}
+
+
+impl EndpointSpec for ListZoneIds {
+    type JsonResponse = CloudflareZones;
+    type ResponseType = ApiSuccess<CloudflareZones>;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+
+    fn path(&self) -> String {
+        String::from("zones")
+    }
+
+
+    #[inline]
+    fn query(&self) -> Option<String> {
+        serialize_query(self)
+    }
+}
+
+
+/// GET /zones/{zone_id}/dns_records?type=TXT&name=…, a page of the TXT records of the name
+#[derive(Debug, Serialize)]
+struct ListTxtRecords {
+    #[serde(skip)]
+    zone_id: String,
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    name: String,
+    page: u32,
+    per_page: u32,
+}
+
+
+impl EndpointSpec for ListTxtRecords {
+    type JsonResponse = Vec<DnsRecord>;
+    type ResponseType = ApiSuccess<Vec<DnsRecord>>;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records", self.zone_id)
+    }
+
+
+    #[inline]
+    fn query(&self) -> Option<String> {
+        serialize_query(self)
+    }
+}
+
+
+/// GET /zones/{zone_id}, the zone with the permissions of the API token
+#[derive(Debug)]
+struct ZoneDetails {
+    zone_id: String,
+}
+
+
+impl EndpointSpec for ZoneDetails {
+    type JsonResponse = CloudflareZone;
+    type ResponseType = ApiSuccess<CloudflareZone>;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+
+    fn path(&self) -> String {
+        format!("zones/{}", self.zone_id)
+    }
+}
+
+
+/// The API token, as verified by the CloudFlare
+#[derive(Debug, Clone, Deserialize)]
+pub struct CloudflareTokenStatus {
+    pub id: String,
+
+    /// "active", "disabled" or "expired"
+    pub status: String,
+}
+
+impl ApiResult for CloudflareTokenStatus {}
+
+
+/// GET /user/tokens/verify
+#[derive(Debug)]
+struct VerifyToken;
+
+
+impl EndpointSpec for VerifyToken {
+    type JsonResponse = CloudflareTokenStatus;
+    type ResponseType = ApiSuccess<CloudflareTokenStatus>;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+
+    fn path(&self) -> String {
+        String::from("user/tokens/verify")
+    }
+}
+
+
+/// DNS provider managing the challenge records over the CloudFlare API
+pub struct CloudflareDns {
+    credentials: CloudflareCredentials,
+
+    /// The zone of the domain and its subdomains, discovered with the API token if None
+    zone_id: Option<String>,
+
+    /// The name of the configured zone, read with the first lookup
+    zone_name: Mutex<Option<String>>,
+
+    /// Cleans up all the challenge records of the domain, not just the created ones
+    sweep_stale_records: bool,
+    api_url: String,
+
+    /// The API client of the account, shared by all requests of the provider
+    client: Arc<Client>,
+
+    /// Challenge record names presented by the provider: (domain, record name)
+    presented_names: Mutex<Vec<(String, String)>>,
+
+    /// Challenge records created by the provider, the only ones cleaned up by default:
+    /// (domain, zone ID, record ID)
+    created_records: Mutex<Vec<(String, String, String)>>,
+
+    /// Zone IDs discovered by the names
+    discovered_zone_ids: Mutex<BTreeMap<String, String>>,
+    delegations: ChallengeDelegations,
+}
+
+
+impl fmt::Debug for CloudflareDns {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("CloudflareDns")
+            .field("zone_id", &self.zone_id)
+            .field("sweep_stale_records", &self.sweep_stale_records)
+            .field("api_url", &self.api_url)
+            .finish_non_exhaustive()
+    }
+}
+
+
+impl CloudflareDns {
+    pub fn new(
+        credentials: CloudflareCredentials,
+        zone_id: Option<&str>,
+        sweep_stale_records: bool,
+    ) -> Result<Self> {
+        let api_url = String::from(DEFAULT_CLOUDFLARE_API_URL);
+        Ok(CloudflareDns {
+            client: Self::client_of(&credentials, &api_url)?,
+            credentials,
+            zone_id: zone_id
+                .filter(|zone_id| !zone_id.is_empty())
+                .map(String::from),
+            zone_name: Mutex::new(None),
+            sweep_stale_records,
+            api_url,
+            presented_names: Mutex::new(vec![]),
+            created_records: Mutex::new(vec![]),
+            discovered_zone_ids: Mutex::new(BTreeMap::new()),
+            delegations: ChallengeDelegations::default(),
+        })
+    }
+
+
+    /// Uses the other API endpoint (the mocks of the tests)
+    pub fn with_api_url(mut self, api_url: &str) -> Result<Self> {
+        self.api_url = api_url.to_string();
+        self.client = Self::client_of(&self.credentials, &self.api_url)?;
+        Ok(self)
+    }
+
+
+    fn client_of(credentials: &CloudflareCredentials, api_url: &str) -> Result<Arc<Client>> {
+        Ok(Arc::new(Client::new(
+            credentials.credentials(),
+            ClientConfig::default(),
+            Environment::Custom(api_url.to_string()),
+        )?))
+    }
+
+
+    /// Sends the request, backing off while CloudFlare responds with 429 Too Many Requests
+    pub(crate) async fn request<Endpoint>(
+        &self,
+        endpoint: &Endpoint,
+    ) -> Result<Endpoint::ResponseType>
+    where
+        Endpoint: EndpointSpec + Send + Sync,
+        Endpoint::ResponseType: ResponseConverter<Endpoint::JsonResponse>,
+    {
+        let client = &self.client;
+        let mut backoff = Duration::from_millis(DEFAULT_CLOUDFLARE_BACKOFF_MS);
+        for _ in 0..DEFAULT_CLOUDFLARE_RATE_LIMIT_RETRIES {
+            match client.request(endpoint).await {
+                Err(ApiFailure::Error(status, _)) if status.as_u16() == 429 => {
+                    warn!(
+                        "CloudFlare API rate limit hit, retrying in {}ms",
+                        backoff.as_millis()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                response => return Ok(response?),
+            }
+        }
+        Ok(client.request(endpoint).await?)
+    }
+
+
+    /// Checks the API token is valid and active (the Global API Key gets checked
+    /// by reading the zone)
+    #[instrument(skip(self))]
+    pub async fn verify_token(&self) -> Result<()> {
+        if matches!(self.credentials, CloudflareCredentials::GlobalApiKey { .. }) {
+            return Ok(());
+        }
+        let token = self
+            .request(&VerifyToken)
+            .await
+            .map_err(|err| anyhow!("The CloudFlare API token is invalid: {err}"))?
+            .result;
+        if token.status != "active" {
+            return Err(anyhow!(
+                "The CloudFlare API token: {} is {}",
+                token.id,
+                token.status
+            ));
+        }
+        Ok(())
+    }
+
+
+    /// Checks the API token can edit the DNS records in the zone of the domain
+    #[instrument(skip(self))]
+    pub async fn verify_dns_edit(&self, domain: &str) -> Result<()> {
+        let zone_id = self.zone_id_of(&challenge_record_name(domain)).await?;
+        let zone = self
+            .request(&ZoneDetails {
+                zone_id: zone_id.to_owned(),
+            })
+            .await
+            .map_err(|err| {
+                anyhow!("The CloudFlare credentials can't read the zone: {zone_id}: {err}")
+            })?
+            .result;
+        if !zone
+            .permissions
+            .iter()
+            .any(|permission| permission == "#dns_records:edit")
+        {
+            return Err(anyhow!(
+                "The CloudFlare API token can't edit the DNS records of the zone: {} ({zone_id}). Give it the Zone:DNS:Edit permission.",
+                zone.name
+            ));
+        }
+        Ok(())
+    }
+
+
+    /// The challenge record names of the domain: its own, the delegated ones and
+    /// the ones presented by the provider (of the subdomains)
+    fn challenge_names_of(&self, domain: &str) -> Result<Vec<String>> {
+        let mut names = vec![challenge_record_name(domain)];
+        names.extend(self.delegations.targets_of(domain));
+        self.presented_names
+            .lock()
+            .map_err(|err| anyhow!("Presented names poisoned: {err}"))?
+            .iter()
+            .filter(|(presented_domain, _)| presented_domain == domain)
+            .for_each(|(_, name)| {
+                if !names.contains(name) {
+                    names.push(name.to_owned());
+                }
+            });
+        Ok(names)
+    }
+
+
+    /// The configured zone ID for the names inside of its zone, otherwise the one of the zone
+    /// owning the name (the longest suffix match of the zones visible to the API token)
+    #[instrument(skip(self))]
+    pub async fn zone_id_of(&self, name: &str) -> Result<String> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if let Some(zone_id) = &self.zone_id {
+            let zone_name = self.zone_name_of(zone_id).await?;
+            if name == zone_name || name.ends_with(&format!(".{zone_name}")) {
+                return Ok(zone_id.to_owned());
+            }
+        }
+        if let Some(zone_id) = self
+            .discovered_zone_ids
+            .lock()
+            .map_err(|err| anyhow!("Discovered zone IDs poisoned: {err}"))?
+            .get(&name)
+        {
+            return Ok(zone_id.to_owned());
+        }
+
+        let labels = name.split('.').collect::<Vec<_>>();
+        let candidates = (0..labels.len().saturating_sub(1))
+            .map(|index| labels[index..].join("."))
+            .collect::<Vec<_>>();
+        for candidate in candidates.iter() {
+            let zones = self
+                .request(&ListZoneIds {
+                    name: candidate.to_owned(),
+                    per_page: 5,
+                })
+                .await
+                .map_err(|err| anyhow!("CloudFlare zones lookup of: {name} failed: {err}"))?;
+            if let Some(zone) = zones
+                .result
+                .0
+                .into_iter()
+                .find(|zone| names_equal(&zone.name, candidate))
+            {
+                info!("CloudFlare zone of: {name} is: {} ({})", zone.name, zone.id);
+                self.discovered_zone_ids
+                    .lock()
+                    .map_err(|err| anyhow!("Discovered zone IDs poisoned: {err}"))?
+                    .insert(name.to_owned(), zone.id.to_owned());
+                return Ok(zone.id);
+            }
+        }
+        Err(anyhow!(
+            "The CloudFlare API token can't see the zone of: {name} (tried: {}). Give the token the Zone:Read permission of the zone, or set the zone_id.",
+            candidates.join(", ")
+        ))
+    }
+
+
+    /// The name of the configured zone, read once
+    async fn zone_name_of(&self, zone_id: &str) -> Result<String> {
+        if let Some(zone_name) = self
+            .zone_name
+            .lock()
+            .map_err(|err| anyhow!("Zone name poisoned: {err}"))?
+            .to_owned()
+        {
+            return Ok(zone_name);
+        }
+        let zone = self
+            .request(&ZoneDetails {
+                zone_id: zone_id.to_owned(),
+            })
+            .await
+            .map_err(|err| {
+                anyhow!("The CloudFlare credentials can't read the zone: {zone_id}: {err}")
+            })?
+            .result;
+        let zone_name = zone.name.trim_end_matches('.').to_lowercase();
+        *self
+            .zone_name
+            .lock()
+            .map_err(|err| anyhow!("Zone name poisoned: {err}"))? = Some(zone_name.to_owned());
+        Ok(zone_name)
+    }
+
+
+    #[instrument(skip(self))]
+    async fn delete_txt_record(&self, zone_id: &str, id: &str) -> Result<()> {
+        let delete_dns_record = DeleteDnsRecord {
+            zone_identifier: zone_id,
+            identifier: id,
+        };
+        self.request(&delete_dns_record).await?;
+        Ok(())
+    }
+
+
+    /// The TXT records of the name, filtered by the API, page by page
+    #[instrument(skip(self))]
+    async fn list_in_zone(&self, zone_id: &str, name: &str) -> Result<Vec<ChallengeRecord>> {
+        let mut records = vec![];
+        let mut page = 1;
+        loop {
+            let response = self
+                .request(&ListTxtRecords {
+                    zone_id: zone_id.to_string(),
+                    record_type: "TXT",
+                    name: name.to_string(),
+                    page,
+                    per_page: DEFAULT_MAX_ELEMENTS_PER_PAGE,
+                })
+                .await?;
+            let total_pages = response
+                .result_info
+                .as_ref()
+                .and_then(|info| info["total_pages"].as_u64())
+                .unwrap_or_default();
+            let last_page = response.result.is_empty() || u64::from(page) >= total_pages;
+            for record in response.result {
+                if let DnsContent::TXT {
+                    content,
+                } = record.content
+                {
+                    info!("Found previously defined DNS TXT record: {}", record.name);
+                    records.push(ChallengeRecord {
+                        id: record.id,
+                        name: record.name,
+                        content,
+                    });
+                }
+            }
+            if last_page {
+                return Ok(records);
+            }
+            page += 1;
+        }
+    }
+
+
+    /// The challenge records of the domain, with their zones
+    async fn challenge_records_of(
+        &self,
+        domain: &str,
+    ) -> Result<Vec<(String, ChallengeRecord)>> {
+        let mut records = vec![];
+        for name in self.challenge_names_of(domain)? {
+            let zone_id = self.zone_id_of(&name).await?;
+            for record in self.list_in_zone(&zone_id, &name).await? {
+                records.push((zone_id.to_owned(), record));
+            }
+        }
+        Ok(records)
+    }
+}
 
 
-#[instrument(skip(config, domain))]
-pub async fn delete_acme_dns_txt_entries(
-    config: &Config,
-    domain: &str,
-) -> Result<(), anyhow::Error> {
-    let dns_response = list_acme_txt_records(config, domain).await;
-    match dns_response {
-        Ok(the_list) => {
-            for entry in the_list {
-                delete_txt_record(config, domain, &entry).await?;
+/// Verifies the CloudFlare accounts of the domains before any order is created: every
+/// distinct API token once, and its DNS edit permission in the zone of every domain.
+/// Reports all the misconfigured accounts in a single error.
+#[instrument(skip(accounts))]
+pub async fn verify_cloudflare_accounts(
+    accounts: &[(String, Result<&CloudflareDns>)],
+) -> Result<()> {
+    let mut verified_tokens = BTreeMap::new();
+    let mut misconfigured = vec![];
+    for (domain, cloudflare) in accounts.iter() {
+        let verified = match cloudflare {
+            Ok(cloudflare) => {
+                let account = (
+                    cloudflare.credentials.to_owned(),
+                    cloudflare.api_url.to_owned(),
+                );
+                if !verified_tokens.contains_key(&account) {
+                    let verified = cloudflare
+                        .verify_token()
+                        .await
+                        .map_err(|err| err.to_string());
+                    verified_tokens.insert(account.to_owned(), verified);
+                }
+                match &verified_tokens[&account] {
+                    Ok(()) => cloudflare.verify_dns_edit(domain).await,
+                    Err(err) => Err(anyhow!("{err}")),
+                }
             }
+            Err(err) => Err(anyhow!("{err}")),
+        };
+        if let Err(err) = verified {
+            // the API failures span multiple lines: the status and the errors
+            let err = err.to_string().lines().collect::<Vec<_>>().join("; ");
+            misconfigured.push(format!("{domain}: {err}"));
         }
-        Err(e) => error!("Err: {e}"),
+    }
+    if !misconfigured.is_empty() {
+        return Err(anyhow!(
+            "Misconfigured CloudFlare accounts:\n{}",
+            misconfigured.join("\n")
+        ));
     }
     Ok(())
 }
 
 
-#[instrument(skip(config))]
-pub async fn list_acme_txt_records(config: &Config, domain: &str) -> Result<Vec<String>> {
-    let zone_id = config.zone_id_of(domain).await;
-    let client = Client::new(
-        Credentials::UserAuthToken {
-            token: config.api_token_of(domain).await,
-        },
-        ClientConfig::default(),
-        Environment::Production,
-    )?;
-    let list_dns_txt_records = ListDnsRecords {
-        zone_identifier: &zone_id,
-        params: ListDnsRecordsParams {
-            per_page: Some(DEFAULT_MAX_ELEMENTS_PER_PAGE),
-            ..ListDnsRecordsParams::default()
-        },
-    };
-    let response = client.request(&list_dns_txt_records).await?;
-
-    let txt_record_ids = response
-        .result
-        .iter()
-        .filter_map(|record| {
-            match record.content.to_owned() {
-                DnsContent::TXT {
-                    content: _, /* the TXT entry is irrelevant to us, we only want to list TXT records… */
-                } => {
-                    // …that contain "_acme-challenge", since we also use other TXT records for MX-stuff
-                    if record.name.contains("_acme-challenge") && record.name.contains(domain)
-                    {
-                        info!("Found previously defined DNS TXT record: {}", record.name);
-                        Some(record.id.to_owned())
-                    } else {
-                        None
-                    }
+impl DnsProvider for CloudflareDns {
+    fn delegations(&self) -> &ChallengeDelegations {
+        &self.delegations
+    }
+
+
+    #[instrument(skip(self))]
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        let record_name = self.delegations.present_name_of(domain, name);
+        let zone_id = self.zone_id_of(&record_name).await?;
+        let create_dns_txt_record = CreateDnsRecord {
+            zone_identifier: &zone_id,
+            params: CreateDnsRecordParams {
+                name: &format!("{record_name}."),
+                priority: None,
+                proxied: Some(false),
+                ttl: Some(60),
+                content: DnsContent::TXT {
+                    content: content.to_string(),
+                },
+            },
+        };
+        let created = self.request(&create_dns_txt_record).await?;
+        self.created_records
+            .lock()
+            .map_err(|err| anyhow!("Created records poisoned: {err}"))?
+            .push((domain.to_string(), zone_id, created.result.id));
+        self.presented_names
+            .lock()
+            .map_err(|err| anyhow!("Presented names poisoned: {err}"))?
+            .push((domain.to_string(), record_name));
+        Ok(())
+    }
+
+
+    /// Deletes the challenge records created by the provider, and the other ones of the
+    /// exact challenge names if sweeping the stale records
+    #[instrument(skip(self))]
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        let created = self
+            .created_records
+            .lock()
+            .map_err(|err| anyhow!("Created records poisoned: {err}"))?
+            .iter()
+            .filter(|(created_domain, ..)| created_domain == domain)
+            .map(|(_, zone_id, id)| (zone_id.to_owned(), id.to_owned()))
+            .collect::<Vec<_>>();
+        for (zone_id, id) in created.iter() {
+            self.delete_txt_record(zone_id, id).await?;
+            self.created_records
+                .lock()
+                .map_err(|err| anyhow!("Created records poisoned: {err}"))?
+                .retain(|(_, created_zone_id, created_id)| {
+                    (created_zone_id, created_id) != (zone_id, id)
+                });
+        }
+
+        if self.sweep_stale_records {
+            for (zone_id, record) in self.challenge_records_of(domain).await? {
+                if !created.contains(&(zone_id.to_owned(), record.id.to_owned())) {
+                    warn!(
+                        "Sweeping the stale challenge record: {} ({})",
+                        record.name, record.id
+                    );
+                    self.delete_txt_record(&zone_id, &record.id).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        Ok(self
+            .challenge_records_of(domain)
+            .await?
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect())
+    }
+}
+
+
+#[tokio::test]
+async fn test_cloudflare_dns_provider() -> Result<()> {
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // the mock CloudFlare API: counts the zone lookups and the record listings,
+    // rate limits the first listing and collects the deleted record IDs
+    let zone_lookups = Arc::new(AtomicUsize::new(0));
+    let looked_up = zone_lookups.clone();
+    let listings = Arc::new(AtomicUsize::new(0));
+    let listed_pages = listings.clone();
+    let deletes: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+    let deleted = deletes.clone();
+    let server = mock_http_server(move |request| {
+        let record = |id: &str, content: &str| {
+            json!({
+                "meta": {},
+                "name": "_acme-challenge.the-cf-domain.com",
+                "ttl": 60,
+                "modified_on": "2026-01-01T00:00:00Z",
+                "created_on": "2026-01-01T00:00:00Z",
+                "proxiable": false,
+                "type": "TXT",
+                "content": content,
+                "id": id,
+                "proxied": false,
+            })
+        };
+        let (status, result, total_pages) = match (request.method.as_str(), request.path()) {
+            ("GET", "/client/v4/zones") => {
+                looked_up.fetch_add(1, Ordering::SeqCst);
+                if request.query().starts_with("name=the-cf-domain.com&") {
+                    (
+                        "200 OK",
+                        json!([{ "id": "the-discovered-zone-id", "name": "the-cf-domain.com" }]),
+                        1,
+                    )
+                } else if request.query().starts_with("name=the-second-cf-domain.com&") {
+                    (
+                        "200 OK",
+                        json!([{ "id": "the-second-zone-id", "name": "the-second-cf-domain.com" }]),
+                        1,
+                    )
+                } else {
+                    ("200 OK", json!([]), 0)
                 }
-                _ => {
-                    // ignore all other record types
-                    None
+            }
+            ("GET", "/client/v4/zones/the-zone-id") => {
+                (
+                    "200 OK",
+                    json!({ "id": "the-zone-id", "name": "the-cf-domain.com" }),
+                    1,
+                )
+            }
+            ("GET", "/client/v4/zones/the-discovered-zone-id/dns_records") => {
+                if listed_pages.fetch_add(1, Ordering::SeqCst) == 0 {
+                    ("429 Too Many Requests", json!(null), 0)
+                } else if !request
+                    .query()
+                    .starts_with("type=TXT&name=_acme-challenge.the-cf-domain.com&")
+                {
+                    ("200 OK", json!([]), 0)
+                } else if request.query().ends_with("&page=1&per_page=100") {
+                    ("200 OK", json!([record("the-record-1", "proof-1")]), 2)
+                } else {
+                    ("200 OK", json!([record("the-record-2", "proof-2")]), 2)
                 }
             }
-        })
-        .collect::<Vec<String>>();
-    // no need to handle errors here, as the empty list of records is still a valid response
-    Ok(txt_record_ids)
-}
-
-
-#[instrument(skip(config))]
-pub async fn delete_txt_record(
-    config: &Config,
-    domain: &str,
-    id: &str,
-) -> Result<ApiSuccess<DeleteDnsRecordResponse>> {
-    let zone_id = config.zone_id_of(domain).await;
-    let client = Client::new(
-        Credentials::UserAuthToken {
-            token: config.api_token_of(domain).await,
-        },
-        ClientConfig::default(),
-        Environment::Production,
-    )?;
-    let delete_dns_record = DeleteDnsRecord {
-        zone_identifier: &zone_id,
-        identifier: id,
+            ("POST", "/client/v4/zones/the-discovered-zone-id/dns_records") => {
+                let created = request.json();
+                (
+                    "200 OK",
+                    record(
+                        "the-record-3",
+                        created["content"].as_str().unwrap_or_default(),
+                    ),
+                    1,
+                )
+            }
+            ("DELETE", path) => {
+                let id = path.rsplit('/').next().unwrap_or_default();
+                deleted
+                    .lock()
+                    .map_err(|err| anyhow!("Deletes poisoned: {err}"))?
+                    .push(id.to_string());
+                ("200 OK", json!({ "id": id }), 1)
+            }
+            _ => ("404 Not Found", json!(null), 0),
+        };
+        let response = json!({
+            "success": status == "200 OK",
+            "result": result,
+            "result_info": { "page": 1, "total_pages": total_pages },
+            "errors": [],
+            "messages": [],
+        });
+        Ok(MockResponse::json(status, &response))
+    })
+    .await?;
+    let api_url = format!("http://{server}/client/v4/");
+
+    let credentials = CloudflareCredentials::ApiToken(String::from("the-zone-read-token"));
+    let provider =
+        CloudflareDns::new(credentials.to_owned(), None, false)?.with_api_url(&api_url)?;
+    let zone_id = provider
+        .zone_id_of("_acme-challenge.the-cf-domain.com")
+        .await?;
+    let lookups = zone_lookups.load(Ordering::SeqCst);
+    let cached_zone_id = provider
+        .zone_id_of("_acme-challenge.the-cf-domain.com.")
+        .await?;
+    let cached_lookups = zone_lookups.load(Ordering::SeqCst);
+    let listed = provider.list("the-cf-domain.com").await?;
+    provider
+        .present("the-cf-domain.com", "the-cf-domain.com", "proof-3")
+        .await?;
+    provider.cleanup("the-cf-domain.com").await?;
+    let deleted_created = deletes.lock().unwrap().clone();
+    CloudflareDns::new(credentials.to_owned(), None, true)?
+        .with_api_url(&api_url)?
+        .cleanup("the-cf-domain.com")
+        .await?;
+    let configured_provider =
+        CloudflareDns::new(credentials, Some("the-zone-id"), false)?.with_api_url(&api_url)?;
+    let configured = configured_provider.zone_id_of("the-cf-domain.com").await?;
+    let configured_subdomain = configured_provider
+        .zone_id_of("_acme-challenge.api.the-cf-domain.com")
+        .await?;
+    // the names of the other zones (the other domains of the account) are looked up
+    let outside_configured = configured_provider
+        .zone_id_of("_acme-challenge.the-second-cf-domain.com")
+        .await?;
+    let invisible = provider.zone_id_of("the-other-domain.com").await;
+
+    assert_eq!(zone_id, "the-discovered-zone-id");
+    // the longest suffix first: "_acme-challenge.the-cf-domain.com", "the-cf-domain.com"
+    assert_eq!(lookups, 2);
+    assert_eq!(cached_zone_id, "the-discovered-zone-id");
+    assert_eq!(cached_lookups, lookups);
+    assert_eq!(
+        listed
+            .iter()
+            .map(|record| (record.id.as_str(), record.content.as_str()))
+            .collect::<Vec<_>>(),
+        [("the-record-1", "proof-1"), ("the-record-2", "proof-2")]
+    );
+    // only the created record, the other ones just when sweeping the stale ones
+    assert_eq!(deleted_created, ["the-record-3"]);
+    assert_eq!(
+        *deletes.lock().unwrap(),
+        ["the-record-3", "the-record-1", "the-record-2"]
+    );
+    // the rate limited one, the two pages of the listing and the two of the sweep
+    assert_eq!(listings.load(Ordering::SeqCst), 5);
+    assert_eq!(configured, "the-zone-id");
+    assert_eq!(configured_subdomain, "the-zone-id");
+    assert_eq!(outside_configured, "the-second-zone-id");
+    assert!(invisible.err().is_some_and(|err| {
+        err.to_string().starts_with(
+            "The CloudFlare API token can't see the zone of: the-other-domain.com",
+        )
+    }));
+    Ok(())
+}
+
+
+#[tokio::test]
+async fn test_verify_cloudflare_accounts() -> Result<()> {
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // the mock CloudFlare API: the permissions by the token, counts the token verifications
+    let verifications = Arc::new(AtomicUsize::new(0));
+    let verified = verifications.clone();
+    let server = mock_http_server(move |request| {
+        // the API token, or the "email:key" of the Global API Key
+        let token = match request.header("x-auth-email") {
+            "" => {
+                request
+                    .header("authorization")
+                    .trim_start_matches("Bearer ")
+                    .to_string()
+            }
+            email => format!("{email}:{}", request.header("x-auth-key")),
+        };
+        let (status, result) = match (request.path(), token.as_str()) {
+            ("/client/v4/user/tokens/verify", "the-invalid-token") => {
+                verified.fetch_add(1, Ordering::SeqCst);
+                (
+                    "401 Unauthorized",
+                    json!({ "code": 1000, "message": "Invalid API Token" }),
+                )
+            }
+            ("/client/v4/user/tokens/verify", token) => {
+                verified.fetch_add(1, Ordering::SeqCst);
+                let status = match token {
+                    "the-expired-token" => "expired",
+                    _ => "active",
+                };
+                ("200 OK", json!({ "id": "the-token-id", "status": status }))
+            }
+            ("/client/v4/zones/the-zone-id", token) => {
+                let permissions = match token {
+                    "the-dns-edit-token" | "me@example.com:the-global-api-key" => {
+                        json!(["#zone:read", "#dns_records:edit"])
+                    }
+                    _ => json!(["#zone:read", "#dns_records:read"]),
+                };
+                (
+                    "200 OK",
+                    json!({ "id": "the-zone-id", "name": "the-cf-domain.com", "permissions": permissions }),
+                )
+            }
+            _ => ("404 Not Found", json!(null)),
+        };
+        let response = match status {
+            "200 OK" => {
+                json!({ "success": true, "result": result, "errors": [], "messages": [] })
+            }
+            _ => json!({ "errors": [result] }),
+        };
+        Ok(MockResponse::json(status, &response))
+    })
+    .await?;
+    let api_url = format!("http://{server}/client/v4/");
+
+    let account = |domain: &str, credentials: CloudflareCredentials| {
+        (
+            domain.to_string(),
+            CloudflareDns::new(credentials, Some("the-zone-id"), false)
+                .and_then(|cloudflare| cloudflare.with_api_url(&api_url)),
+        )
     };
-    client
-        .request(&delete_dns_record)
-        .await
-        .map_err(|e| e.into())
-}
-
-
-#[instrument(skip(config))]
-pub async fn create_txt_record(
-    config: &Config,
-    domain: &str,
-    content: &str,
-) -> Result<ApiSuccess<DnsRecord>> {
-    let zone_id = config.zone_id_of(domain).await;
-    let client = Client::new(
-        Credentials::UserAuthToken {
-            token: config.api_token_of(domain).await,
-        },
-        ClientConfig::default(),
-        Environment::Production,
+    let token = |token: &str| CloudflareCredentials::ApiToken(token.to_string());
+    let provider_config: DnsProviderConfig = ron::from_str(
+        r#"Cloudflare(auth: Some(GlobalApiKey(email: "me@example.com", api_key: Command(["echo", "the-global-api-key"]))))"#,
     )?;
-    let create_dns_txt_record = CreateDnsRecord {
-        zone_identifier: &zone_id,
-        params: CreateDnsRecordParams {
-            name: &format!("_acme-challenge.{domain}."),
-            priority: None,
-            proxied: Some(false),
-            ttl: Some(60),
-            content: DnsContent::TXT {
-                content: content.to_string(),
-            },
-        },
+    let DnsProviderConfig::Cloudflare {
+        api_token,
+        auth,
+        ..
+    } = provider_config
+    else {
+        return Err(anyhow!("Not the CloudFlare provider"));
+    };
+    let global_api_key = CloudflareCredentials::resolve(&api_token, auth.as_ref()).await?;
+    let verify = async |accounts: Vec<(String, Result<CloudflareDns>)>| {
+        let accounts = accounts
+            .iter()
+            .map(|(domain, cloudflare)| {
+                (
+                    domain.to_owned(),
+                    cloudflare.as_ref().map_err(|err| anyhow!("{err}")),
+                )
+            })
+            .collect::<Vec<_>>();
+        verify_cloudflare_accounts(&accounts).await
     };
+    let valid = verify(vec![
+        account("the-cf-domain.com", token("the-dns-edit-token")),
+        account("api.the-cf-domain.com", token("the-dns-edit-token")),
+        account("global.the-cf-domain.com", global_api_key),
+    ])
+    .await;
+    let valid_verifications = verifications.load(Ordering::SeqCst);
+    let misconfigured = verify(vec![
+        account("the-cf-domain.com", token("the-dns-edit-token")),
+        account("read-only.the-cf-domain.com", token("the-dns-read-token")),
+        account("the-invalid.com", token("the-invalid-token")),
+        account("the-expired.com", token("the-expired-token")),
+        (
+            String::from("the-unconfigured.com"),
+            CloudflareCredentials::resolve("", None)
+                .await
+                .and_then(|credentials| CloudflareDns::new(credentials, None, false)),
+        ),
+    ])
+    .await;
 
-    client
-        .request(&create_dns_txt_record)
-        .await
-        .map_err(|e| e.into())
+    valid?;
+    // the token shared by the accounts is verified just once, the Global API Key never
+    assert_eq!(valid_verifications, 1);
+    let misconfigured = misconfigured
+        .err()
+        .map(|err| err.to_string())
+        .unwrap_or_default();
+    let lines = misconfigured.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], "Misconfigured CloudFlare accounts:");
+    assert!(lines[1].starts_with(
+        "read-only.the-cf-domain.com: The CloudFlare API token can't edit the DNS records of the zone: the-cf-domain.com"
+    ));
+    assert!(lines[2].starts_with(
+        "the-invalid.com: The CloudFlare API token is invalid: HTTP 401 Unauthorized; 1000: Invalid API Token"
+    ));
+    assert_eq!(
+        lines[3],
+        "the-expired.com: The CloudFlare API token: the-token-id is expired"
+    );
+    assert_eq!(
+        lines[4],
+        "the-unconfigured.com: No CloudFlare credentials configured"
+    );
+    Ok(())
 }
@@ -0,0 +1,466 @@
+use crate::*;
+
+use cloudflare::framework::{
+    endpoint::{Method, RequestBody, serialize_query, spec::EndpointSpec},
+    response::{ApiFailure, ApiResult, ApiSuccess},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::marker::PhantomData;
+
+
+/// Upload of the renewed certificates of the domain to the CloudFlare, with the
+/// credentials of its CloudFlare DNS provider
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CloudflareCertificate {
+    /// "ubiquitous", "optimal" or "force". The CloudFlare default if None
+    #[serde(default)]
+    pub bundle_method: Option<String>,
+
+    /// The SSL for SaaS zone. Replaces the certificate of the Custom Hostname of the
+    /// domain (or its wildcard) there, instead of the custom certificate of the domain zone
+    #[serde(default)]
+    pub custom_hostname_zone_id: Option<String>,
+}
+
+
+/// The uploaded custom certificate, or the Custom Hostname
+#[derive(Debug, Clone, Deserialize)]
+pub struct CloudflareUploaded {
+    pub id: String,
+}
+
+impl ApiResult for CloudflareUploaded {}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct CloudflareUploadedList(pub Vec<CloudflareUploaded>);
+
+impl ApiResult for CloudflareUploadedList {}
+
+
+/// The CloudFlare API request without the endpoint in the cloudflare crate
+#[derive(Debug)]
+struct JsonRequest<ResultType> {
+    method: Method,
+    path: String,
+    query: Option<String>,
+    body: Option<Value>,
+    result: PhantomData<ResultType>,
+}
+
+
+impl<ResultType> JsonRequest<ResultType> {
+    fn new(method: Method, path: String) -> Self {
+        JsonRequest {
+            method,
+            path,
+            query: None,
+            body: None,
+            result: PhantomData,
+        }
+    }
+}
+
+
+impl<ResultType: ApiResult> EndpointSpec for JsonRequest<ResultType> {
+    type JsonResponse = ResultType;
+    type ResponseType = ApiSuccess<ResultType>;
+
+    fn method(&self) -> Method {
+        self.method.to_owned()
+    }
+
+
+    fn path(&self) -> String {
+        self.path.to_owned()
+    }
+
+
+    #[inline]
+    fn query(&self) -> Option<String> {
+        self.query.to_owned()
+    }
+
+
+    #[inline]
+    fn body(&self) -> Option<RequestBody<'_>> {
+        self.body
+            .as_ref()
+            .map(|body| RequestBody::Json(body.to_string()))
+    }
+}
+
+
+/// Checks if the CloudFlare responded with 404 Not Found (the certificate deleted in the dashboard)
+fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ApiFailure>(),
+        Some(ApiFailure::Error(status, _)) if status.as_u16() == 404
+    )
+}
+
+
+impl CloudflareDns {
+    /// Uploads the chained.pem and domain.key of the certificate dir, replacing the
+    /// certificate uploaded before (its ID is kept in the certificate dir).
+    /// Returns the ID of the custom certificate (or the Custom Hostname).
+    #[instrument(skip(self, upload))]
+    pub async fn upload_certificate(
+        &self,
+        upload: &CloudflareCertificate,
+        domain: &str,
+        name: &str,
+        certificate_dir: &str,
+    ) -> Result<String> {
+        let domain_key_file = format!("{certificate_dir}/domain.key");
+        if !std::path::Path::new(&domain_key_file).exists() {
+            return Err(anyhow!(
+                "No {domain_key_file} to upload (the domains with an external CSR have no key)"
+            ));
+        }
+        let certificate =
+            tokio::fs::read_to_string(format!("{certificate_dir}/chained.pem")).await?;
+        let private_key = tokio::fs::read_to_string(&domain_key_file).await?;
+        let id_file = format!("{certificate_dir}/{DEFAULT_CLOUDFLARE_CERTIFICATE_ID_FILE}");
+        let uploaded_id = tokio::fs::read_to_string(&id_file)
+            .await
+            .ok()
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty());
+
+        let id = match &upload.custom_hostname_zone_id {
+            Some(zone_id) => {
+                let mut ssl = json!({
+                    "method": "http",
+                    "type": "dv",
+                    "custom_certificate": certificate,
+                    "custom_key": private_key,
+                });
+                if let Some(bundle_method) = &upload.bundle_method {
+                    ssl["bundle_method"] = json!(bundle_method);
+                }
+                self.replace_custom_hostname_certificate(zone_id, name, uploaded_id, ssl)
+                    .await?
+            }
+            None => {
+                let zone_id = self.zone_id_of(domain).await?;
+                let mut body = json!({
+                    "certificate": certificate,
+                    "private_key": private_key,
+                });
+                if let Some(bundle_method) = &upload.bundle_method {
+                    body["bundle_method"] = json!(bundle_method);
+                }
+                self.replace_custom_certificate(&zone_id, uploaded_id, body)
+                    .await?
+            }
+        };
+        tokio::fs::write(&id_file, &id).await?;
+        Ok(id)
+    }
+
+
+    /// Updates the custom certificate in place, or uploads the new one
+    #[instrument(skip(self, body))]
+    async fn replace_custom_certificate(
+        &self,
+        zone_id: &str,
+        uploaded_id: Option<String>,
+        body: Value,
+    ) -> Result<String> {
+        if let Some(id) = uploaded_id {
+            let mut update = JsonRequest::<CloudflareUploaded>::new(
+                Method::PATCH,
+                format!("zones/{zone_id}/custom_certificates/{id}"),
+            );
+            update.body = Some(body.to_owned());
+            match self.request(&update).await {
+                Ok(updated) => return Ok(updated.result.id),
+                Err(err) if is_not_found(&err) => {
+                    warn!(
+                        "The CloudFlare custom certificate: {id} is gone, uploading the new one"
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let mut create = JsonRequest::<CloudflareUploaded>::new(
+            Method::POST,
+            format!("zones/{zone_id}/custom_certificates"),
+        );
+        create.body = Some(body);
+        Ok(self.request(&create).await?.result.id)
+    }
+
+
+    /// Replaces the custom certificate of the Custom Hostname, looked up by the hostname
+    /// unless its ID is known
+    #[instrument(skip(self, ssl))]
+    async fn replace_custom_hostname_certificate(
+        &self,
+        zone_id: &str,
+        hostname: &str,
+        uploaded_id: Option<String>,
+        ssl: Value,
+    ) -> Result<String> {
+        let update = |id: &str| {
+            let mut update = JsonRequest::<CloudflareUploaded>::new(
+                Method::PATCH,
+                format!("zones/{zone_id}/custom_hostnames/{id}"),
+            );
+            update.body = Some(json!({ "ssl": ssl }));
+            update
+        };
+        if let Some(id) = uploaded_id {
+            match self.request(&update(&id)).await {
+                Ok(updated) => return Ok(updated.result.id),
+                Err(err) if is_not_found(&err) => {
+                    warn!(
+                        "The CloudFlare Custom Hostname: {id} is gone, looking up: {hostname}"
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let mut lookup = JsonRequest::<CloudflareUploadedList>::new(
+            Method::GET,
+            format!("zones/{zone_id}/custom_hostnames"),
+        );
+        lookup.query = serialize_query(&[("hostname", hostname)]);
+        let Some(custom_hostname) = self.request(&lookup).await?.result.0.into_iter().next()
+        else {
+            return Err(anyhow!(
+                "No CloudFlare Custom Hostname: {hostname} in the zone: {zone_id}"
+            ));
+        };
+        Ok(self.request(&update(&custom_hostname.id)).await?.result.id)
+    }
+}
+
+
+/// Uploads the renewed certificate of the domain (or its wildcard) to the CloudFlare if configured
+#[instrument(skip(config, dns_backends))]
+pub async fn upload_cloudflare_certificate(
+    config: &Config,
+    dns_backends: &DnsBackends,
+    domain: &str,
+    wildcard: bool,
+) -> Result<Option<String>> {
+    let Some(upload) = config.cloudflare_certificate_of(domain).await else {
+        return Ok(None);
+    };
+    // the throwaway certificates of the dry run are never deployed
+    if config.dry_run().await {
+        info!("Dry run, not uploading the certificate of: {domain} to the CloudFlare");
+        return Ok(None);
+    }
+    let Some(cloudflare) = dns_backends.cloudflare_of(domain).await else {
+        return Err(anyhow!(
+            "The CloudFlare certificate upload needs the CloudFlare DNS provider of: {domain}"
+        ));
+    };
+    let name = match wildcard {
+        true => format!("*.{domain}"),
+        false => domain.to_string(),
+    };
+    let id = cloudflare?
+        .upload_certificate(&upload, domain, &name, &domain_dir_of(domain, wildcard))
+        .await?;
+    info!("The certificate of: {name} uploaded to the CloudFlare: {id}");
+    Ok(Some(id))
+}
+
+
+#[tokio::test]
+async fn test_upload_cloudflare_certificate() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    // the mock CloudFlare API: the requests with the uploaded certificates
+    let requests: Arc<Mutex<Vec<(String, String, Value)>>> = Arc::new(Mutex::new(vec![]));
+    let requested = requests.clone();
+    let server = mock_http_server(move |request| {
+        let path = request
+            .target
+            .trim_start_matches("/client/v4/zones/")
+            .to_string();
+        let (status, result) = match (request.method.as_str(), path.as_str()) {
+            ("GET", "the-zone-id") => {
+                (
+                    "200 OK",
+                    json!({ "id": "the-zone-id", "name": "the-cf-domain.com" }),
+                )
+            }
+            ("POST", "the-zone-id/custom_certificates") => {
+                ("200 OK", json!({ "id": "the-certificate-id" }))
+            }
+            ("PATCH", "the-zone-id/custom_certificates/the-certificate-id") => {
+                ("200 OK", json!({ "id": "the-certificate-id" }))
+            }
+            ("GET", "the-saas-zone-id/custom_hostnames?hostname=*.the-cf-domain.com") => {
+                ("200 OK", json!([{ "id": "the-hostname-id" }]))
+            }
+            ("PATCH", "the-saas-zone-id/custom_hostnames/the-hostname-id") => {
+                ("200 OK", json!({ "id": "the-hostname-id" }))
+            }
+            _ => {
+                (
+                    "404 Not Found",
+                    json!({ "code": 1404, "message": "Not found" }),
+                )
+            }
+        };
+        requested
+            .lock()
+            .map_err(|err| anyhow!("Requests poisoned: {err}"))?
+            .push((request.method.to_owned(), path, request.json()));
+        let response = match status {
+            "200 OK" => {
+                json!({ "success": true, "result": result, "errors": [], "messages": [] })
+            }
+            _ => json!({ "errors": [result] }),
+        };
+        Ok(MockResponse::json(status, &response))
+    })
+    .await?;
+    let api_url = format!("http://{server}/client/v4/");
+
+    let certificate_dir = std::env::temp_dir()
+        .join(format!("certsd-cf-certificate-{}", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    tokio::fs::create_dir_all(&certificate_dir).await?;
+    tokio::fs::write(format!("{certificate_dir}/chained.pem"), "the-chain").await?;
+    tokio::fs::write(format!("{certificate_dir}/domain.key"), "the-key").await?;
+    let id_file = format!("{certificate_dir}/{DEFAULT_CLOUDFLARE_CERTIFICATE_ID_FILE}");
+    let cloudflare = CloudflareDns::new(
+        CloudflareCredentials::ApiToken(String::from("the-ssl-edit-token")),
+        Some("the-zone-id"),
+        false,
+    )?
+    .with_api_url(&api_url)?;
+
+    let custom_certificate: CloudflareCertificate =
+        ron::from_str(r#"(bundle_method: Some("optimal"))"#)?;
+    let uploaded = async {
+        let created = cloudflare
+            .upload_certificate(
+                &custom_certificate,
+                "the-cf-domain.com",
+                "the-cf-domain.com",
+                &certificate_dir,
+            )
+            .await?;
+        let replaced = cloudflare
+            .upload_certificate(
+                &custom_certificate,
+                "the-cf-domain.com",
+                "the-cf-domain.com",
+                &certificate_dir,
+            )
+            .await?;
+        // deleted in the dashboard
+        tokio::fs::write(&id_file, "the-deleted-id").await?;
+        let recreated = cloudflare
+            .upload_certificate(
+                &custom_certificate,
+                "the-cf-domain.com",
+                "the-cf-domain.com",
+                &certificate_dir,
+            )
+            .await?;
+        Ok::<_, anyhow::Error>((created, replaced, recreated))
+    }
+    .await;
+    let certificate_requests = std::mem::take(&mut *requests.lock().unwrap());
+
+    let custom_hostname: CloudflareCertificate =
+        ron::from_str(r#"(custom_hostname_zone_id: Some("the-saas-zone-id"))"#)?;
+    tokio::fs::remove_file(&id_file).await?;
+    let hostname_uploaded = async {
+        let looked_up = cloudflare
+            .upload_certificate(
+                &custom_hostname,
+                "the-cf-domain.com",
+                "*.the-cf-domain.com",
+                &certificate_dir,
+            )
+            .await?;
+        let replaced = cloudflare
+            .upload_certificate(
+                &custom_hostname,
+                "the-cf-domain.com",
+                "*.the-cf-domain.com",
+                &certificate_dir,
+            )
+            .await?;
+        Ok::<_, anyhow::Error>((looked_up, replaced))
+    }
+    .await;
+    let hostname_requests = std::mem::take(&mut *requests.lock().unwrap());
+    let stored_id = tokio::fs::read_to_string(&id_file).await;
+    tokio::fs::remove_file(format!("{certificate_dir}/domain.key")).await?;
+    let without_key = cloudflare
+        .upload_certificate(
+            &custom_certificate,
+            "the-cf-domain.com",
+            "the-cf-domain.com",
+            &certificate_dir,
+        )
+        .await;
+    tokio::fs::remove_dir_all(&certificate_dir).await?;
+
+    assert_eq!(
+        uploaded?,
+        (
+            String::from("the-certificate-id"),
+            String::from("the-certificate-id"),
+            String::from("the-certificate-id")
+        )
+    );
+    assert_eq!(
+        certificate_requests
+            .iter()
+            .map(|(method, path, _)| format!("{method} {path}"))
+            .collect::<Vec<_>>(),
+        [
+            // the name of the configured zone, read once
+            "GET the-zone-id",
+            "POST the-zone-id/custom_certificates",
+            "PATCH the-zone-id/custom_certificates/the-certificate-id",
+            "PATCH the-zone-id/custom_certificates/the-deleted-id",
+            "POST the-zone-id/custom_certificates",
+        ]
+    );
+    assert_eq!(
+        certificate_requests[1].2,
+        json!({ "certificate": "the-chain", "private_key": "the-key", "bundle_method": "optimal" })
+    );
+
+    assert_eq!(
+        hostname_uploaded?,
+        (
+            String::from("the-hostname-id"),
+            String::from("the-hostname-id")
+        )
+    );
+    assert_eq!(
+        hostname_requests
+            .iter()
+            .map(|(method, path, _)| format!("{method} {path}"))
+            .collect::<Vec<_>>(),
+        [
+            "GET the-saas-zone-id/custom_hostnames?hostname=*.the-cf-domain.com",
+            "PATCH the-saas-zone-id/custom_hostnames/the-hostname-id",
+            "PATCH the-saas-zone-id/custom_hostnames/the-hostname-id",
+        ]
+    );
+    assert_eq!(
+        hostname_requests[1].2["ssl"]["custom_certificate"],
+        "the-chain"
+    );
+    assert_eq!(stored_id?, "the-hostname-id");
+    assert!(without_key.is_err());
+    Ok(())
+}
@@ -0,0 +1,376 @@
+use crate::*;
+
+use openssl::{
+    asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time},
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    sha::sha256,
+    ssl::{AlpnError, SslAcceptor, SslMethod, select_next_proto},
+    x509::{X509, X509Extension, X509NameBuilder, extension::SubjectAlternativeName},
+};
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+
+/// ALPN protocol of the TLS-ALPN-01 challenge (RFC 8737), in the wire format
+const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"\x0aacme-tls/1";
+
+/// OID of the acmeIdentifier extension (RFC 8737)
+const OID_ACME_IDENTIFIER: &str = "1.3.6.1.5.5.7.1.31";
+
+
+/// How to prove the control over the IP address to the ACME provider
+#[derive(Debug, Clone, Deserialize)]
+pub enum IpValidation {
+    /// HTTP-01 served by certsd itself, from the listener bound to given address
+    Http { listen: String },
+
+    /// HTTP-01 served by the existing web server, from the files written to its webroot
+    Webroot { path: String },
+
+    /// TLS-ALPN-01 served by certsd itself, from the listener bound to given address
+    TlsAlpn { listen: String },
+}
+
+
+impl IpValidation {
+    /// The ACME challenge type of the validation method
+    pub fn challenge_type(&self) -> &str {
+        match self {
+            IpValidation::Http {
+                ..
+            }
+            | IpValidation::Webroot {
+                ..
+            } => "http-01",
+            IpValidation::TlsAlpn {
+                ..
+            } => "tls-alpn-01",
+        }
+    }
+}
+
+
+/// Serves the proof of the single challenge until dropped
+#[derive(Debug)]
+pub enum ChallengeResponder {
+    Http {
+        local_addr: SocketAddr,
+        server: JoinHandle<()>,
+    },
+    Webroot {
+        proof_file: PathBuf,
+    },
+    TlsAlpn {
+        local_addr: SocketAddr,
+        stop: Arc<AtomicBool>,
+    },
+}
+
+
+impl ChallengeResponder {
+    /// Starts serving the key authorization of the challenge token
+    #[instrument(skip(key_authorization))]
+    pub async fn start(
+        validation: &IpValidation,
+        identifier: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<Self> {
+        match validation {
+            IpValidation::Http {
+                listen,
+            } => {
+                let listener = TcpListener::bind(listen).await?;
+                let local_addr = listener.local_addr()?;
+                let challenge_path = format!("/.well-known/acme-challenge/{token}");
+                let key_authorization = key_authorization.to_string();
+                info!("Serving the HTTP-01 challenge on: {local_addr}");
+                let server = tokio::spawn(async move {
+                    while let Ok((mut stream, peer)) = listener.accept().await {
+                        let mut request = vec![0u8; 4096];
+                        let length = stream.read(&mut request).await.unwrap_or_default();
+                        let request = String::from_utf8_lossy(&request[..length]);
+                        let path = request.split_whitespace().nth(1).unwrap_or_default();
+                        debug!("HTTP-01 request from: {peer} for: {path}");
+                        let response = if path == challenge_path {
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{key_authorization}",
+                                key_authorization.len()
+                            )
+                        } else {
+                            String::from(
+                                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            )
+                        };
+                        stream
+                            .write_all(response.as_bytes())
+                            .await
+                            .unwrap_or_default();
+                    }
+                });
+                Ok(ChallengeResponder::Http {
+                    local_addr,
+                    server,
+                })
+            }
+
+            IpValidation::Webroot {
+                path,
+            } => {
+                let challenge_dir = PathBuf::from(path).join(".well-known/acme-challenge");
+                tokio::fs::create_dir_all(&challenge_dir).await?;
+                let proof_file = challenge_dir.join(token);
+                tokio::fs::write(&proof_file, key_authorization).await?;
+                info!("HTTP-01 challenge written to: {}", proof_file.display());
+                Ok(ChallengeResponder::Webroot {
+                    proof_file,
+                })
+            }
+
+            IpValidation::TlsAlpn {
+                listen,
+            } => {
+                let acceptor = tls_alpn_acceptor(identifier, key_authorization)?;
+                let listener = std::net::TcpListener::bind(listen)?;
+                listener.set_nonblocking(true)?;
+                let local_addr = listener.local_addr()?;
+                let stop = Arc::new(AtomicBool::new(false));
+                let stop_server = stop.clone();
+                info!("Serving the TLS-ALPN-01 challenge on: {local_addr}");
+                std::thread::spawn(move || {
+                    while !stop_server.load(Ordering::Relaxed) {
+                        match listener.accept() {
+                            Ok((stream, peer)) => {
+                                debug!("TLS-ALPN-01 handshake from: {peer}");
+                                stream.set_nonblocking(false).unwrap_or_default();
+                                stream
+                                    .set_read_timeout(Some(Duration::from_secs(10)))
+                                    .unwrap_or_default();
+                                if let Ok(mut tls_stream) = acceptor.accept(stream) {
+                                    let _ = tls_stream.shutdown();
+                                }
+                            }
+                            Err(_) => std::thread::sleep(Duration::from_millis(100)),
+                        }
+                    }
+                });
+                Ok(ChallengeResponder::TlsAlpn {
+                    local_addr,
+                    stop,
+                })
+            }
+        }
+    }
+}
+
+
+impl Drop for ChallengeResponder {
+    fn drop(&mut self) {
+        match self {
+            ChallengeResponder::Http {
+                server, ..
+            } => server.abort(),
+            ChallengeResponder::Webroot {
+                proof_file,
+            } => std::fs::remove_file(proof_file).unwrap_or_default(),
+            ChallengeResponder::TlsAlpn {
+                stop, ..
+            } => stop.store(true, Ordering::Relaxed),
+        }
+    }
+}
+
+
+/// TLS acceptor presenting the self-signed validation certificate (RFC 8737 section 3)
+fn tls_alpn_acceptor(identifier: &str, key_authorization: &str) -> Result<SslAcceptor> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "certsd acme-tls/1")?;
+    let name = name.build();
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial_number = BigNum::from_u32(1)?;
+    let serial_number = Asn1Integer::from_bn(&serial_number)?;
+    builder.set_serial_number(&serial_number)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&pkey)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(1)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+
+    let mut alt_name = SubjectAlternativeName::new();
+    if identifier.parse::<std::net::IpAddr>().is_ok() {
+        alt_name.ip(identifier);
+    } else {
+        alt_name.dns(identifier);
+    }
+    let alt_name = alt_name.build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(alt_name)?;
+
+    // acmeIdentifier ::= OCTET STRING (SIZE (32)), the SHA-256 of the key authorization
+    let mut acme_identifier = vec![DER_OCTET_STRING, 32];
+    acme_identifier.extend(sha256(key_authorization.as_bytes()));
+    let acme_identifier_oid = Asn1Object::from_str(OID_ACME_IDENTIFIER)?;
+    let acme_identifier = Asn1OctetString::new_from_bytes(&acme_identifier)?;
+    builder.append_extension(X509Extension::new_from_der(
+        &acme_identifier_oid,
+        true,
+        &acme_identifier,
+    )?)?;
+    builder.sign(&pkey, MessageDigest::sha256())?;
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_private_key(&pkey)?;
+    acceptor.set_certificate(&builder.build())?;
+    acceptor.set_alpn_select_callback(|_, client_protocols| {
+        select_next_proto(ACME_TLS_ALPN_PROTOCOL, client_protocols).ok_or(AlpnError::NOACK)
+    });
+    Ok(acceptor.build())
+}
+
+
+#[tokio::test]
+async fn test_http_challenge_responder() -> Result<()> {
+    let validation = IpValidation::Http {
+        listen: String::from("127.0.0.1:0"),
+    };
+    let responder =
+        ChallengeResponder::start(&validation, "127.0.0.1", "the-token", "the-token.thumb")
+            .await?;
+    let ChallengeResponder::Http {
+        local_addr, ..
+    } = &responder
+    else {
+        panic!("Expected the HTTP responder!");
+    };
+
+    let client = reqwest::Client::new();
+    let proof = client
+        .get(format!(
+            "http://{local_addr}/.well-known/acme-challenge/the-token"
+        ))
+        .send()
+        .await?;
+    assert!(proof.status().is_success());
+    assert_eq!(proof.text().await?, "the-token.thumb");
+    let missing = client
+        .get(format!(
+            "http://{local_addr}/.well-known/acme-challenge/other"
+        ))
+        .send()
+        .await?;
+    assert_eq!(missing.status().as_u16(), 404);
+    Ok(())
+}
+
+
+#[tokio::test]
+async fn test_tls_alpn_challenge_responder() -> Result<()> {
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+
+    let validation = IpValidation::TlsAlpn {
+        listen: String::from("127.0.0.1:0"),
+    };
+    let responder =
+        ChallengeResponder::start(&validation, "127.0.0.1", "the-token", "the-token.thumb")
+            .await?;
+    let ChallengeResponder::TlsAlpn {
+        local_addr, ..
+    } = &responder
+    else {
+        panic!("Expected the TLS-ALPN responder!");
+    };
+
+    // the ACME provider offers just the acme-tls/1 protocol and doesn't verify the certificate
+    let local_addr = *local_addr;
+    let (protocol, certificate) = tokio::task::spawn_blocking(move || {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_alpn_protos(ACME_TLS_ALPN_PROTOCOL)?;
+        let mut configuration = connector.build().configure()?;
+        configuration.set_verify_hostname(false);
+        configuration.set_use_server_name_indication(false);
+        let stream = std::net::TcpStream::connect(local_addr)?;
+        let tls_stream = configuration
+            .connect("127.0.0.1", stream)
+            .map_err(|err| anyhow!("TLS-ALPN-01 handshake failed: {err}"))?;
+        let protocol = tls_stream
+            .ssl()
+            .selected_alpn_protocol()
+            .map(<[u8]>::to_vec);
+        let certificate = tls_stream
+            .ssl()
+            .peer_certificate()
+            .ok_or_else(|| anyhow!("No validation certificate"))?;
+        Ok::<_, anyhow::Error>((protocol, certificate))
+    })
+    .await??;
+    drop(responder);
+
+    assert_eq!(protocol.as_deref(), Some(&b"acme-tls/1"[..]));
+    let alt_names = certificate
+        .subject_alt_names()
+        .ok_or_else(|| anyhow!("No subjectAltName"))?;
+    assert_eq!(
+        alt_names
+            .iter()
+            .map(|alt_name| alt_name.ipaddress().map(<[u8]>::to_vec))
+            .collect::<Vec<_>>(),
+        [Some(vec![127, 0, 0, 1])]
+    );
+
+    // the critical acmeIdentifier extension with the SHA-256 of the key authorization
+    let certificate_der = certificate.to_der()?;
+    let certificate_fields = DerReader::new(&certificate_der).expect(DER_SEQUENCE)?;
+    let tbs_certificate = DerReader::new(certificate_fields.content).expect(DER_SEQUENCE)?;
+    let mut tbs_fields = DerReader::new(tbs_certificate.content);
+    let mut acme_identifier = None;
+    while !tbs_fields.is_empty() {
+        let field = tbs_fields.read()?;
+        if field.tag != DER_TBS_EXTENSIONS {
+            continue;
+        }
+        let extensions = DerReader::new(field.content).expect(DER_SEQUENCE)?;
+        let mut extensions = DerReader::new(extensions.content);
+        while !extensions.is_empty() {
+            let extension = extensions.read()?;
+            let mut extension_fields = DerReader::new(extension.content);
+            let oid = extension_fields.expect(DER_OID)?;
+            if oid.content != [0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f] {
+                continue;
+            }
+            let critical = extension_fields.expect(DER_BOOLEAN)?;
+            let value = extension_fields.expect(DER_OCTET_STRING)?;
+            acme_identifier = Some((critical.content.to_vec(), value.content.to_vec()));
+        }
+    }
+    let (critical, value) =
+        acme_identifier.ok_or_else(|| anyhow!("No acmeIdentifier extension"))?;
+    assert_eq!(critical, [0xff]);
+    assert_eq!(
+        value,
+        der_encode(DER_OCTET_STRING, &sha256(b"the-token.thumb"))
+    );
+    Ok(())
+}
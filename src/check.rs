@@ -0,0 +1,593 @@
+use crate::*;
+
+use ron::error::SpannedError;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::IpAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+
+/// The problem of the configuration, with the path of its field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// The field, e.g. "accounts[1].contacts[0]"
+    pub path: String,
+
+    /// The line and column of the value in the configuration file, if located
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((line, col)) = self.position {
+            write!(formatter, "{line}:{col}: ")?;
+        }
+        match self.path.is_empty() {
+            true => write!(formatter, "{}", self.message),
+            false => write!(formatter, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+
+/// Loads the configuration file and validates it without any network calls.
+/// Returns all problems found, not just the first one.
+#[instrument]
+pub async fn check_config(config_file: &str) -> Result<Config, Vec<ConfigProblem>> {
+    let source = tokio::fs::read_to_string(config_file)
+        .await
+        .map_err(|err| {
+            vec![ConfigProblem {
+                path: String::new(),
+                position: None,
+                message: format!("Couldn't read the configuration file: {config_file}: {err}"),
+            }]
+        })?;
+    let config = ron::from_str::<Config>(&source).map_err(
+        |SpannedError {
+             code,
+             span,
+         }| {
+            vec![ConfigProblem {
+                path: String::new(),
+                position: Some((span.start.line, span.start.col)),
+                message: code.to_string(),
+            }]
+        },
+    )?;
+
+    let config_dir = match Path::new(config_file).parent() {
+        Some(path) if !path.as_os_str().is_empty() => path.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut checker = ConfigChecker {
+        source: &source,
+        data_dir: config_dir.join("certs"),
+        occurrences: BTreeMap::new(),
+        problems: vec![],
+    };
+    checker.check(&config, &config_dir);
+    match checker.problems.is_empty() {
+        true => Ok(config),
+        false => Err(checker.problems),
+    }
+}
+
+
+/// Checks if the contact is a plain e-mail address (certsd adds the "mailto:" itself)
+pub fn is_email_address(contact: &str) -> bool {
+    match contact.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !local.starts_with("mailto:")
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !contact.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+
+/// Checks if the name is a valid domain name, optionally with the "*." prefix
+fn is_domain_name(name: &str) -> bool {
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+        })
+}
+
+
+struct ConfigChecker<'a> {
+    source: &'a str,
+
+    /// The secret files and the output directories are relative to it
+    data_dir: PathBuf,
+
+    /// How many times each quoted value was located, to tell the duplicates apart
+    occurrences: BTreeMap<String, usize>,
+    problems: Vec<ConfigProblem>,
+}
+
+
+impl ConfigChecker<'_> {
+    /// Records the problem, located at the next occurrence of the quoted value (if given)
+    fn report(&mut self, path: &str, value: Option<&str>, message: &str) {
+        let position = value.and_then(|value| self.locate(value));
+        self.problems.push(ConfigProblem {
+            path: path.to_string(),
+            position,
+            message: message.to_string(),
+        });
+    }
+
+
+    /// The line and column of the next occurrence of the quoted value in the source
+    fn locate(&mut self, value: &str) -> Option<(usize, usize)> {
+        let quoted = format!("{value:?}");
+        let occurrence = self.occurrences.entry(quoted.to_owned()).or_default();
+        let (offset, _) = self.source.match_indices(&quoted).nth(*occurrence)?;
+        *occurrence += 1;
+        let line_start = self.source[..offset]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        Some((
+            self.source[..offset].matches('\n').count() + 1,
+            self.source[line_start..offset].chars().count() + 1,
+        ))
+    }
+
+
+    fn check(&mut self, config: &Config, config_dir: &Path) {
+        if config.accounts.is_empty() && config.ip_addresses.is_empty() {
+            self.report("accounts", None, "No accounts nor IP addresses configured");
+        }
+
+        // the domain => the field it was first listed in, and its name there
+        let mut listed = BTreeMap::<String, (String, String)>::new();
+        for (index, account) in config.accounts.iter().enumerate() {
+            self.check_account(&format!("accounts[{index}]"), account, &mut listed);
+        }
+        for (index, ip_account) in config.ip_addresses.iter().enumerate() {
+            let path = format!("ip_addresses[{index}]");
+            if ip_account.ip.parse::<IpAddr>().is_err() {
+                self.report(
+                    &format!("{path}.ip"),
+                    Some(&ip_account.ip),
+                    &format!("Not an IP address: {:?}", ip_account.ip),
+                );
+            } else if let Some((first, _)) = listed.get(&ip_account.ip) {
+                self.report(
+                    &format!("{path}.ip"),
+                    Some(&ip_account.ip),
+                    &format!(
+                        "Duplicate IP address: {}, already listed in: {first}",
+                        ip_account.ip
+                    ),
+                );
+            } else {
+                let first = (format!("{path}.ip"), ip_account.ip.to_owned());
+                listed.insert(ip_account.ip.to_owned(), first);
+            }
+            self.check_contacts(&path, &ip_account.contacts);
+        }
+
+        for (index, notification) in config.notifications.iter().enumerate() {
+            let path = format!("notifications[{index}]");
+            match notification {
+                NotifyWith::Slack {
+                    webhook,
+                } if webhook.trim().is_empty() => {
+                    self.report(&format!("{path}.webhook"), None, "Empty Slack webhook")
+                }
+                NotifyWith::Slack {
+                    webhook,
+                } if !webhook.starts_with("https://") => {
+                    self.report(
+                        &format!("{path}.webhook"),
+                        Some(webhook),
+                        "The Slack webhook isn't an https:// URL",
+                    )
+                }
+                NotifyWith::Telegram {
+                    chat_id,
+                    token,
+                } => {
+                    if chat_id.trim().is_empty() {
+                        self.report(
+                            &format!("{path}.chat_id"),
+                            None,
+                            "Empty Telegram chat id",
+                        );
+                    }
+                    if token.trim().is_empty() {
+                        self.report(
+                            &format!("{path}.token"),
+                            None,
+                            "Empty Telegram bot token",
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // certsd creates the data dir in the config dir on the first run
+        let data_dir = self.data_dir.to_owned();
+        match data_dir.exists() {
+            true => self.check_writable_dir("", &data_dir),
+            false => self.check_writable_dir("", config_dir),
+        }
+    }
+
+
+    fn check_account(
+        &mut self,
+        path: &str,
+        account: &CloudFlareAccount,
+        listed: &mut BTreeMap<String, (String, String)>,
+    ) {
+        let names = std::iter::once((format!("{path}.domain"), &account.domain))
+            .filter(|(_, name)| !name.is_empty())
+            .chain(
+                account
+                    .domains
+                    .iter()
+                    .enumerate()
+                    .map(|(index, name)| (format!("{path}.domains[{index}]"), name)),
+            )
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            self.report(&format!("{path}.domain"), None, "No domain configured");
+        }
+        for (name_path, name) in names {
+            if !is_domain_name(name) {
+                self.report(
+                    &name_path,
+                    Some(name),
+                    &format!("Not a domain name: {name:?}"),
+                );
+                continue;
+            }
+            // the wildcard and the apex of the same account share the domain
+            let domain = name.trim_start_matches("*.").to_string();
+            match listed.get(&domain) {
+                Some((first_path, first_name))
+                    if !first_path.starts_with(&format!("{path}.")) || first_name == name =>
+                {
+                    let message =
+                        format!("Duplicate domain: {domain}, already listed in: {first_path}");
+                    self.report(&name_path, Some(name), &message);
+                }
+                _ => {
+                    self.locate(name);
+                    listed.entry(domain).or_insert((name_path, name.to_owned()));
+                }
+            }
+        }
+
+        // these are of the domain only, the other domains would share them
+        if !account.domains.is_empty() {
+            let shared = [
+                ("csr", account.csr.is_some()),
+                ("endpoints", !account.endpoints.is_empty()),
+                (
+                    "kubernetes_secret.name",
+                    account
+                        .kubernetes_secret
+                        .as_ref()
+                        .is_some_and(|secret| secret.name.is_some()),
+                ),
+            ];
+            for (field, configured) in shared {
+                if configured {
+                    self.report(
+                        &format!("{path}.{field}"),
+                        None,
+                        &format!(
+                            "The {field} can't be used with the domains, configure them in their own accounts"
+                        ),
+                    );
+                }
+            }
+        }
+
+        self.check_contacts(path, &account.contacts);
+        match &account.dns_provider {
+            Some(dns_provider) => {
+                self.check_dns_provider(&format!("{path}.dns_provider"), dns_provider)
+            }
+            None if account.cloudflare_api_token.trim().is_empty() => {
+                self.report(
+                    &format!("{path}.cloudflare_api_token"),
+                    None,
+                    "Empty CloudFlare API token (and no dns_provider configured)",
+                )
+            }
+            None => {}
+        }
+
+        if let Some(kubernetes_secret) = &account.kubernetes_secret {
+            if kubernetes_secret.namespace.trim().is_empty() {
+                self.report(
+                    &format!("{path}.kubernetes_secret.namespace"),
+                    None,
+                    "Empty Kubernetes namespace",
+                );
+            }
+            if let Some(directory) = &kubernetes_secret.directory {
+                let directory = self.data_dir.join(directory);
+                if directory.exists() {
+                    self.check_writable_dir(
+                        &format!("{path}.kubernetes_secret.directory"),
+                        &directory,
+                    );
+                }
+            }
+        }
+        for (field, keystore) in [("pkcs12", &account.pkcs12), ("jks", &account.jks)] {
+            if let Some(keystore) = keystore {
+                if keystore.alias.trim().is_empty() {
+                    self.report(
+                        &format!("{path}.{field}.alias"),
+                        None,
+                        "Empty keystore alias",
+                    );
+                }
+                self.check_secret(&format!("{path}.{field}.password"), &keystore.password);
+            }
+        }
+    }
+
+
+    fn check_contacts(&mut self, path: &str, contacts: &[String]) {
+        for (index, contact) in contacts.iter().enumerate() {
+            if !is_email_address(contact) {
+                self.report(
+                    &format!("{path}.contacts[{index}]"),
+                    Some(contact),
+                    &format!("Not an e-mail address: {contact:?}"),
+                );
+            }
+        }
+    }
+
+
+    fn check_dns_provider(&mut self, path: &str, dns_provider: &DnsProviderConfig) {
+        let mut required = vec![];
+        match dns_provider {
+            DnsProviderConfig::Cloudflare {
+                api_token,
+                auth,
+                ..
+            } => {
+                match auth {
+                    Some(CloudflareAuth::ApiToken(api_token)) => {
+                        self.check_secret(&format!("{path}.auth.ApiToken"), api_token)
+                    }
+                    Some(CloudflareAuth::GlobalApiKey {
+                        email,
+                        api_key,
+                    }) => {
+                        if !is_email_address(email) {
+                            self.report(
+                                &format!("{path}.auth.GlobalApiKey.email"),
+                                Some(email),
+                                &format!("Not an e-mail address: {email:?}"),
+                            );
+                        }
+                        self.check_secret(
+                            &format!("{path}.auth.GlobalApiKey.api_key"),
+                            api_key,
+                        );
+                    }
+                    None => required.push(("api_token", api_token)),
+                }
+            }
+            DnsProviderConfig::Rfc2136 {
+                server,
+                key_name,
+                key_secret,
+                ..
+            } => {
+                required.extend([("server", server), ("key_name", key_name)]);
+                self.check_secret(&format!("{path}.key_secret"), key_secret);
+            }
+            DnsProviderConfig::Route53 {
+                access_key_id,
+                secret_access_key,
+                ..
+            } => {
+                if let Some(access_key_id) = access_key_id {
+                    required.push(("access_key_id", access_key_id));
+                }
+                if let Some(secret_access_key) = secret_access_key {
+                    self.check_secret(&format!("{path}.secret_access_key"), secret_access_key);
+                }
+            }
+            DnsProviderConfig::PowerDns {
+                server_url,
+                api_key,
+                ..
+            } => {
+                required.push(("server_url", server_url));
+                self.check_secret(&format!("{path}.api_key"), api_key);
+            }
+            DnsProviderConfig::Exec {
+                program, ..
+            } => required.push(("program", program)),
+            DnsProviderConfig::AcmeDns {
+                server_url, ..
+            } => required.push(("server_url", server_url)),
+            DnsProviderConfig::InMemory => {}
+        }
+        for (field, value) in required {
+            if value.trim().is_empty() {
+                self.report(&format!("{path}.{field}"), None, &format!("Empty {field}"));
+            }
+        }
+    }
+
+
+    fn check_secret(&mut self, path: &str, secret: &SecretSource) {
+        match secret {
+            SecretSource::File(file) if file.trim().is_empty() => {
+                self.report(path, None, "Empty secret file name")
+            }
+            SecretSource::File(file) if !self.data_dir.join(file).is_file() => {
+                self.report(
+                    path,
+                    Some(file),
+                    &format!("The secret file doesn't exist: {file}"),
+                )
+            }
+            SecretSource::Env(variable) if variable.trim().is_empty() => {
+                self.report(path, None, "Empty environment variable name")
+            }
+            SecretSource::Command(command)
+                if command
+                    .first()
+                    .is_none_or(|program| program.trim().is_empty()) =>
+            {
+                self.report(path, None, "Empty secret command")
+            }
+            _ => {}
+        }
+    }
+
+
+    /// Checks if certsd can write into the directory, and nobody else can
+    fn check_writable_dir(&mut self, path: &str, directory: &Path) {
+        let display = directory.to_string_lossy().to_string();
+        let metadata = match std::fs::metadata(directory) {
+            Ok(metadata) if metadata.is_dir() => metadata,
+            Ok(_) => return self.report(path, None, &format!("Not a directory: {display}")),
+            Err(err) => {
+                return self.report(
+                    path,
+                    None,
+                    &format!("Inaccessible directory: {display}: {err}"),
+                );
+            }
+        };
+        if metadata.permissions().mode() & 0o002 != 0 {
+            self.report(path, None, &format!("World-writable directory: {display}"));
+        }
+        let probe = directory.join(format!(".certsd-check-{}", std::process::id()));
+        match std::fs::write(&probe, b"") {
+            Ok(()) => std::fs::remove_file(&probe).unwrap_or_default(),
+            Err(err) => {
+                self.report(
+                    path,
+                    None,
+                    &format!("Unwritable directory: {display}: {err}"),
+                )
+            }
+        }
+    }
+}
+
+
+#[tokio::test]
+async fn test_check_config() {
+    let config_dir = std::env::temp_dir().join(format!("certsd-check-{}", std::process::id()));
+    std::fs::create_dir_all(config_dir.join("certs")).unwrap();
+    std::fs::write(config_dir.join("certs/keystore.pass"), "the-password").unwrap();
+    let config_file = config_dir.join("certsd.conf").to_string_lossy().to_string();
+
+    std::fs::write(
+        &config_file,
+        r#"(
+    acme_staging: true,
+    accounts: [
+        (
+            cloudflare_api_token: "the-api-token",
+            domain: "the-domain.com",
+            contacts: ["me@example.com"],
+            pkcs12: Some((alias: "tomcat", password: File("keystore.pass"))),
+        ),
+    ],
+    notifications: [],
+)"#,
+    )
+    .unwrap();
+    let valid = check_config(&config_file).await;
+
+    std::fs::write(
+        &config_file,
+        r#"(
+    acme_staging: true,
+    accounts: [
+        (
+            cloudflare_api_token: "",
+            domain: "the-domain.com",
+            contacts: ["me@example.com", "mailto:someone@example.com"],
+            jks: Some((alias: "tomcat", password: File("missing.pass"))),
+        ),
+        (
+            dns_provider: Some(Cloudflare(auth: Some(ApiToken(Env(""))))),
+            domains: ["*.the-domain.com", "the-second-domain.com"],
+            contacts: ["not an address"],
+            endpoints: ["the-second-domain.com:443"],
+        ),
+    ],
+    ip_addresses: [
+        (ip: "203.0.113.300", contacts: [], validation: Http(listen: "0.0.0.0:80")),
+    ],
+    notifications: [
+        Slack(webhook: ""),
+        Telegram(chat_id: "@Public_Channel", token: ""),
+    ],
+)"#,
+    )
+    .unwrap();
+    let invalid = check_config(&config_file).await;
+
+    std::fs::write(
+        &config_file,
+        "(\n    acme_staging: true,\n    accounts: [(domain: )],\n)",
+    )
+    .unwrap();
+    let unparsable = check_config(&config_file).await;
+    std::fs::remove_dir_all(&config_dir).unwrap();
+
+    assert!(valid.is_ok(), "{valid:?}");
+    let problems = invalid
+        .unwrap_err()
+        .iter()
+        .map(ConfigProblem::to_string)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        problems,
+        [
+            "7:42: accounts[0].contacts[1]: Not an e-mail address: \"mailto:someone@example.com\"",
+            "accounts[0].cloudflare_api_token: Empty CloudFlare API token (and no dns_provider configured)",
+            "8:56: accounts[0].jks.password: The secret file doesn't exist: missing.pass",
+            "12:23: accounts[1].domains[0]: Duplicate domain: the-domain.com, already listed in: accounts[0].domain",
+            "accounts[1].endpoints: The endpoints can't be used with the domains, configure them in their own accounts",
+            "13:24: accounts[1].contacts[0]: Not an e-mail address: \"not an address\"",
+            "accounts[1].dns_provider.auth.ApiToken: Empty environment variable name",
+            "18:14: ip_addresses[0].ip: Not an IP address: \"203.0.113.300\"",
+            "notifications[0].webhook: Empty Slack webhook",
+            "notifications[1].token: Empty Telegram bot token",
+        ]
+    );
+    let problems = unparsable.unwrap_err();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].position, Some((3, 24)));
+}
@@ -0,0 +1,127 @@
+use crate::*;
+use clap::{Parser, Subcommand};
+
+
+/// CertsD - automated, asynchronous LE certificate issuer.
+#[derive(Debug, Clone, Default, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Run the whole flow against the ACME staging with a separate staging account.
+    /// All outputs go into a throwaway directory, the production files stay untouched
+    /// and no success notifications are sent.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Process only the given domain (or IP address). Can be repeated.
+    #[arg(long = "domain", value_name = "DOMAIN")]
+    pub domains: Vec<String>,
+
+    /// Process only the wildcard certificates (*.example.com)
+    #[arg(long, conflicts_with = "apex_only")]
+    pub wildcard_only: bool,
+
+    /// Process only the apex certificates (example.com)
+    #[arg(long)]
+    pub apex_only: bool,
+
+    /// Renew the certificates regardless of their remaining validity
+    #[arg(long)]
+    pub force: bool,
+
+    /// Renew the certificates with the new domain keys (implies --force). The previous
+    /// keys are kept next to the dated copies of the previous certificates.
+    #[arg(long)]
+    pub rotate_key: bool,
+
+    /// Without a command, certsd renews the certificates
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Show the inventory of the managed certificates
+    Status {
+        /// Print the inventory as JSON instead of the table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Import the certificates, keys and the account of the certbot installation
+    Import {
+        /// The certbot configuration dir (with the live/, archive/ and renewal/ dirs)
+        #[arg(long, default_value = DEFAULT_CERTBOT_DIR)]
+        letsencrypt_dir: String,
+    },
+
+    /// Validate the configuration, without any network calls
+    CheckConfig,
+}
+
+
+impl Cli {
+    /// Checks if the domain (or IP address) was selected with the --domain option
+    pub fn selects(&self, domain: &str) -> bool {
+        self.domains.is_empty() || self.domains.iter().any(|selected| selected == domain)
+    }
+
+
+    pub fn includes_wildcard(&self) -> bool {
+        !self.apex_only
+    }
+
+
+    pub fn includes_apex(&self) -> bool {
+        !self.wildcard_only
+    }
+}
+
+
+#[test]
+fn test_cli_parse() {
+    let cli = Cli::parse_from(["certsd"]);
+    assert!(!cli.dry_run);
+
+    assert!(cli.selects("the-domain.com"));
+    assert!(cli.includes_wildcard() && cli.includes_apex());
+
+    let cli = Cli::parse_from(["certsd", "--dry-run"]);
+    assert!(cli.dry_run);
+
+    let cli = Cli::parse_from(["certsd", "--rotate-key"]);
+    assert!(cli.rotate_key);
+
+    let cli = Cli::parse_from([
+        "certsd",
+        "--domain",
+        "the-domain.com",
+        "--domain",
+        "203.0.113.7",
+        "--wildcard-only",
+        "--force",
+    ]);
+    assert!(cli.force && !cli.rotate_key);
+    assert!(cli.selects("the-domain.com") && cli.selects("203.0.113.7"));
+    assert!(!cli.selects("the-second-domain.com"));
+    assert!(cli.includes_wildcard() && !cli.includes_apex());
+
+    assert!(Cli::try_parse_from(["certsd", "--wildcard-only", "--apex-only"]).is_err());
+
+    let cli = Cli::parse_from(["certsd", "status", "--json"]);
+    assert!(matches!(
+        cli.command,
+        Some(Command::Status {
+            json: true
+        })
+    ));
+
+    let cli = Cli::parse_from(["certsd", "import"]);
+    assert!(matches!(
+        cli.command,
+        Some(Command::Import { letsencrypt_dir }) if letsencrypt_dir == DEFAULT_CERTBOT_DIR
+    ));
+
+    let cli = Cli::parse_from(["certsd", "check-config"]);
+    assert!(matches!(cli.command, Some(Command::CheckConfig)));
+}
@@ -11,14 +11,123 @@ pub struct Config {
     pub acme_staging: bool,
     pub notifications: Vec<NotifyWith>,
     pub accounts: Vec<CloudFlareAccount>,
+
+    #[serde(default)]
+    pub ip_addresses: Vec<IpAccount>,
+
+    /// Verification of the SCTs embedded in the issued certificates (disabled if None)
+    #[serde(default)]
+    pub certificate_transparency: Option<CtPolicy>,
+
+    /// Resolver ("host:port") following the CNAMEs of the challenge records,
+    /// the first nameserver of /etc/resolv.conf if None
+    #[serde(default)]
+    pub resolver: Option<String>,
+
+    /// Set by the --dry-run option, never read from the configuration file
+    #[serde(skip)]
+    pub dry_run: bool,
+
+    /// Absolute path of the staging account key used by the dry run
+    #[serde(skip)]
+    pub dry_run_account_key_file: String,
+
+    /// Set by the --force option, never read from the configuration file
+    #[serde(skip)]
+    pub force_renewal: bool,
+
+    /// Set by the --rotate-key option, never read from the configuration file
+    #[serde(skip)]
+    pub rotate_domain_key: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct CloudFlareAccount {
+    /// Legacy CloudFlare credentials, used when no dns_provider is set
+    #[serde(default)]
     pub cloudflare_api_token: String,
+    /// Looked up with the API token if empty
+    #[serde(default)]
     pub cloudflare_zone_id: String,
+
+    /// DNS backend publishing the challenge records of the domain
+    #[serde(default)]
+    pub dns_provider: Option<DnsProviderConfig>,
+
+    #[serde(default)]
     pub domain: String,
+
+    /// More domains and subdomains sharing the account, each with just its own certificate:
+    /// of the name, or the wildcard one of the "*.name" ones (list both to get both)
+    #[serde(default)]
+    pub domains: Vec<String>,
+    pub contacts: Vec<String>,
+
+    /// Externally generated CSR to finalize the orders of the domain with, instead of
+    /// the domain.key. Relative to the config data dir. Not supported with the domains
+    #[serde(default)]
+    pub csr: Option<String>,
+
+    /// TLS endpoints ("host:port") expected to serve the current certificate of the domain.
+    /// Not supported with the domains
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+
+    /// Write the kubernetes.io/tls Secret manifests of the domain certificates
+    #[serde(default)]
+    pub kubernetes_secret: Option<KubernetesSecret>,
+
+    /// Write the PKCS#12 keystore (keystore.p12) of the domain certificates
+    #[serde(default)]
+    pub pkcs12: Option<KeystoreOutput>,
+
+    /// Write the legacy JKS keystore (keystore.jks) of the domain certificates
+    #[serde(default)]
+    pub jks: Option<KeystoreOutput>,
+
+    /// Upload the domain certificates to the CloudFlare as the custom certificates
+    #[serde(default)]
+    pub cloudflare_certificate: Option<CloudflareCertificate>,
+}
+
+impl CloudFlareAccount {
+    /// The domain and the other domains of the account, without the "*." of the wildcard ones
+    pub fn all_domains(&self) -> Vec<String> {
+        let mut all_domains = vec![];
+        for domain in std::iter::once(&self.domain).chain(self.domains.iter()) {
+            let domain = domain.trim_start_matches("*.");
+            if !domain.is_empty() && !all_domains.iter().any(|listed| listed == domain) {
+                all_domains.push(domain.to_string());
+            }
+        }
+        all_domains
+    }
+
+
+    /// Checks if the wildcard certificate of the domain is managed: of the domain
+    /// (apex and wildcard), or of its "*.domain" entry
+    pub fn includes_wildcard(&self, domain: &str) -> bool {
+        self.domain.trim_start_matches("*.") == domain
+            || self
+                .domains
+                .iter()
+                .any(|name| *name == format!("*.{domain}"))
+    }
+
+
+    /// Checks if the certificate of the domain name itself is managed: of the domain
+    /// (apex and wildcard), or of its plain entry
+    pub fn includes_apex(&self, domain: &str) -> bool {
+        self.domain == domain || self.domains.iter().any(|name| name == domain)
+    }
+}
+
+/// IP address identifier (RFC 8738), validated over HTTP-01 or TLS-ALPN-01
+#[derive(Debug, Clone, Deserialize)]
+pub struct IpAccount {
+    pub ip: String,
     pub contacts: Vec<String>,
+    pub validation: IpValidation,
 }
 
 
@@ -79,47 +188,173 @@ impl Config {
 
     #[instrument]
     pub async fn domains(&self) -> Vec<String> {
-        self.accounts
+        let mut domains = vec![];
+        for domain in self
+            .accounts
             .iter()
-            .cloned()
-            .map(|accts| accts.domain)
-            .collect()
+            .flat_map(CloudFlareAccount::all_domains)
+        {
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+        domains
     }
 
 
+    /// The account of the domain, listed as its domain or one of its domains
     #[instrument]
-    pub async fn contacts_of(&self, domain: &str) -> Vec<String> {
+    pub async fn account_of(&self, domain: &str) -> Option<&CloudFlareAccount> {
         self.accounts
             .iter()
-            .find(|&entry| entry.domain == domain)
-            .cloned()
-            .map(|entry| entry.contacts)
+            .find(|&entry| entry.all_domains().iter().any(|listed| listed == domain))
+    }
+
+
+    /// Checks if the wildcard certificate of the domain is managed
+    #[instrument]
+    pub async fn manages_wildcard(&self, domain: &str) -> bool {
+        self.account_of(domain)
+            .await
+            .is_some_and(|entry| entry.includes_wildcard(domain))
+    }
+
+
+    /// Checks if the certificate of the domain name itself is managed
+    #[instrument]
+    pub async fn manages_apex(&self, domain: &str) -> bool {
+        self.account_of(domain)
+            .await
+            .is_some_and(|entry| entry.includes_apex(domain))
+    }
+
+
+    #[instrument]
+    pub async fn contacts_of(&self, domain: &str) -> Vec<String> {
+        self.account_of(domain)
+            .await
+            .map(|entry| entry.contacts.to_owned())
             .unwrap_or_default()
     }
 
 
+    /// The plaintext CloudFlare API token of the domain (empty for the other DNS providers,
+    /// or if read with the auth)
     #[instrument]
     pub async fn api_token_of(&self, domain: &str) -> String {
+        match self.dns_provider_of(domain).await {
+            DnsProviderConfig::Cloudflare {
+                api_token, ..
+            } => api_token,
+            _ => String::new(),
+        }
+    }
+
+
+    /// The configured CloudFlare zone ID of the domain (None if it's looked up, or for the
+    /// other DNS providers)
+    #[instrument]
+    pub async fn zone_id_of(&self, domain: &str) -> Option<String> {
+        match self.dns_provider_of(domain).await {
+            DnsProviderConfig::Cloudflare {
+                zone_id, ..
+            } => zone_id,
+            _ => None,
+        }
+    }
+
+
+    /// The DNS provider of the domain, the CloudFlare one built from the legacy fields by default
+    #[instrument]
+    pub async fn dns_provider_of(&self, domain: &str) -> DnsProviderConfig {
+        let account = self.account_of(domain).await.cloned().unwrap_or_default();
+        account
+            .dns_provider
+            .unwrap_or(DnsProviderConfig::Cloudflare {
+                api_token: account.cloudflare_api_token,
+                auth: None,
+                zone_id: Some(account.cloudflare_zone_id)
+                    .filter(|zone_id| !zone_id.is_empty()),
+                sweep_stale_records: false,
+            })
+    }
+
+
+    #[instrument]
+    pub async fn csr_of(&self, domain: &str) -> Option<String> {
         self.accounts
             .iter()
             .find(|&entry| entry.domain == domain)
-            .cloned()
-            .map(|entry| entry.cloudflare_api_token)
-            .unwrap_or_default()
+            .and_then(|entry| entry.csr.to_owned())
     }
 
 
     #[instrument]
-    pub async fn zone_id_of(&self, domain: &str) -> String {
+    pub async fn endpoints_of(&self, domain: &str) -> Vec<String> {
         self.accounts
             .iter()
             .find(|&entry| entry.domain == domain)
-            .cloned()
-            .map(|entry| entry.cloudflare_zone_id)
+            .map(|entry| entry.endpoints.to_owned())
             .unwrap_or_default()
     }
 
 
+    #[instrument]
+    pub async fn kubernetes_secret_of(&self, domain: &str) -> Option<KubernetesSecret> {
+        self.account_of(domain)
+            .await
+            .and_then(|entry| entry.kubernetes_secret.to_owned())
+    }
+
+
+    #[instrument]
+    pub async fn pkcs12_of(&self, domain: &str) -> Option<KeystoreOutput> {
+        self.account_of(domain)
+            .await
+            .and_then(|entry| entry.pkcs12.to_owned())
+    }
+
+
+    #[instrument]
+    pub async fn jks_of(&self, domain: &str) -> Option<KeystoreOutput> {
+        self.account_of(domain)
+            .await
+            .and_then(|entry| entry.jks.to_owned())
+    }
+
+
+    #[instrument]
+    pub async fn cloudflare_certificate_of(
+        &self,
+        domain: &str,
+    ) -> Option<CloudflareCertificate> {
+        self.account_of(domain)
+            .await
+            .and_then(|entry| entry.cloudflare_certificate.to_owned())
+    }
+
+
+    #[instrument]
+    pub async fn ip_addresses(&self) -> Vec<IpAccount> {
+        self.ip_addresses.to_owned()
+    }
+
+
+    #[instrument]
+    pub async fn certificate_transparency(&self) -> Option<CtPolicy> {
+        self.certificate_transparency.to_owned()
+    }
+
+
+    #[instrument]
+    pub async fn resolver(&self) -> String {
+        match &self.resolver {
+            Some(resolver) => resolver.to_owned(),
+            None => system_resolver().await,
+        }
+    }
+
+
     #[instrument]
     pub async fn notifications(&self) -> Vec<NotifyWith> {
         self.notifications.to_owned()
@@ -128,7 +363,102 @@ impl Config {
 
     #[instrument]
     pub async fn acme_staging(&self) -> bool {
-        self.acme_staging
+        self.acme_staging || self.dry_run
+    }
+
+
+    #[instrument]
+    pub async fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+
+    /// Switches to the dry run, with the separate staging account key stored in the data dir
+    #[instrument(skip(self))]
+    pub async fn enable_dry_run(&mut self, config_data_dir: &str) {
+        self.dry_run = true;
+        self.dry_run_account_key_file =
+            format!("{config_data_dir}/{DEFAULT_DRY_RUN_ACCOUNT_KEY_FILE}");
+    }
+
+
+    /// Makes the relative CSR files, acme-dns registrations, secret files and the CT log
+    /// list absolute, so they're read from the config data dir after changing the dir
+    /// (into the throwaway one of the dry run too)
+    #[instrument(skip(self))]
+    pub async fn resolve_relative_paths(&mut self, config_data_dir: &str) {
+        let mut relative_paths = vec![];
+        for account in self.accounts.iter_mut() {
+            relative_paths.extend(account.csr.as_mut());
+            let mut secrets = vec![];
+            match account.dns_provider.as_mut() {
+                Some(DnsProviderConfig::AcmeDns {
+                    credentials_file, ..
+                }) => {
+                    relative_paths.push(
+                        credentials_file.get_or_insert_with(|| {
+                            DEFAULT_ACME_DNS_CREDENTIALS_FILE.to_string()
+                        }),
+                    );
+                }
+                Some(dns_provider) => secrets.extend(dns_provider.secrets_mut()),
+                None => {}
+            }
+            secrets.extend(
+                account
+                    .pkcs12
+                    .iter_mut()
+                    .chain(account.jks.iter_mut())
+                    .map(|keystore| &mut keystore.password),
+            );
+            relative_paths.extend(secrets.into_iter().filter_map(SecretSource::file_mut));
+        }
+        relative_paths.extend(
+            self.certificate_transparency
+                .as_mut()
+                .map(|policy| &mut policy.log_list),
+        );
+        for path in relative_paths {
+            if Path::new(path).is_relative() {
+                *path = format!("{config_data_dir}/{path}");
+            }
+        }
+    }
+
+
+    #[instrument]
+    pub async fn force_renewal(&self) -> bool {
+        self.force_renewal
+    }
+
+
+    #[instrument(skip(self))]
+    pub async fn enable_force_renewal(&mut self) {
+        self.force_renewal = true;
+    }
+
+
+    #[instrument]
+    pub async fn rotate_domain_key(&self) -> bool {
+        self.rotate_domain_key
+    }
+
+
+    /// The new keys need the new certificates, so the key rotation forces the renewal
+    #[instrument(skip(self))]
+    pub async fn enable_key_rotation(&mut self) {
+        self.rotate_domain_key = true;
+        self.force_renewal = true;
+    }
+
+
+    #[instrument]
+    pub async fn account_key_file(&self) -> String {
+        if self.dry_run {
+            self.dry_run_account_key_file.to_owned()
+        } else {
+            String::from(DEFAULT_ACCOUNT_KEY_FILE)
+        }
     }
 }
 
@@ -148,14 +478,129 @@ async fn test_config_load() -> Result<()> {
         ["me@example.com", "someone@example.com"]
     );
     let zone_id = config.zone_id_of(domain).await;
-    assert_eq!(&zone_id, "the-zone-id");
+    assert_eq!(zone_id.as_deref(), Some("the-zone-id"));
+    assert!(matches!(
+        config.dns_provider_of(domain).await,
+        DnsProviderConfig::Cloudflare { api_token, .. } if api_token == "the-api-token"
+    ));
 
     let domain = "the-second-domain.com";
     assert_eq!(config.contacts_of(domain).await, ["another.me@example.com"]);
     let zone_id = config.zone_id_of(domain).await;
-    assert_eq!(&zone_id, "the-second-zone-id");
+    assert_eq!(zone_id.as_deref(), Some("the-second-zone-id"));
     let api_token = config.api_token_of(domain).await;
     assert_eq!(&api_token, "the-second-api-token");
+    assert_eq!(
+        config.csr_of(domain).await,
+        Some("appliance.csr".to_string())
+    );
+    assert_eq!(config.csr_of("the-domain.com").await, None);
+    let mut resolved_config = config.to_owned();
+    resolved_config
+        .resolve_relative_paths("/the-config-data-dir")
+        .await;
+    assert_eq!(
+        resolved_config.csr_of(domain).await,
+        Some("/the-config-data-dir/appliance.csr".to_string())
+    );
+    assert_eq!(
+        resolved_config
+            .certificate_transparency()
+            .await
+            .map(|policy| policy.log_list),
+        Some("/the-config-data-dir/log_list.json".to_string())
+    );
+    assert!(matches!(
+        resolved_config.pkcs12_of("the-domain.com").await,
+        Some(KeystoreOutput { password: SecretSource::File(file), .. })
+            if file == "/the-config-data-dir/keystore.pass"
+    ));
+    let mut acme_dns_config: Config = from_str(
+        r#"(
+            acme_staging: true,
+            notifications: [],
+            accounts: [
+                (
+                    dns_provider: Some(AcmeDns(server_url: "https://auth.the-domain.com")),
+                    domain: "the-domain.com",
+                    contacts: [],
+                ),
+                (
+                    dns_provider: Some(Rfc2136(
+                        server: "127.0.0.1:53",
+                        key_name: "certsd",
+                        key_algorithm: HmacSha256,
+                        key_secret: File("tsig.key"),
+                    )),
+                    domain: "the-rfc2136-domain.com",
+                    contacts: [],
+                ),
+                (
+                    dns_provider: Some(Cloudflare(auth: Some(ApiToken(File("/etc/cloudflare.token"))))),
+                    domain: "the-cloudflare-domain.com",
+                    contacts: [],
+                ),
+            ],
+        )"#,
+    )?;
+    acme_dns_config
+        .resolve_relative_paths("/the-config-data-dir")
+        .await;
+    assert!(matches!(
+        acme_dns_config.dns_provider_of("the-domain.com").await,
+        DnsProviderConfig::AcmeDns { credentials_file: Some(credentials_file), .. }
+            if credentials_file == "/the-config-data-dir/acme-dns.json"
+    ));
+    assert!(matches!(
+        acme_dns_config.dns_provider_of("the-rfc2136-domain.com").await,
+        DnsProviderConfig::Rfc2136 { key_secret: SecretSource::File(file), .. }
+            if file == "/the-config-data-dir/tsig.key"
+    ));
+    assert!(matches!(
+        acme_dns_config.dns_provider_of("the-cloudflare-domain.com").await,
+        DnsProviderConfig::Cloudflare { auth: Some(CloudflareAuth::ApiToken(SecretSource::File(file))), .. }
+            if file == "/etc/cloudflare.token"
+    ));
+    assert_eq!(
+        config.endpoints_of("the-domain.com").await,
+        ["the-domain.com:443", "edge.the-domain.com:8443"]
+    );
+    assert!(config.endpoints_of(domain).await.is_empty());
+    let kubernetes_secret = config
+        .kubernetes_secret_of("the-domain.com")
+        .await
+        .unwrap_or_else(|| panic!("Kubernetes Secret should be configured!"));
+    assert_eq!(kubernetes_secret.namespace, "ingress");
+    assert_eq!(
+        kubernetes_secret.labels.get("app.kubernetes.io/managed-by"),
+        Some(&String::from("certsd"))
+    );
+    assert!(config.kubernetes_secret_of(domain).await.is_none());
+    let pkcs12 = config
+        .pkcs12_of("the-domain.com")
+        .await
+        .unwrap_or_else(|| panic!("PKCS#12 should be configured!"));
+    assert_eq!(pkcs12.alias, "tomcat");
+    assert!(matches!(pkcs12.password, SecretSource::File(file) if file == "keystore.pass"));
+    let jks = config
+        .jks_of("the-domain.com")
+        .await
+        .unwrap_or_else(|| panic!("JKS should be configured!"));
+    assert!(
+        matches!(jks.password, SecretSource::Env(variable) if variable == "KEYSTORE_PASSWORD")
+    );
+    assert!(config.pkcs12_of(domain).await.is_none());
+
+    let ip_addresses = config.ip_addresses().await;
+    assert_eq!(ip_addresses.len(), 1);
+    assert_eq!(ip_addresses[0].ip, "203.0.113.7");
+    assert_eq!(ip_addresses[0].validation.challenge_type(), "tls-alpn-01");
+
+    let ct_policy = config.certificate_transparency().await.unwrap_or_else(|| {
+        panic!("Certificate transparency should be configured!");
+    });
+    assert_eq!(ct_policy.log_list, "log_list.json");
+    assert_eq!(ct_policy.min_distinct_logs, DEFAULT_CT_MIN_DISTINCT_LOGS);
 
     config.notifications.iter().for_each(|elem| {
         match elem {
@@ -184,3 +629,66 @@ async fn test_config_load() -> Result<()> {
 
     Ok(())
 }
+
+
+#[tokio::test]
+async fn test_config_account_domains() -> Result<()> {
+    let config: Config = from_str(
+        r#"(
+            acme_staging: true,
+            notifications: [],
+            accounts: [
+                (
+                    dns_provider: Some(Cloudflare(api_token: "the-shared-api-token")),
+                    domain: "example.com",
+                    domains: ["api.example.com", "*.staging.example.com", "example.org", "*.example.org"],
+                    contacts: ["me@example.com"],
+                    kubernetes_secret: Some((namespace: "ingress")),
+                ),
+            ],
+        )"#,
+    )?;
+    assert_eq!(
+        config.domains().await,
+        [
+            "example.com",
+            "api.example.com",
+            "staging.example.com",
+            "example.org"
+        ]
+    );
+    for domain in config.domains().await {
+        assert!(matches!(
+            config.dns_provider_of(&domain).await,
+            DnsProviderConfig::Cloudflare { api_token, .. } if api_token == "the-shared-api-token"
+        ));
+        assert_eq!(config.contacts_of(&domain).await, ["me@example.com"]);
+    }
+    // the plain subdomains get just their own certificate, the "*.name" ones the wildcard
+    let managed = async |domain: &str| {
+        (
+            config.manages_apex(domain).await,
+            config.manages_wildcard(domain).await,
+        )
+    };
+    assert_eq!(managed("example.com").await, (true, true));
+    assert_eq!(managed("api.example.com").await, (true, false));
+    assert_eq!(managed("staging.example.com").await, (false, true));
+    assert_eq!(managed("example.org").await, (true, true));
+    let secret_names = async |domain: &str| {
+        config
+            .kubernetes_secret_of(domain)
+            .await
+            .map(|secret| secret.name_of(domain, false))
+    };
+    assert_eq!(
+        secret_names("example.com").await,
+        Some(String::from("example-com-tls"))
+    );
+    assert_eq!(
+        secret_names("api.example.com").await,
+        Some(String::from("api-example-com-tls"))
+    );
+    assert!(config.account_of("other.example.com").await.is_none());
+    Ok(())
+}
@@ -1,6 +1,10 @@
 /// How many months left until certificate expiration
 pub const DEFAULT_MAX_CERT_VALIDITY_IN_MONTHS: u32 = 2;
 
+/// The certificates living shorter than that (e.g. the short-lived IP certificates)
+/// are renewed when less than 1/N of their lifetime remains
+pub const DEFAULT_SHORT_LIVED_CERT_RENEWAL_FRACTION: i32 = 3;
+
 /// Max retries for ACME query
 pub const DEFAULT_MAX_ATTEMPTS: usize = 5;
 
@@ -34,5 +38,66 @@ pub const DEFAULT_SLACK_FAILURE_COLOR: &str = "#ff1111";
 /// Default success notification color:
 pub const DEFAULT_SLACK_SUCCESS_COLOR: &str = "#00ff00";
 
-/// Default max elements for CF API DNS API calls
-pub const DEFAULT_MAX_ELEMENTS_PER_PAGE: u32 = 100_000;
+/// Default max elements per page of the CF API DNS record listings
+pub const DEFAULT_MAX_ELEMENTS_PER_PAGE: u32 = 100;
+
+/// Retries of the CF API requests rate limited with 429
+pub const DEFAULT_CLOUDFLARE_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// The first pause after the CF API rate limit, doubled on every retry
+pub const DEFAULT_CLOUDFLARE_BACKOFF_MS: u64 = 1000;
+
+/// The CloudFlare API v4 endpoint
+pub const DEFAULT_CLOUDFLARE_API_URL: &str = "https://api.cloudflare.com/client/v4/";
+
+/// Clock skew tolerance used when validating OCSP response times
+pub const DEFAULT_OCSP_CLOCK_SKEW_SECS: u32 = 300;
+
+/// The stored OCSP response is refreshed this long before its nextUpdate time
+pub const DEFAULT_OCSP_REFRESH_MARGIN_SECS: i64 = 2 * 24 * 60 * 60;
+
+/// ACME account key file of the production account
+pub const DEFAULT_ACCOUNT_KEY_FILE: &str = "account.key";
+
+/// ACME account key file of the separate staging account, used by the dry run
+pub const DEFAULT_DRY_RUN_ACCOUNT_KEY_FILE: &str = "account.dry-run.key";
+
+/// Connection and handshake timeout when verifying the deployed endpoints
+pub const DEFAULT_ENDPOINT_TIMEOUT_SECS: u64 = 10;
+
+/// How many distinct CT logs have to sign a valid SCT of the issued certificate
+pub const DEFAULT_CT_MIN_DISTINCT_LOGS: usize = 2;
+
+/// Configuration dir of the certbot installation to import from
+pub const DEFAULT_CERTBOT_DIR: &str = "/etc/letsencrypt";
+
+/// Connection and response timeout of the DNS server exchanges (RFC 2136 updates)
+pub const DEFAULT_DNS_TIMEOUT_SECS: u64 = 10;
+
+/// AWS Route 53 API endpoint
+pub const DEFAULT_ROUTE53_ENDPOINT: &str = "https://route53.amazonaws.com";
+
+/// How long to wait for the Route 53 change to reach INSYNC, and how often to check it
+pub const DEFAULT_ROUTE53_CHANGE_TIMEOUT_SECS: u64 = 120;
+pub const DEFAULT_ROUTE53_POLL_SECS: u64 = 5;
+
+/// The server ID of the PowerDNS Authoritative HTTP API
+pub const DEFAULT_POWERDNS_SERVER_ID: &str = "localhost";
+
+/// How long the external DNS hook program may run
+pub const DEFAULT_EXEC_TIMEOUT_SECS: u64 = 120;
+
+/// Resolver following the CNAMEs of the challenge records, when /etc/resolv.conf has none
+pub const DEFAULT_DNS_RESOLVER: &str = "1.1.1.1:53";
+
+/// How many CNAMEs of the challenge record to follow
+pub const MAX_CNAME_CHAIN: usize = 8;
+
+/// The acme-dns registrations, in the config data dir
+pub const DEFAULT_ACME_DNS_CREDENTIALS_FILE: &str = "acme-dns.json";
+
+/// File of the certificate dir with the ID of the certificate uploaded to the CloudFlare
+pub const DEFAULT_CLOUDFLARE_CERTIFICATE_ID_FILE: &str = "cloudflare-certificate.id";
+
+/// The ACME profile of the IP address certificates (Let's Encrypt issues them just short-lived)
+pub const DEFAULT_IP_CERTIFICATE_PROFILE: &str = "shortlived";
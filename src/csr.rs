@@ -0,0 +1,201 @@
+use crate::*;
+
+use openssl::{
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKeyRef, Private},
+    stack::Stack,
+    x509::{X509Req, X509ReqBuilder, extension::SubjectAlternativeName},
+};
+use std::net::IpAddr;
+
+
+/// Certificate Signing Request supplied by an external device
+#[derive(Debug, Clone)]
+pub struct ExternalCsr {
+    /// DER encoded CSR, as sent to the ACME finalize endpoint
+    pub der: Vec<u8>,
+
+    /// The names requested in the CSR (subjectAltName entries, or the CN if no SAN present)
+    pub names: Vec<String>,
+}
+
+
+/// Reads, verifies and parses the PEM or DER encoded CSR file
+#[instrument]
+pub async fn load_csr(csr_file: &str) -> Result<ExternalCsr> {
+    let csr_data = tokio::fs::read(csr_file).await?;
+    let csr = X509Req::from_pem(&csr_data).or_else(|_| X509Req::from_der(&csr_data))?;
+    let public_key = csr.public_key()?;
+    if !csr.verify(&public_key)? {
+        return Err(anyhow!("The CSR: {csr_file} has an invalid signature."));
+    }
+
+    let mut names = vec![];
+    for extension in csr.extensions().unwrap_or(openssl::stack::Stack::new()?) {
+        let extension_der = extension.to_der()?;
+        let (oid, value) = extension_value(&extension_der)?;
+        if oid != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        let mut general_names =
+            DerReader::new(DerReader::new(value).expect(DER_SEQUENCE)?.content);
+        while !general_names.is_empty() {
+            let general_name = general_names.read()?;
+            match general_name.tag {
+                DER_GENERAL_NAME_DNS => {
+                    names.push(String::from_utf8(general_name.content.to_vec())?);
+                }
+                tag => {
+                    return Err(anyhow!(
+                        "The CSR: {csr_file} contains an unsupported subjectAltName type: {tag:#04x}"
+                    ));
+                }
+            }
+        }
+    }
+    if names.is_empty()
+        && let Some(common_name) = csr.subject_name().entries_by_nid(Nid::COMMONNAME).next()
+    {
+        let common_name = std::str::from_utf8(common_name.data().as_slice())
+            .map_err(|_| anyhow!("The CSR: {csr_file} contains an invalid commonName"))?;
+        names.push(common_name.to_string());
+    }
+    if names.is_empty() {
+        return Err(anyhow!("The CSR: {csr_file} doesn't request any name."));
+    }
+    let mut unique_names: Vec<String> = vec![];
+    for name in names.into_iter().map(|name| name.to_lowercase()) {
+        if !unique_names.contains(&name) {
+            unique_names.push(name);
+        }
+    }
+
+    Ok(ExternalCsr {
+        der: csr.to_der()?,
+        names: unique_names,
+    })
+}
+
+
+/// Checks that every name requested in the CSR belongs to the domain we manage
+pub fn validate_csr_names(names: &[String], domain: &str) -> Result<()> {
+    let domain = domain.to_lowercase();
+    let foreign_names = names
+        .iter()
+        .filter(|name| {
+            let name = name.strip_prefix("*.").unwrap_or(name);
+            name != domain && !name.ends_with(&format!(".{domain}"))
+        })
+        .cloned()
+        .collect::<Vec<String>>();
+    if foreign_names.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "The CSR requests names outside of the domain: {domain}: {foreign_names:?}"
+        ))
+    }
+}
+
+
+/// Builds the DER encoded CSR for the DNS names and IP addresses
+pub fn create_csr(pkey: &PKeyRef<Private>, names: &[String]) -> Result<Vec<u8>> {
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_pubkey(pkey)?;
+    let mut alt_name = SubjectAlternativeName::new();
+    for name in names {
+        if name.parse::<IpAddr>().is_ok() {
+            alt_name.ip(name);
+        } else {
+            alt_name.dns(name);
+        }
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(alt_name.build(&builder.x509v3_context(None))?)?;
+    builder.add_extensions(&extensions)?;
+    builder.sign(pkey, MessageDigest::sha256())?;
+    Ok(builder.build().to_der()?)
+}
+
+
+#[tokio::test]
+async fn test_load_csr() -> Result<()> {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        pkey::PKey,
+        x509::X509NameBuilder,
+    };
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "the-domain.com")?;
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_subject_name(&name.build())?;
+    builder.set_pubkey(&pkey)?;
+    let mut extensions = Stack::new()?;
+    extensions.push(
+        SubjectAlternativeName::new()
+            .dns("the-domain.com")
+            .dns("*.API.the-domain.com")
+            .build(&builder.x509v3_context(None))?,
+    )?;
+    builder.add_extensions(&extensions)?;
+    builder.sign(&pkey, MessageDigest::sha256())?;
+
+    let csr_file = std::env::temp_dir().join(format!("certsd-{}.csr", std::process::id()));
+    tokio::fs::write(&csr_file, builder.build().to_pem()?).await?;
+    let csr = load_csr(&csr_file.to_string_lossy()).await?;
+    tokio::fs::remove_file(&csr_file).await?;
+
+    assert_eq!(csr.names, ["the-domain.com", "*.api.the-domain.com"]);
+    assert!(validate_csr_names(&csr.names, "the-domain.com").is_ok());
+    assert!(validate_csr_names(&csr.names, "api.the-domain.com").is_err());
+    assert!(
+        validate_csr_names(&["the-domain.com.evil.net".into()], "the-domain.com").is_err()
+    );
+    Ok(())
+}
+
+
+#[test]
+fn test_create_csr() -> Result<()> {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        pkey::PKey,
+    };
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let csr = X509Req::from_der(&create_csr(
+        &pkey,
+        &[String::from("203.0.113.7"), String::from("the-domain.com")],
+    )?)?;
+    let public_key = csr.public_key()?;
+    assert!(csr.verify(&public_key)?);
+
+    let mut alt_names = vec![];
+    for extension in csr.extensions()? {
+        let extension_der = extension.to_der()?;
+        let (oid, value) = extension_value(&extension_der)?;
+        if oid != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        let mut general_names =
+            DerReader::new(DerReader::new(value).expect(DER_SEQUENCE)?.content);
+        while !general_names.is_empty() {
+            let general_name = general_names.read()?;
+            alt_names.push((general_name.tag, general_name.content.to_vec()));
+        }
+    }
+    // the IP address SAN is the iPAddress (4 octets), not the dNSName of its text
+    assert_eq!(
+        alt_names,
+        [
+            (DER_GENERAL_NAME_IP, vec![203, 0, 113, 7]),
+            (DER_GENERAL_NAME_DNS, b"the-domain.com".to_vec()),
+        ]
+    );
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use crate::*;
+
+
+/// ASN.1 tag of the SEQUENCE
+pub const DER_SEQUENCE: u8 = 0x30;
+
+/// ASN.1 tag of the OBJECT IDENTIFIER
+pub const DER_OID: u8 = 0x06;
+
+/// ASN.1 tag of the BOOLEAN
+pub const DER_BOOLEAN: u8 = 0x01;
+
+/// ASN.1 tag of the OCTET STRING
+pub const DER_OCTET_STRING: u8 = 0x04;
+
+/// GeneralName dNSName ([2] IMPLICIT IA5String)
+pub const DER_GENERAL_NAME_DNS: u8 = 0x82;
+
+/// GeneralName iPAddress ([7] IMPLICIT OCTET STRING)
+pub const DER_GENERAL_NAME_IP: u8 = 0x87;
+
+/// TBSCertificate extensions ([3] EXPLICIT Extensions)
+pub const DER_TBS_EXTENSIONS: u8 = 0xa3;
+
+/// DER encoded OID of the subjectAltName extension (2.5.29.17)
+pub const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// DER encoded OID of the embedded SCT list extension (1.3.6.1.4.1.11129.2.4.2, RFC 6962)
+pub const OID_SCT_LIST: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02];
+
+
+/// A single DER Tag-Length-Value element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub tag: u8,
+    pub content: &'a [u8],
+
+    /// The whole element, including the tag and length octets
+    pub raw: &'a [u8],
+}
+
+
+/// Minimal reader of DER encoded elements (single octet tags only)
+#[derive(Debug, Clone)]
+pub struct DerReader<'a> {
+    data: &'a [u8],
+}
+
+
+impl<'a> DerReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        DerReader {
+            data,
+        }
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+
+    /// Reads the next element
+    pub fn read(&mut self) -> Result<Tlv<'a>> {
+        let (tag, first_length_octet) = match self.data {
+            [tag, length, ..] => (*tag, *length),
+            _ => return Err(anyhow!("Truncated DER element")),
+        };
+        let (length, header_length) = if first_length_octet < 0x80 {
+            (first_length_octet as usize, 2)
+        } else {
+            let octets = (first_length_octet & 0x7f) as usize;
+            if octets == 0 || octets > 4 || self.data.len() < 2 + octets {
+                return Err(anyhow!("Unsupported DER length encoding"));
+            }
+            let length = self.data[2..2 + octets]
+                .iter()
+                .fold(0usize, |acc, octet| (acc << 8) | *octet as usize);
+            (length, 2 + octets)
+        };
+        if self.data.len() < header_length + length {
+            return Err(anyhow!("Truncated DER element"));
+        }
+        let (raw, rest) = self.data.split_at(header_length + length);
+        self.data = rest;
+        Ok(Tlv {
+            tag,
+            content: &raw[header_length..],
+            raw,
+        })
+    }
+
+
+    /// Reads the next element and checks its tag
+    pub fn expect(&mut self, tag: u8) -> Result<Tlv<'a>> {
+        let tlv = self.read()?;
+        if tlv.tag != tag {
+            return Err(anyhow!(
+                "Unexpected DER tag: {:#04x}, expected: {tag:#04x}",
+                tlv.tag
+            ));
+        }
+        Ok(tlv)
+    }
+}
+
+
+/// Extracts the (OID, value) pair from the DER encoded X.509 Extension
+pub fn extension_value(extension_der: &[u8]) -> Result<(&[u8], &[u8])> {
+    let extension = DerReader::new(extension_der).expect(DER_SEQUENCE)?;
+    let mut fields = DerReader::new(extension.content);
+    let oid = fields.expect(DER_OID)?;
+    let mut value = fields.read()?;
+    if value.tag == DER_BOOLEAN {
+        value = fields.read()?;
+    }
+    if value.tag != DER_OCTET_STRING {
+        return Err(anyhow!("Malformed X.509 extension value"));
+    }
+    Ok((oid.content, value.content))
+}
+
+
+/// Encodes the DER element with given tag and content
+pub fn der_encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if content.len() < 0x80 {
+        encoded.push(content.len() as u8);
+    } else {
+        let length = content.len().to_be_bytes();
+        let length = &length[length.iter().take_while(|&&octet| octet == 0).count()..];
+        encoded.push(0x80 | length.len() as u8);
+        encoded.extend(length);
+    }
+    encoded.extend(content);
+    encoded
+}
@@ -0,0 +1,846 @@
+use crate::*;
+
+use serde::Deserialize;
+use std::{
+    future::Future,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tokio::sync::OnceCell;
+
+
+/// TXT record of the DNS-01 challenge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChallengeRecord {
+    /// Provider specific identifier of the record
+    pub id: String,
+
+    /// Fully qualified name, e.g. "_acme-challenge.example.com"
+    pub name: String,
+    pub content: String,
+}
+
+
+/// DNS backend publishing the DNS-01 challenge records
+pub trait DnsProvider: Sync {
+    /// The challenge names of the order delegated with the CNAME
+    fn delegations(&self) -> &ChallengeDelegations;
+
+    /// Creates the challenge TXT record for the name (the domain itself or its subdomain)
+    fn present(
+        &self,
+        domain: &str,
+        name: &str,
+        content: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Removes all challenge records of the domain
+    fn cleanup(&self, domain: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Lists the challenge records of the domain
+    fn list(&self, domain: &str) -> impl Future<Output = Result<Vec<ChallengeRecord>>> + Send;
+}
+
+
+/// The DNS backend of the account, as configured
+#[derive(Debug, Clone, Deserialize)]
+pub enum DnsProviderConfig {
+    /// The zone of the domain is looked up with the API token if the zone_id isn't set
+    /// Authenticated with the plaintext api_token, or with the auth reading the secrets
+    Cloudflare {
+        #[serde(default)]
+        api_token: String,
+        #[serde(default)]
+        auth: Option<CloudflareAuth>,
+        #[serde(default)]
+        zone_id: Option<String>,
+
+        /// Cleans up also the challenge records of the domain certsd didn't create
+        /// in this run (left behind by the crashed runs, or other ACME clients)
+        #[serde(default)]
+        sweep_stale_records: bool,
+    },
+
+    /// RFC 2136 dynamic updates signed with the TSIG key (the base64 secret)
+    Rfc2136 {
+        /// The primary server, "host:port"
+        server: String,
+
+        /// The zone to update, looked up with the SOA query of the record name by default
+        #[serde(default)]
+        zone: Option<String>,
+        key_name: String,
+        key_algorithm: TsigAlgorithm,
+        key_secret: SecretSource,
+    },
+
+    /// AWS Route 53. The credentials from the configuration, the AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY environment variables or the shared credentials file.
+    Route53 {
+        #[serde(default)]
+        access_key_id: Option<String>,
+        #[serde(default)]
+        secret_access_key: Option<SecretSource>,
+
+        /// The profile of the shared credentials file, AWS_PROFILE or "default" if None
+        #[serde(default)]
+        profile: Option<String>,
+
+        /// The hosted zone of the domain, looked up by the domain name if None
+        #[serde(default)]
+        hosted_zone_id: Option<String>,
+
+        /// The API endpoint, DEFAULT_ROUTE53_ENDPOINT if None
+        #[serde(default)]
+        endpoint: Option<String>,
+    },
+
+    /// PowerDNS Authoritative HTTP API
+    PowerDns {
+        /// The API base, e.g. "http://ns1.example.net:8081"
+        server_url: String,
+        #[serde(default = "default_powerdns_server_id")]
+        server_id: String,
+        api_key: SecretSource,
+
+        /// The zone of the domain, looked up by the domain name if None
+        #[serde(default)]
+        zone: Option<String>,
+    },
+
+    /// Runs the external program (the lego "exec" hooks) for the unsupported DNS providers
+    Exec {
+        program: String,
+
+        /// Arguments put before the action
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default = "default_exec_timeout_secs")]
+        timeout_secs: u64,
+    },
+
+    /// The acme-dns server. The "_acme-challenge" names get registered on the first use
+    /// and have to be delegated with the CNAMEs to their full domains at the server.
+    AcmeDns {
+        server_url: String,
+
+        /// The registrations, "{config data dir}/acme-dns.json" if None. Relative to the
+        /// config data dir
+        #[serde(default)]
+        credentials_file: Option<String>,
+
+        /// CIDR ranges allowed to update the registrations
+        #[serde(default)]
+        allow_from: Vec<String>,
+    },
+
+    /// Keeps the records in the process memory. Nothing gets published, for tests only
+    InMemory,
+}
+
+
+/// The DNS backend of the account, built from its DnsProviderConfig. Keeps the state
+/// of the orders: the presented records and the delegated challenge names.
+#[derive(Debug)]
+pub enum DnsBackend {
+    Cloudflare(CloudflareDns),
+    Rfc2136(Rfc2136Dns),
+    Route53(Route53Dns),
+    PowerDns(PowerDns),
+    Exec(ExecDns),
+    AcmeDns(AcmeDns),
+    InMemory(InMemoryDns),
+}
+
+
+impl DnsProviderConfig {
+    /// The secrets of the provider, read when its backend gets built
+    pub fn secrets_mut(&mut self) -> Vec<&mut SecretSource> {
+        match self {
+            DnsProviderConfig::Cloudflare {
+                auth: Some(CloudflareAuth::ApiToken(api_token)),
+                ..
+            } => vec![api_token],
+            DnsProviderConfig::Cloudflare {
+                auth:
+                    Some(CloudflareAuth::GlobalApiKey {
+                        api_key, ..
+                    }),
+                ..
+            } => vec![api_key],
+            DnsProviderConfig::Rfc2136 {
+                key_secret, ..
+            } => vec![key_secret],
+            DnsProviderConfig::Route53 {
+                secret_access_key, ..
+            } => secret_access_key.iter_mut().collect(),
+            DnsProviderConfig::PowerDns {
+                api_key, ..
+            } => vec![api_key],
+            _ => vec![],
+        }
+    }
+
+
+    /// Builds the backend, resolving its secrets once for the whole order
+    #[instrument(skip(self))]
+    pub async fn build(&self) -> Result<DnsBackend> {
+        let backend = match self {
+            DnsProviderConfig::Cloudflare {
+                api_token,
+                auth,
+                zone_id,
+                sweep_stale_records,
+            } => {
+                DnsBackend::Cloudflare(CloudflareDns::new(
+                    CloudflareCredentials::resolve(api_token, auth.as_ref()).await?,
+                    zone_id.as_deref(),
+                    *sweep_stale_records,
+                )?)
+            }
+            DnsProviderConfig::Rfc2136 {
+                server,
+                zone,
+                key_name,
+                key_algorithm,
+                key_secret,
+            } => {
+                DnsBackend::Rfc2136(
+                    Rfc2136Dns::new(
+                        server,
+                        zone.as_deref(),
+                        key_name,
+                        *key_algorithm,
+                        key_secret,
+                    )
+                    .await?,
+                )
+            }
+            DnsProviderConfig::Route53 {
+                access_key_id,
+                secret_access_key,
+                profile,
+                hosted_zone_id,
+                endpoint,
+            } => {
+                let credentials = AwsCredentials::resolve(
+                    access_key_id.as_deref(),
+                    secret_access_key.as_ref(),
+                    profile.as_deref(),
+                )
+                .await?;
+                DnsBackend::Route53(Route53Dns::new(
+                    endpoint.as_deref().unwrap_or(DEFAULT_ROUTE53_ENDPOINT),
+                    credentials,
+                    hosted_zone_id.as_deref(),
+                ))
+            }
+            DnsProviderConfig::PowerDns {
+                server_url,
+                server_id,
+                api_key,
+                zone,
+            } => {
+                DnsBackend::PowerDns(PowerDns::new(
+                    server_url,
+                    server_id,
+                    &api_key.resolve().await?,
+                    zone.as_deref(),
+                ))
+            }
+            DnsProviderConfig::Exec {
+                program,
+                args,
+                timeout_secs,
+            } => DnsBackend::Exec(ExecDns::new(program, args, *timeout_secs)),
+            DnsProviderConfig::AcmeDns {
+                server_url,
+                credentials_file,
+                allow_from,
+            } => {
+                // resolved against the config data dir before the dir is changed
+                let credentials_file = credentials_file
+                    .as_deref()
+                    .unwrap_or(DEFAULT_ACME_DNS_CREDENTIALS_FILE);
+                DnsBackend::AcmeDns(AcmeDns::new(server_url, credentials_file, allow_from))
+            }
+            DnsProviderConfig::InMemory => DnsBackend::InMemory(InMemoryDns::default()),
+        };
+        Ok(backend)
+    }
+}
+
+
+/// The DNS backends of the accounts, each built on its first use and shared by the
+/// domains of the account, with its caches (e.g. the CloudFlare zone IDs)
+#[derive(Debug, Default)]
+pub struct DnsBackends {
+    accounts: Vec<AccountDnsBackend>,
+}
+
+
+#[derive(Debug)]
+struct AccountDnsBackend {
+    domains: Vec<String>,
+    dns_provider: DnsProviderConfig,
+
+    /// The built backend, or the error of the build
+    backend: OnceCell<Result<DnsBackend, String>>,
+}
+
+
+impl DnsBackends {
+    #[instrument(skip(config))]
+    pub async fn new(config: &Config) -> Self {
+        let mut accounts = vec![];
+        for account in config.accounts.iter() {
+            let domains = account.all_domains();
+            if let Some(domain) = domains.first() {
+                let dns_provider = config.dns_provider_of(domain).await;
+                accounts.push(AccountDnsBackend {
+                    domains,
+                    dns_provider,
+                    backend: OnceCell::new(),
+                });
+            }
+        }
+        DnsBackends {
+            accounts,
+        }
+    }
+
+
+    fn account_of(&self, domain: &str) -> Result<&AccountDnsBackend> {
+        self.accounts
+            .iter()
+            .find(|account| account.domains.iter().any(|listed| listed == domain))
+            .ok_or_else(|| anyhow!("No account of the domain: {domain}"))
+    }
+
+
+    /// The backend of the domain, built on the first use (a failed build is reported
+    /// for all domains of the account)
+    #[instrument(skip(self))]
+    pub async fn of(&self, domain: &str) -> Result<&DnsBackend> {
+        let account = self.account_of(domain)?;
+        account
+            .backend
+            .get_or_init(|| {
+                async {
+                    account
+                        .dns_provider
+                        .build()
+                        .await
+                        .map_err(|err| err.to_string())
+                }
+            })
+            .await
+            .as_ref()
+            .map_err(|err| anyhow!("{err}"))
+    }
+
+
+    /// The CloudFlare backend of the domain (None for the other DNS providers)
+    #[instrument(skip(self))]
+    pub async fn cloudflare_of(&self, domain: &str) -> Option<Result<&CloudflareDns>> {
+        let is_cloudflare = |account: &AccountDnsBackend| {
+            matches!(account.dns_provider, DnsProviderConfig::Cloudflare { .. })
+        };
+        if !self.account_of(domain).is_ok_and(is_cloudflare) {
+            return None;
+        }
+        match self.of(domain).await {
+            Ok(DnsBackend::Cloudflare(cloudflare)) => Some(Ok(cloudflare)),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+
+impl DnsProvider for DnsBackend {
+    fn delegations(&self) -> &ChallengeDelegations {
+        match self {
+            DnsBackend::Cloudflare(provider) => provider.delegations(),
+            DnsBackend::Rfc2136(provider) => provider.delegations(),
+            DnsBackend::Route53(provider) => provider.delegations(),
+            DnsBackend::PowerDns(provider) => provider.delegations(),
+            DnsBackend::Exec(provider) => provider.delegations(),
+            DnsBackend::AcmeDns(provider) => provider.delegations(),
+            DnsBackend::InMemory(provider) => provider.delegations(),
+        }
+    }
+
+
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        match self {
+            DnsBackend::Cloudflare(provider) => provider.present(domain, name, content).await,
+            DnsBackend::Rfc2136(provider) => provider.present(domain, name, content).await,
+            DnsBackend::Route53(provider) => provider.present(domain, name, content).await,
+            DnsBackend::PowerDns(provider) => provider.present(domain, name, content).await,
+            DnsBackend::Exec(provider) => provider.present(domain, name, content).await,
+            DnsBackend::AcmeDns(provider) => provider.present(domain, name, content).await,
+            DnsBackend::InMemory(provider) => provider.present(domain, name, content).await,
+        }
+    }
+
+
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        match self {
+            DnsBackend::Cloudflare(provider) => provider.cleanup(domain).await,
+            DnsBackend::Rfc2136(provider) => provider.cleanup(domain).await,
+            DnsBackend::Route53(provider) => provider.cleanup(domain).await,
+            DnsBackend::PowerDns(provider) => provider.cleanup(domain).await,
+            DnsBackend::Exec(provider) => provider.cleanup(domain).await,
+            DnsBackend::AcmeDns(provider) => provider.cleanup(domain).await,
+            DnsBackend::InMemory(provider) => provider.cleanup(domain).await,
+        }
+    }
+
+
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        match self {
+            DnsBackend::Cloudflare(provider) => provider.list(domain).await,
+            DnsBackend::Rfc2136(provider) => provider.list(domain).await,
+            DnsBackend::Route53(provider) => provider.list(domain).await,
+            DnsBackend::PowerDns(provider) => provider.list(domain).await,
+            DnsBackend::Exec(provider) => provider.list(domain).await,
+            DnsBackend::AcmeDns(provider) => provider.list(domain).await,
+            DnsBackend::InMemory(provider) => provider.list(domain).await,
+        }
+    }
+}
+
+
+fn default_powerdns_server_id() -> String {
+    String::from(DEFAULT_POWERDNS_SERVER_ID)
+}
+
+
+fn default_exec_timeout_secs() -> u64 {
+    DEFAULT_EXEC_TIMEOUT_SECS
+}
+
+
+/// The name of the challenge record of the domain (or its subdomain)
+pub fn challenge_record_name(name: &str) -> String {
+    format!("_acme-challenge.{}", name.trim_end_matches('.'))
+}
+
+
+/// The challenge names of the orders: (domain, "_acme-challenge" name of the presented
+/// name), and the ones delegated with the CNAME: ("_acme-challenge" name, target)
+#[derive(Debug, Default)]
+pub struct ChallengeDelegations {
+    presented: Mutex<Vec<(String, String)>>,
+    delegated: Mutex<Vec<(String, String)>>,
+}
+
+
+impl ChallengeDelegations {
+    /// Where to publish the challenge record of the name: the CNAME target of its
+    /// "_acme-challenge" name if delegated (see follow_cname), or the name itself
+    pub fn record_name_of(&self, name: &str) -> String {
+        let challenge_name = challenge_record_name(name);
+        self.delegated
+            .lock()
+            .ok()
+            .and_then(|delegated| {
+                delegated
+                    .iter()
+                    .find(|(delegated_name, _)| names_equal(delegated_name, &challenge_name))
+                    .map(|(_, target)| target.to_owned())
+            })
+            .unwrap_or(challenge_name)
+    }
+
+
+    /// Where to publish the challenge record of the name (the apex, wildcard or subdomain
+    /// name of the domain order), remembering its "_acme-challenge" name as the domain's
+    pub fn present_name_of(&self, domain: &str, name: &str) -> String {
+        let challenge_name = challenge_record_name(name);
+        if let Ok(mut presented) = self.presented.lock()
+            && !presented.iter().any(|(presented_domain, presented_name)| {
+                presented_domain == domain && names_equal(presented_name, &challenge_name)
+            })
+        {
+            presented.push((domain.to_string(), challenge_name));
+        }
+        self.record_name_of(name)
+    }
+
+
+    /// The "_acme-challenge" names of the domain: its own and of the names presented for it
+    pub fn challenge_names_of(&self, domain: &str) -> Vec<String> {
+        let mut names = vec![challenge_record_name(domain)];
+        if let Ok(presented) = self.presented.lock() {
+            for (_, name) in presented
+                .iter()
+                .filter(|(presented_domain, _)| presented_domain == domain)
+            {
+                if !names.iter().any(|known| names_equal(known, name)) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names
+    }
+
+
+    /// The CNAME targets the challenge records of the domain (or its presented names) are
+    /// delegated to
+    pub fn targets_of(&self, domain: &str) -> Vec<String> {
+        let challenge_names = self.challenge_names_of(domain);
+        self.delegated
+            .lock()
+            .map(|delegated| {
+                delegated
+                    .iter()
+                    .filter(|(challenge_name, _)| {
+                        challenge_names
+                            .iter()
+                            .any(|name| names_equal(name, challenge_name))
+                    })
+                    .map(|(_, target)| target.to_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+
+    /// Checks if the record name is exactly a challenge record name of the domain (or of its
+    /// presented names), including the delegated ones
+    pub fn is_challenge_record_of(&self, record_name: &str, domain: &str) -> bool {
+        self.challenge_names_of(domain)
+            .into_iter()
+            .chain(self.targets_of(domain))
+            .any(|name| names_equal(&name, record_name))
+    }
+
+
+    /// Follows the CNAME chain of the "_acme-challenge" name of the name (the domain
+    /// or its subdomain), so the challenge record gets published in the validation
+    /// zone (or the acme-dns server) it's delegated to. Returns the final name.
+    #[instrument(skip(self))]
+    pub async fn follow_cname(&self, resolver: &str, name: &str) -> Result<String> {
+        let challenge_name = challenge_record_name(name);
+        let mut target = challenge_name.to_owned();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let mut query = DnsMessage::new(random_message_id()?, DNS_OPCODE_QUERY);
+            query.flags |= DNS_FLAG_RECURSION_DESIRED;
+            query
+                .questions
+                .push((target.to_owned(), DNS_TYPE_CNAME, DNS_CLASS_IN));
+            let response =
+                DnsMessage::from_wire(&dns_tcp_exchange(resolver, &query.to_wire()?).await?)?;
+            match response
+                .cname_answers()
+                .into_iter()
+                .find(|(owner, _)| names_equal(owner, &target))
+            {
+                Some((_, next)) => target = next.trim_end_matches('.').to_string(),
+                None => break,
+            }
+        }
+
+        let mut delegated = self
+            .delegated
+            .lock()
+            .map_err(|err| anyhow!("Delegated challenges poisoned: {err}"))?;
+        delegated.retain(|(delegated_name, _)| !names_equal(delegated_name, &challenge_name));
+        if !names_equal(&target, &challenge_name) {
+            info!("Challenge record: {challenge_name} is delegated to: {target}");
+            delegated.push((challenge_name, target.to_owned()));
+        }
+        Ok(target)
+    }
+}
+
+
+/// The first nameserver of /etc/resolv.conf, DEFAULT_DNS_RESOLVER if there's none
+#[instrument]
+pub async fn system_resolver() -> String {
+    tokio::fs::read_to_string("/etc/resolv.conf")
+        .await
+        .ok()
+        .and_then(|resolv_conf| {
+            resolv_conf.lines().find_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some("nameserver"), Some(address)) if address.contains(':') => {
+                        Some(format!("[{address}]:53"))
+                    }
+                    (Some("nameserver"), Some(address)) => Some(format!("{address}:53")),
+                    _ => None,
+                }
+            })
+        })
+        .unwrap_or_else(|| String::from(DEFAULT_DNS_RESOLVER))
+}
+
+
+/// DNS provider keeping the records in the memory of the instance
+#[derive(Debug, Default)]
+pub struct InMemoryDns {
+    records: Mutex<Vec<ChallengeRecord>>,
+    next_record_id: AtomicUsize,
+    delegations: ChallengeDelegations,
+}
+
+
+impl DnsProvider for InMemoryDns {
+    fn delegations(&self) -> &ChallengeDelegations {
+        &self.delegations
+    }
+
+
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        let mut records = self
+            .records
+            .lock()
+            .map_err(|err| anyhow!("In-memory DNS poisoned: {err}"))?;
+        records.push(ChallengeRecord {
+            id: format!(
+                "in-memory-{}",
+                self.next_record_id.fetch_add(1, Ordering::Relaxed)
+            ),
+            name: self.delegations.present_name_of(domain, name),
+            content: content.to_string(),
+        });
+        Ok(())
+    }
+
+
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        self.records
+            .lock()
+            .map_err(|err| anyhow!("In-memory DNS poisoned: {err}"))?
+            .retain(|record| {
+                !self
+                    .delegations
+                    .is_challenge_record_of(&record.name, domain)
+            });
+        Ok(())
+    }
+
+
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        Ok(self
+            .records
+            .lock()
+            .map_err(|err| anyhow!("In-memory DNS poisoned: {err}"))?
+            .iter()
+            .filter(|record| {
+                self.delegations
+                    .is_challenge_record_of(&record.name, domain)
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+
+#[tokio::test]
+async fn test_in_memory_dns_provider() -> Result<()> {
+    let provider = ron::from_str::<DnsProviderConfig>("InMemory")?
+        .build()
+        .await?;
+    let domain = "in-memory.the-domain.com";
+    provider.present(domain, domain, "proof-1").await?;
+    provider
+        .present(domain, &format!("api.{domain}"), "proof-2")
+        .await?;
+    provider
+        .present("other.com", "other.com", "proof-3")
+        .await?;
+    // the subdomain with its own order isn't a name of the domain
+    let unrelated = format!("unrelated.{domain}");
+    provider.present(&unrelated, &unrelated, "proof-4").await?;
+
+    let records = provider.list(domain).await?;
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| (record.name.as_str(), record.content.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            ("_acme-challenge.in-memory.the-domain.com", "proof-1"),
+            ("_acme-challenge.api.in-memory.the-domain.com", "proof-2")
+        ]
+    );
+
+    provider.cleanup(domain).await?;
+    assert!(provider.list(domain).await?.is_empty());
+    assert_eq!(provider.list("other.com").await?.len(), 1);
+    assert_eq!(provider.list(&unrelated).await?.len(), 1);
+    provider.cleanup("other.com").await?;
+    provider.cleanup(&unrelated).await?;
+
+    assert!(
+        !provider.delegations().is_challenge_record_of(
+            "_acme-challenge.the-domain.com.evil.net",
+            "the-domain.com"
+        )
+    );
+    Ok(())
+}
+
+
+#[tokio::test]
+async fn test_follow_challenge_cname() -> Result<()> {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // the resolver delegating _acme-challenge.the-delegated-domain.com to
+    // validation-zone.the-delegated-domain.com, the target compressed
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let resolver = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let length = stream.read_u16().await? as usize;
+            let mut query = vec![0u8; length];
+            stream.read_exact(&mut query).await?;
+            let question = DnsMessage::from_wire(&query)?.questions[0].0.to_owned();
+            let mut response = query[..length].to_vec();
+            response[2] |= 0x80;
+            let delegated_to = match question.as_str() {
+                "_acme-challenge.the-delegated-domain.com" => Some("validation-zone"),
+                "_acme-challenge.api.the-delegated-domain.com" => Some("api-validation-zone"),
+                _ => None,
+            };
+            if let Some(label) = delegated_to {
+                response[7] = 1;
+                response.extend([0xc0, 12]);
+                response.extend(DNS_TYPE_CNAME.to_be_bytes());
+                response.extend(DNS_CLASS_IN.to_be_bytes());
+                response.extend(300u32.to_be_bytes());
+                response.extend((label.len() as u16 + 3).to_be_bytes());
+                response.push(label.len() as u8);
+                response.extend(label.as_bytes());
+                // "the-delegated-domain.com" of the question
+                let suffix_offset = 12 + question.len() - "the-delegated-domain.com".len();
+                response.extend([0xc0, suffix_offset as u8]);
+            }
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    });
+
+    let delegations = ChallengeDelegations::default();
+    let domain = "the-delegated-domain.com";
+    assert_eq!(
+        delegations.follow_cname(&resolver, domain).await?,
+        "validation-zone.the-delegated-domain.com"
+    );
+    assert_eq!(
+        delegations.record_name_of(domain),
+        "validation-zone.the-delegated-domain.com"
+    );
+    assert_eq!(
+        delegations.targets_of(domain),
+        ["validation-zone.the-delegated-domain.com"]
+    );
+    assert!(
+        delegations
+            .is_challenge_record_of("validation-zone.the-delegated-domain.com.", domain)
+    );
+
+    // the delegation of the subdomain ordered on its own isn't the domain's,
+    // unless the subdomain is presented as a name of the domain order
+    let subdomain = "api.the-delegated-domain.com";
+    assert_eq!(
+        delegations.follow_cname(&resolver, subdomain).await?,
+        "api-validation-zone.the-delegated-domain.com"
+    );
+    assert_eq!(
+        delegations.targets_of(domain),
+        ["validation-zone.the-delegated-domain.com"]
+    );
+    assert!(
+        !delegations
+            .is_challenge_record_of("api-validation-zone.the-delegated-domain.com", domain)
+    );
+    assert_eq!(
+        delegations.present_name_of(domain, subdomain),
+        "api-validation-zone.the-delegated-domain.com"
+    );
+    assert_eq!(
+        delegations.targets_of(domain),
+        [
+            "validation-zone.the-delegated-domain.com",
+            "api-validation-zone.the-delegated-domain.com"
+        ]
+    );
+
+    let domain = "api.the-undelegated-domain.com";
+    assert_eq!(
+        delegations.follow_cname(&resolver, domain).await?,
+        "_acme-challenge.api.the-undelegated-domain.com"
+    );
+    assert_eq!(
+        delegations.record_name_of(domain),
+        "_acme-challenge.api.the-undelegated-domain.com"
+    );
+    assert!(
+        delegations
+            .targets_of("the-undelegated-domain.com")
+            .is_empty()
+    );
+    Ok(())
+}
+
+
+#[tokio::test]
+async fn test_dns_backends() -> Result<()> {
+    let config: Config = ron::from_str(
+        r#"(
+            acme_staging: true,
+            notifications: [],
+            accounts: [
+                (
+                    dns_provider: Some(InMemory),
+                    domain: "the-domain.com",
+                    domains: ["api.the-domain.com"],
+                    contacts: [],
+                ),
+                (
+                    dns_provider: Some(InMemory),
+                    domain: "the-other-domain.com",
+                    contacts: [],
+                ),
+                (
+                    cloudflare_api_token: "",
+                    domain: "the-cf-domain.com",
+                    contacts: [],
+                ),
+            ],
+        )"#,
+    )?;
+    let dns_backends = DnsBackends::new(&config).await;
+
+    // the domains of the account share the backend, and its state
+    let backend = dns_backends.of("the-domain.com").await?;
+    backend
+        .present("api.the-domain.com", "api.the-domain.com", "proof")
+        .await?;
+    let shared = dns_backends.of("api.the-domain.com").await?;
+    assert!(std::ptr::eq(backend, shared));
+    assert_eq!(shared.list("api.the-domain.com").await?.len(), 1);
+    let other = dns_backends.of("the-other-domain.com").await?;
+    assert!(!std::ptr::eq(backend, other));
+    assert!(dns_backends.of("the-unknown.com").await.is_err());
+
+    assert!(dns_backends.cloudflare_of("the-domain.com").await.is_none());
+    assert!(
+        dns_backends
+            .cloudflare_of("the-cf-domain.com")
+            .await
+            .is_some_and(|cloudflare| cloudflare.is_err())
+    );
+    Ok(())
+}
@@ -0,0 +1,615 @@
+use crate::*;
+
+use openssl::{hash::MessageDigest, pkey::PKey, rand::rand_bytes, sign::Signer};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+
+pub const DNS_TYPE_SOA: u16 = 6;
+pub const DNS_TYPE_CNAME: u16 = 5;
+pub const DNS_TYPE_TXT: u16 = 16;
+pub const DNS_TYPE_TSIG: u16 = 250;
+
+pub const DNS_CLASS_IN: u16 = 1;
+pub const DNS_CLASS_NONE: u16 = 254;
+pub const DNS_CLASS_ANY: u16 = 255;
+
+pub const DNS_OPCODE_QUERY: u16 = 0;
+pub const DNS_OPCODE_UPDATE: u16 = 5;
+
+/// Header flags: QR (response) and RD (recursion desired)
+pub const DNS_FLAG_RESPONSE: u16 = 0x8000;
+pub const DNS_FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+/// RCODE NOERROR and NXDOMAIN (the name doesn't exist)
+pub const DNS_RCODE_NOERROR: u16 = 0;
+pub const DNS_RCODE_NXDOMAIN: u16 = 3;
+
+/// RCODE NOTAUTH, returned for the TSIG failures
+pub const DNS_RCODE_NOTAUTH: u16 = 9;
+
+/// Allowed difference of the TSIG signing time, in seconds
+const TSIG_FUDGE: u16 = 300;
+
+/// Limit of the followed compression pointers in a single name
+const MAX_COMPRESSION_POINTERS: usize = 64;
+
+
+/// HMAC algorithm of the TSIG key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+
+impl TsigAlgorithm {
+    /// The algorithm name, as put into the TSIG record
+    pub fn name(&self) -> &str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256.",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512.",
+        }
+    }
+
+
+    fn digest(&self) -> MessageDigest {
+        match self {
+            TsigAlgorithm::HmacSha256 => MessageDigest::sha256(),
+            TsigAlgorithm::HmacSha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+
+/// A resource record, with the raw RDATA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceRecord {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+
+/// DNS message (RFC 1035). For the UPDATE (RFC 2136) the sections are the zone,
+/// prerequisite, update and additional ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub flags: u16,
+
+    /// (name, type, class)
+    pub questions: Vec<(String, u16, u16)>,
+    pub answers: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    pub additionals: Vec<ResourceRecord>,
+
+    /// Offset of the TSIG record (the last additional one) in the parsed message
+    pub tsig_offset: Option<usize>,
+}
+
+
+/// TSIG record RDATA (RFC 8945 section 4.2)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TsigRdata {
+    pub algorithm: String,
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+
+impl DnsMessage {
+    pub fn new(id: u16, opcode: u16) -> Self {
+        DnsMessage {
+            id,
+            flags: opcode << 11,
+            ..DnsMessage::default()
+        }
+    }
+
+
+    pub fn opcode(&self) -> u16 {
+        (self.flags >> 11) & 0x0f
+    }
+
+
+    pub fn rcode(&self) -> u16 {
+        self.flags & 0x0f
+    }
+
+
+    pub fn to_wire(&self) -> Result<Vec<u8>> {
+        let mut wire = vec![];
+        wire.extend(self.id.to_be_bytes());
+        wire.extend(self.flags.to_be_bytes());
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            wire.extend((count as u16).to_be_bytes());
+        }
+        for (name, record_type, class) in self.questions.iter() {
+            wire.extend(encode_name(name)?);
+            wire.extend(record_type.to_be_bytes());
+            wire.extend(class.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additionals.iter())
+        {
+            wire.extend(encode_name(&record.name)?);
+            wire.extend(record.record_type.to_be_bytes());
+            wire.extend(record.class.to_be_bytes());
+            wire.extend(record.ttl.to_be_bytes());
+            wire.extend((record.rdata.len() as u16).to_be_bytes());
+            wire.extend(&record.rdata);
+        }
+        Ok(wire)
+    }
+
+
+    pub fn from_wire(wire: &[u8]) -> Result<Self> {
+        let mut reader = WireReader::new(wire);
+        let mut message = DnsMessage {
+            id: reader.u16()?,
+            flags: reader.u16()?,
+            ..DnsMessage::default()
+        };
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+        for _ in 0..counts[0] {
+            message
+                .questions
+                .push((reader.name()?, reader.u16()?, reader.u16()?));
+        }
+        for (section, count) in counts.iter().enumerate().skip(1) {
+            for _ in 0..*count {
+                let offset = reader.position;
+                let name = reader.name()?;
+                let record_type = reader.u16()?;
+                let class = reader.u16()?;
+                let ttl = reader.u32()?;
+                let length = reader.u16()? as usize;
+                let rdata_offset = reader.position;
+                let mut rdata = reader.bytes(length)?.to_vec();
+                if record_type == DNS_TYPE_CNAME {
+                    // the target may be compressed, so it's kept uncompressed
+                    let mut target = WireReader {
+                        wire,
+                        position: rdata_offset,
+                    };
+                    rdata = encode_name(&target.name()?)?;
+                }
+                let record = ResourceRecord {
+                    name,
+                    record_type,
+                    class,
+                    ttl,
+                    rdata,
+                };
+                match section {
+                    1 => message.answers.push(record),
+                    2 => message.authorities.push(record),
+                    _ => {
+                        message.tsig_offset =
+                            (record.record_type == DNS_TYPE_TSIG).then_some(offset);
+                        message.additionals.push(record);
+                    }
+                }
+            }
+        }
+        Ok(message)
+    }
+
+
+    /// The CNAME answers: (name, target)
+    pub fn cname_answers(&self) -> Vec<(String, String)> {
+        self.answers
+            .iter()
+            .filter(|record| record.record_type == DNS_TYPE_CNAME)
+            .filter_map(|record| {
+                let target = WireReader::new(&record.rdata).name().ok()?;
+                Some((record.name.to_owned(), target))
+            })
+            .collect()
+    }
+
+
+    /// The TXT answers: (name, text)
+    pub fn txt_answers(&self) -> Vec<(String, String)> {
+        self.answers
+            .iter()
+            .filter(|record| record.record_type == DNS_TYPE_TXT)
+            .map(|record| (record.name.to_owned(), parse_txt_rdata(&record.rdata)))
+            .collect()
+    }
+}
+
+
+/// TSIG key (RFC 8945) signing the requests and verifying the responses
+#[derive(Debug, Clone)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+
+impl TsigKey {
+    /// Appends the TSIG record to the message and returns its MAC. The responses
+    /// are signed over the MAC of the request.
+    pub fn sign(
+        &self,
+        message: &mut DnsMessage,
+        request_mac: Option<&[u8]>,
+        time_signed: u64,
+    ) -> Result<Vec<u8>> {
+        let mut tsig = TsigRdata {
+            algorithm: self.algorithm.name().to_string(),
+            time_signed,
+            fudge: TSIG_FUDGE,
+            mac: vec![],
+            original_id: message.id,
+            error: 0,
+            other: vec![],
+        };
+        tsig.mac = self.mac(&message.to_wire()?, &tsig, request_mac)?;
+        message.additionals.push(ResourceRecord {
+            name: self.name.to_owned(),
+            record_type: DNS_TYPE_TSIG,
+            class: DNS_CLASS_ANY,
+            ttl: 0,
+            rdata: encode_tsig_rdata(&tsig)?,
+        });
+        Ok(tsig.mac)
+    }
+
+
+    /// Verifies the TSIG record of the message parsed from the wire and returns its MAC
+    pub fn verify(
+        &self,
+        wire: &[u8],
+        message: &DnsMessage,
+        request_mac: Option<&[u8]>,
+        now: u64,
+    ) -> Result<Vec<u8>> {
+        let (Some(tsig_offset), Some(tsig_record)) =
+            (message.tsig_offset, message.additionals.last())
+        else {
+            return Err(anyhow!("The DNS message isn't signed"));
+        };
+        if !names_equal(&tsig_record.name, &self.name) {
+            return Err(anyhow!(
+                "The DNS message is signed with the unknown key: {}",
+                tsig_record.name
+            ));
+        }
+        let tsig = parse_tsig_rdata(&tsig_record.rdata)?;
+        if !names_equal(&tsig.algorithm, self.algorithm.name()) {
+            return Err(anyhow!("Unexpected TSIG algorithm: {}", tsig.algorithm));
+        }
+
+        // the MAC covers the message without the TSIG record, with the original ID
+        let mut unsigned = wire[..tsig_offset].to_vec();
+        unsigned[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let additional_count = u16::from_be_bytes([unsigned[10], unsigned[11]]) - 1;
+        unsigned[10..12].copy_from_slice(&additional_count.to_be_bytes());
+        let expected_mac = self.mac(&unsigned, &tsig, request_mac)?;
+        if !openssl::memcmp::eq(&expected_mac, &tsig.mac) {
+            return Err(anyhow!("Invalid TSIG signature of the key: {}", self.name));
+        }
+        if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            return Err(anyhow!(
+                "The TSIG signing time is outside of the allowed fudge"
+            ));
+        }
+        Ok(tsig.mac)
+    }
+
+
+    fn mac(
+        &self,
+        message: &[u8],
+        tsig: &TsigRdata,
+        request_mac: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let key = PKey::hmac(&self.secret)?;
+        let mut signer = Signer::new(self.algorithm.digest(), &key)?;
+        if let Some(request_mac) = request_mac {
+            signer.update(&(request_mac.len() as u16).to_be_bytes())?;
+            signer.update(request_mac)?;
+        }
+        signer.update(message)?;
+        // TSIG variables (RFC 8945 section 4.3.3), the names in the canonical form
+        signer.update(&encode_name(&self.name.to_lowercase())?)?;
+        signer.update(&DNS_CLASS_ANY.to_be_bytes())?;
+        signer.update(&0u32.to_be_bytes())?;
+        signer.update(&encode_name(&tsig.algorithm.to_lowercase())?)?;
+        signer.update(&tsig.time_signed.to_be_bytes()[2..])?;
+        signer.update(&tsig.fudge.to_be_bytes())?;
+        signer.update(&tsig.error.to_be_bytes())?;
+        signer.update(&(tsig.other.len() as u16).to_be_bytes())?;
+        signer.update(&tsig.other)?;
+        Ok(signer.sign_to_vec()?)
+    }
+}
+
+
+/// Sends the message over TCP (RFC 7766 framing) and returns the response
+#[instrument(skip(wire))]
+pub async fn dns_tcp_exchange(server: &str, wire: &[u8]) -> Result<Vec<u8>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(server).await?;
+        let mut request = (wire.len() as u16).to_be_bytes().to_vec();
+        request.extend(wire);
+        stream.write_all(&request).await?;
+        let length = stream.read_u16().await? as usize;
+        let mut response = vec![0u8; length];
+        stream.read_exact(&mut response).await?;
+        Ok::<Vec<u8>, anyhow::Error>(response)
+    };
+    timeout(Duration::from_secs(DEFAULT_DNS_TIMEOUT_SECS), exchange)
+        .await
+        .map_err(|_| anyhow!("The DNS server: {server} didn't respond in time"))?
+}
+
+
+pub fn random_message_id() -> Result<u16> {
+    let mut id = [0u8; 2];
+    rand_bytes(&mut id)?;
+    Ok(u16::from_be_bytes(id))
+}
+
+
+/// Encodes the domain name in the uncompressed wire format
+pub fn encode_name(name: &str) -> Result<Vec<u8>> {
+    let mut wire = vec![];
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+    {
+        if label.len() > 63 {
+            return Err(anyhow!("The DNS label: {label} is too long"));
+        }
+        wire.push(label.len() as u8);
+        wire.extend(label.as_bytes());
+    }
+    wire.push(0);
+    if wire.len() > 255 {
+        return Err(anyhow!("The DNS name: {name} is too long"));
+    }
+    Ok(wire)
+}
+
+
+/// Compares the domain names (case insensitive, with or without the trailing dot)
+pub fn names_equal(name: &str, other: &str) -> bool {
+    name.trim_end_matches('.')
+        .eq_ignore_ascii_case(other.trim_end_matches('.'))
+}
+
+
+/// TXT RDATA of the text, split into the 255 octet character strings
+pub fn txt_rdata(text: &str) -> Vec<u8> {
+    let mut rdata = vec![];
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend(chunk);
+    }
+    if rdata.is_empty() {
+        rdata.push(0);
+    }
+    rdata
+}
+
+
+/// Joins the character strings of the TXT RDATA
+pub fn parse_txt_rdata(rdata: &[u8]) -> String {
+    let mut text = vec![];
+    let mut rest = rdata;
+    while let [length, tail @ ..] = rest {
+        let length = (*length as usize).min(tail.len());
+        text.extend(&tail[..length]);
+        rest = &tail[length..];
+    }
+    String::from_utf8_lossy(&text).to_string()
+}
+
+
+fn encode_tsig_rdata(tsig: &TsigRdata) -> Result<Vec<u8>> {
+    let mut rdata = encode_name(&tsig.algorithm)?;
+    rdata.extend(&tsig.time_signed.to_be_bytes()[2..]);
+    rdata.extend(tsig.fudge.to_be_bytes());
+    rdata.extend((tsig.mac.len() as u16).to_be_bytes());
+    rdata.extend(&tsig.mac);
+    rdata.extend(tsig.original_id.to_be_bytes());
+    rdata.extend(tsig.error.to_be_bytes());
+    rdata.extend((tsig.other.len() as u16).to_be_bytes());
+    rdata.extend(&tsig.other);
+    Ok(rdata)
+}
+
+
+pub fn parse_tsig_rdata(rdata: &[u8]) -> Result<TsigRdata> {
+    let mut reader = WireReader::new(rdata);
+    let algorithm = reader.name()?;
+    let time_signed = reader
+        .bytes(6)?
+        .iter()
+        .fold(0u64, |acc, octet| (acc << 8) | *octet as u64);
+    let fudge = reader.u16()?;
+    let mac_length = reader.u16()? as usize;
+    let mac = reader.bytes(mac_length)?.to_vec();
+    let original_id = reader.u16()?;
+    let error = reader.u16()?;
+    let other_length = reader.u16()? as usize;
+    let other = reader.bytes(other_length)?.to_vec();
+    Ok(TsigRdata {
+        algorithm,
+        time_signed,
+        fudge,
+        mac,
+        original_id,
+        error,
+        other,
+    })
+}
+
+
+/// Reader of the DNS wire format, following the name compression pointers
+struct WireReader<'a> {
+    wire: &'a [u8],
+    position: usize,
+}
+
+
+impl<'a> WireReader<'a> {
+    fn new(wire: &'a [u8]) -> Self {
+        WireReader {
+            wire,
+            position: 0,
+        }
+    }
+
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .wire
+            .get(self.position..self.position + length)
+            .ok_or_else(|| anyhow!("Truncated DNS message"))?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+
+    fn name(&mut self) -> Result<String> {
+        let mut labels = vec![];
+        let mut position = self.position;
+        let mut end_position = None;
+        for _ in 0..MAX_COMPRESSION_POINTERS {
+            let length = *self
+                .wire
+                .get(position)
+                .ok_or_else(|| anyhow!("Truncated DNS name"))?
+                as usize;
+            match length {
+                0 => {
+                    self.position = end_position.unwrap_or(position + 1);
+                    return Ok(labels.join("."));
+                }
+                length if length & 0xc0 == 0xc0 => {
+                    let low = *self
+                        .wire
+                        .get(position + 1)
+                        .ok_or_else(|| anyhow!("Truncated DNS name"))?
+                        as usize;
+                    end_position.get_or_insert(position + 2);
+                    position = ((length & 0x3f) << 8) | low;
+                }
+                length => {
+                    let label = self
+                        .wire
+                        .get(position + 1..position + 1 + length)
+                        .ok_or_else(|| anyhow!("Truncated DNS name"))?;
+                    labels.push(String::from_utf8_lossy(label).to_string());
+                    position += 1 + length;
+                }
+            }
+        }
+        Err(anyhow!("Too many DNS name compression pointers"))
+    }
+}
+
+
+#[test]
+fn test_dns_message() -> Result<()> {
+    let key = TsigKey {
+        name: String::from("certsd-key."),
+        algorithm: TsigAlgorithm::HmacSha512,
+        secret: b"the-secret".to_vec(),
+    };
+    let mut update = DnsMessage::new(0x1234, DNS_OPCODE_UPDATE);
+    update
+        .questions
+        .push((String::from("the-domain.com"), DNS_TYPE_SOA, DNS_CLASS_IN));
+    update.authorities.push(ResourceRecord {
+        name: String::from("_acme-challenge.the-domain.com"),
+        record_type: DNS_TYPE_TXT,
+        class: DNS_CLASS_IN,
+        ttl: 60,
+        rdata: txt_rdata("the-proof"),
+    });
+    let request_mac = key.sign(&mut update, None, 1_700_000_000)?;
+    let wire = update.to_wire()?;
+
+    let parsed = DnsMessage::from_wire(&wire)?;
+    assert_eq!(parsed.opcode(), DNS_OPCODE_UPDATE);
+    assert_eq!(parsed.authorities, update.authorities);
+    assert_eq!(
+        key.verify(&wire, &parsed, None, 1_700_000_100)?,
+        request_mac
+    );
+    assert!(key.verify(&wire, &parsed, None, 1_700_001_000).is_err());
+    let mut tampered = wire.clone();
+    tampered[20] ^= 1;
+    assert!(
+        key.verify(
+            &tampered,
+            &DnsMessage::from_wire(&tampered)?,
+            None,
+            1_700_000_000
+        )
+        .is_err()
+    );
+
+    // compressed names: "_acme-challenge" + pointer to the question name
+    let mut response = DnsMessage::new(0x1234, DNS_OPCODE_QUERY).to_wire()?;
+    response[5] = 1;
+    response[7] = 1;
+    response.extend(encode_name("the-domain.com")?);
+    response.extend(DNS_TYPE_TXT.to_be_bytes());
+    response.extend(DNS_CLASS_IN.to_be_bytes());
+    response.push(15);
+    response.extend(b"_acme-challenge");
+    response.extend([0xc0, 12]);
+    response.extend(DNS_TYPE_TXT.to_be_bytes());
+    response.extend(DNS_CLASS_IN.to_be_bytes());
+    response.extend(60u32.to_be_bytes());
+    let rdata = txt_rdata(&"x".repeat(300));
+    response.extend((rdata.len() as u16).to_be_bytes());
+    response.extend(&rdata);
+    let response = DnsMessage::from_wire(&response)?;
+    assert_eq!(
+        response.txt_answers(),
+        [(
+            String::from("_acme-challenge.the-domain.com"),
+            "x".repeat(300)
+        )]
+    );
+    Ok(())
+}
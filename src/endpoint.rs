@@ -0,0 +1,252 @@
+use crate::*;
+
+use openssl::{
+    hash::MessageDigest,
+    ssl::{SslConnector, SslMethod, SslVerifyMode},
+    x509::X509,
+};
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
+
+
+/// Certificate served by the deployed endpoint, compared with the current one
+#[derive(Debug, Clone)]
+pub struct EndpointState {
+    /// The endpoint as configured: "host:port"
+    pub endpoint: String,
+
+    /// SHA-256 fingerprint of the leaf certificate served by the endpoint
+    pub served_fingerprint: String,
+
+    /// Whether the served leaf is one of the current certificates of the domain
+    pub up_to_date: bool,
+}
+
+
+/// Checks all configured endpoints of the domain against its current apex and wildcard
+/// certificates. Endpoints that couldn't be reached are logged and reported as failures.
+#[instrument(skip(config))]
+pub async fn verify_endpoints(config: &Config, domain: &str) -> Vec<EndpointState> {
+    let domain_dirs = [false, true].map(|wildcard| domain_dir_of(domain, wildcard));
+    verify_endpoints_in(config, domain, &domain_dirs).await
+}
+
+
+/// Checks all configured endpoints of the domain against the certificates of the domain dirs
+#[instrument(skip(config))]
+pub async fn verify_endpoints_in(
+    config: &Config,
+    domain: &str,
+    domain_dirs: &[String],
+) -> Vec<EndpointState> {
+    let endpoints = config.endpoints_of(domain).await;
+    if endpoints.is_empty() {
+        return vec![];
+    }
+
+    let mut current_fingerprints = vec![];
+    for domain_dir in domain_dirs {
+        let certificate_file = format!("{domain_dir}/chained.pem");
+        if !Path::new(&certificate_file).exists() {
+            continue;
+        }
+        match certificate_fingerprint_of(&certificate_file).await {
+            Ok(fingerprint) => current_fingerprints.push(fingerprint),
+            Err(err) => error!("Couldn't read the certificate: {certificate_file}: {err}"),
+        }
+    }
+
+    let mut states = vec![];
+    for endpoint in endpoints {
+        match served_certificate_fingerprint(&endpoint, domain).await {
+            Ok(served_fingerprint) => {
+                let up_to_date = current_fingerprints.contains(&served_fingerprint);
+                if up_to_date {
+                    info!("Endpoint: {endpoint} serves the current certificate");
+                } else {
+                    warn!(
+                        "Endpoint: {endpoint} serves a stale certificate with fingerprint: {served_fingerprint}"
+                    );
+                }
+                states.push(EndpointState {
+                    endpoint,
+                    served_fingerprint,
+                    up_to_date,
+                });
+            }
+            Err(err) => {
+                let error_msg = &format!("Couldn't verify the endpoint: {endpoint}: {err}");
+                error!(error_msg);
+                notify_failure(config, domain, error_msg)
+                    .await
+                    .unwrap_or_default();
+            }
+        }
+    }
+    states
+}
+
+
+/// SHA-256 fingerprint of the leaf (first) certificate of the PEM chain
+#[instrument]
+pub async fn certificate_fingerprint_of(certificate_file: &str) -> Result<String> {
+    let certificate = X509::from_pem(&tokio::fs::read(certificate_file).await?)?;
+    certificate_fingerprint(&certificate)
+}
+
+
+/// Formats the SHA-256 fingerprint as "AB:CD:..."
+pub fn certificate_fingerprint(certificate: &X509) -> Result<String> {
+    Ok(certificate
+        .digest(MessageDigest::sha256())?
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<String>>()
+        .join(":"))
+}
+
+
+/// Connects to the "host:port" endpoint (sending the domain as SNI, so the endpoints
+/// given by the IP address select the certificate of the domain too) and returns
+/// the fingerprint of the leaf certificate it serves
+#[instrument]
+pub async fn served_certificate_fingerprint(endpoint: &str, domain: &str) -> Result<String> {
+    let (host, port) = endpoint
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("The endpoint: {endpoint} isn't in the host:port format"))?;
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = port.parse::<u16>()?;
+    let domain = domain.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        let address = (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Couldn't resolve: {host}"))?;
+        let timeout = Duration::from_secs(DEFAULT_ENDPOINT_TIMEOUT_SECS);
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        // we compare the fingerprints, the chain doesn't have to be trusted (e.g. staging)
+        let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        let mut configuration = connector.build().configure()?;
+        configuration.set_verify_hostname(false);
+        let mut tls_stream = configuration
+            .connect(&domain, stream)
+            .map_err(|err| anyhow!("TLS handshake with: {host}:{port} failed: {err}"))?;
+        let certificate = tls_stream.ssl().peer_certificate().ok_or_else(|| {
+            anyhow!("The endpoint: {host}:{port} didn't present a certificate")
+        })?;
+        let _ = tls_stream.shutdown();
+        certificate_fingerprint(&certificate)
+    })
+    .await?
+}
+
+
+#[tokio::test]
+async fn test_verify_endpoints() -> Result<()> {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::{PKey, Private},
+        ssl::{NameType, SslAcceptor},
+        x509::X509NameBuilder,
+    };
+    use std::sync::{Arc, Mutex};
+
+    let self_signed = |common_name: &str| -> Result<(PKey<Private>, X509)> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        let name = name.build();
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&pkey)?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(1)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        builder.sign(&pkey, MessageDigest::sha256())?;
+        Ok((pkey, builder.build()))
+    };
+
+    // the local TLS listener serves the "deployed" certificate
+    let (served_key, served_certificate) = self_signed("localhost")?;
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_private_key(&served_key)?;
+    acceptor.set_certificate(&served_certificate)?;
+    // the server names sent by the client
+    let server_names = Arc::new(Mutex::new(vec![]));
+    let sent_server_names = server_names.clone();
+    acceptor.set_servername_callback(move |ssl, _| {
+        if let Ok(mut server_names) = sent_server_names.lock() {
+            server_names.push(ssl.servername(NameType::HOST_NAME).map(String::from));
+        }
+        Ok(())
+    });
+    let acceptor = acceptor.build();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let local_addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Ok(mut tls_stream) = acceptor.accept(stream) {
+                let _ = tls_stream.shutdown();
+            }
+        }
+    });
+
+    let domain = format!("certsd-endpoint-{}.test", std::process::id());
+    let config = Config {
+        accounts: vec![CloudFlareAccount {
+            domain: domain.to_owned(),
+            endpoints: vec![local_addr.to_string()],
+            ..CloudFlareAccount::default()
+        }],
+        ..Config::default()
+    };
+    let domain_dir = std::env::temp_dir()
+        .join(&domain)
+        .to_string_lossy()
+        .to_string();
+    tokio::fs::create_dir_all(&domain_dir).await?;
+    let certificate_file = format!("{domain_dir}/chained.pem");
+    let domain_dirs = [domain_dir.to_owned()];
+
+    tokio::fs::write(&certificate_file, served_certificate.to_pem()?).await?;
+    let deployed = verify_endpoints_in(&config, &domain, &domain_dirs).await;
+
+    let (_, renewed_certificate) = self_signed("localhost")?;
+    tokio::fs::write(&certificate_file, renewed_certificate.to_pem()?).await?;
+    let stale = verify_endpoints_in(&config, &domain, &domain_dirs).await;
+    tokio::fs::remove_dir_all(&domain_dir).await?;
+
+    assert_eq!(deployed.len(), 1);
+    assert!(deployed[0].up_to_date);
+    assert_eq!(
+        deployed[0].served_fingerprint,
+        certificate_fingerprint(&served_certificate)?
+    );
+    assert_eq!(stale.len(), 1);
+    assert!(!stale[0].up_to_date);
+    // the endpoint is given by the IP address, the SNI is still the domain
+    assert_eq!(
+        *server_names
+            .lock()
+            .map_err(|err| anyhow!("Server names poisoned: {err}"))?,
+        [Some(domain.to_owned()), Some(domain)]
+    );
+    Ok(())
+}
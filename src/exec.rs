@@ -0,0 +1,224 @@
+use crate::*;
+
+use std::{process::Stdio, sync::Mutex, time::Duration};
+use tokio::{process::Command, time::timeout};
+
+
+/// TTL of the published challenge records, passed to the program
+const CHALLENGE_RECORD_TTL: u32 = 60;
+
+
+/// DNS provider running an external program, compatible with the lego "exec" hooks:
+/// `program present|cleanup <fqdn> <value>`, the FQDN with the trailing dot.
+/// The same is passed in CERTSD_FQDN, CERTSD_TXT_VALUE, CERTSD_TTL and CERTSD_DOMAIN.
+#[derive(Debug)]
+pub struct ExecDns {
+    program: String,
+
+    /// Arguments put before the action
+    args: Vec<String>,
+    timeout_secs: u64,
+
+    /// Records presented by the provider: (domain, FQDN with the trailing dot, value).
+    /// The program gets both on the cleanup, as the lego exec provider does.
+    presented_records: Mutex<Vec<(String, String, String)>>,
+    delegations: ChallengeDelegations,
+}
+
+
+impl ExecDns {
+    pub fn new(program: &str, args: &[String], timeout_secs: u64) -> Self {
+        ExecDns {
+            program: program.to_string(),
+            args: args.to_vec(),
+            timeout_secs,
+            presented_records: Mutex::new(vec![]),
+            delegations: ChallengeDelegations::default(),
+        }
+    }
+
+
+    /// Runs the program with the action and checks its exit code
+    #[instrument(skip(self))]
+    async fn run(&self, action: &str, domain: &str, fqdn: &str, value: &str) -> Result<()> {
+        let child = Command::new(&self.program)
+            .args(&self.args)
+            .args([action, fqdn, value])
+            .env("CERTSD_ACTION", action)
+            .env("CERTSD_DOMAIN", domain)
+            .env("CERTSD_FQDN", fqdn)
+            .env("CERTSD_TXT_VALUE", value)
+            .env("CERTSD_TTL", CHALLENGE_RECORD_TTL.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| anyhow!("Couldn't run the DNS hook: {}: {err}", self.program))?;
+        let output = timeout(
+            Duration::from_secs(self.timeout_secs),
+            child.wait_with_output(),
+        )
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "The DNS hook: {} {action} didn't finish in {}s",
+                self.program,
+                self.timeout_secs
+            )
+        })??;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !stdout.trim().is_empty() {
+            debug!("DNS hook output: {}", stdout.trim());
+        }
+        if !output.status.success() {
+            return Err(anyhow!(
+                "The DNS hook: {} {action} failed with: {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+
+    fn presented_records_of(&self, domain: &str) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .iter()
+            .filter(|(presented_domain, ..)| presented_domain == domain)
+            .map(|(_, fqdn, value)| (fqdn.to_owned(), value.to_owned()))
+            .collect())
+    }
+}
+
+
+impl DnsProvider for ExecDns {
+    fn delegations(&self) -> &ChallengeDelegations {
+        &self.delegations
+    }
+
+
+    #[instrument(skip(self))]
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        let fqdn = format!("{}.", self.delegations.present_name_of(domain, name));
+        self.run("present", domain, &fqdn, content).await?;
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .push((domain.to_string(), fqdn, content.to_string()));
+        Ok(())
+    }
+
+
+    /// Cleans up the records presented by the provider (the program can't list them)
+    #[instrument(skip(self))]
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        for (fqdn, value) in self.presented_records_of(domain)? {
+            self.run("cleanup", domain, &fqdn, &value).await?;
+            self.presented_records
+                .lock()
+                .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+                .retain(|record| {
+                    record != &(domain.to_string(), fqdn.to_owned(), value.to_owned())
+                });
+        }
+        Ok(())
+    }
+
+
+    /// The records presented by the provider
+    #[instrument(skip(self))]
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        Ok(self
+            .presented_records_of(domain)?
+            .into_iter()
+            .map(|(fqdn, value)| {
+                ChallengeRecord {
+                    id: format!("{fqdn} {value}"),
+                    name: fqdn,
+                    content: value,
+                }
+            })
+            .collect())
+    }
+}
+
+
+#[tokio::test]
+async fn test_exec_dns_provider() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let hook_dir = std::env::temp_dir().join(format!("certsd-exec-{}", std::process::id()));
+    tokio::fs::create_dir_all(&hook_dir).await?;
+    let hook_dir = hook_dir.to_string_lossy().to_string();
+    let hook = format!("{hook_dir}/hook.sh");
+    tokio::fs::write(
+        &hook,
+        format!(
+            "#!/bin/sh\n\
+             [ \"$1\" = \"--zone\" ] || exit 2\n\
+             [ \"$5\" = \"fail\" ] && {{ echo \"the API said no\" >&2; exit 3; }}\n\
+             [ \"$5\" = \"hang\" ] && exec sleep 10\n\
+             echo \"$3 $4 $5 $CERTSD_DOMAIN $CERTSD_TTL\" >> {hook_dir}/hook.log\n"
+        ),
+    )
+    .await?;
+    let mut permissions = tokio::fs::metadata(&hook).await?.permissions();
+    permissions.set_mode(0o755);
+    tokio::fs::set_permissions(&hook, permissions).await?;
+
+    let provider = ron::from_str::<DnsProviderConfig>(&format!(
+        r#"Exec(program: "{hook}", args: ["--zone", "the-exec-domain.com"], timeout_secs: 1)"#
+    ))?
+    .build()
+    .await?;
+    let domain = "the-exec-domain.com";
+    let presented = async {
+        provider.present(domain, domain, "proof-1").await?;
+        provider
+            .present(domain, &format!("api.{domain}"), "proof-2")
+            .await?;
+        provider.list(domain).await
+    }
+    .await;
+    let cleaned = provider.cleanup(domain).await;
+    let listed_after_cleanup = provider.list(domain).await;
+    let failed = provider.present(domain, domain, "fail").await;
+    let hung = provider.present(domain, domain, "hang").await;
+    let log = tokio::fs::read_to_string(format!("{hook_dir}/hook.log")).await;
+    tokio::fs::remove_dir_all(&hook_dir).await?;
+
+    assert_eq!(
+        presented?
+            .iter()
+            .map(|record| (record.name.as_str(), record.content.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            ("_acme-challenge.the-exec-domain.com.", "proof-1"),
+            ("_acme-challenge.api.the-exec-domain.com.", "proof-2")
+        ]
+    );
+    cleaned?;
+    assert!(listed_after_cleanup?.is_empty());
+    assert_eq!(
+        log?,
+        "present _acme-challenge.the-exec-domain.com. proof-1 the-exec-domain.com 60\n\
+         present _acme-challenge.api.the-exec-domain.com. proof-2 the-exec-domain.com 60\n\
+         cleanup _acme-challenge.the-exec-domain.com. proof-1 the-exec-domain.com 60\n\
+         cleanup _acme-challenge.api.the-exec-domain.com. proof-2 the-exec-domain.com 60\n"
+    );
+    assert!(
+        failed
+            .err()
+            .is_some_and(|err| err.to_string().ends_with("exit status: 3: the API said no"))
+    );
+    assert!(
+        hung.err()
+            .is_some_and(|err| err.to_string().ends_with("present didn't finish in 1s"))
+    );
+    Ok(())
+}
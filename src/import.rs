@@ -0,0 +1,551 @@
+use crate::*;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use openssl::{bn::BigNum, ec::EcKey, nid::Nid, rsa::Rsa, x509::X509};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+
+/// Certificate lineage (live/archive/renewal entry) imported from certbot
+#[derive(Debug, Clone)]
+pub struct CertbotLineage {
+    /// The certbot lineage name (the renewal/*.conf file name)
+    pub name: String,
+
+    /// The domain of the generated account entry
+    pub domain: String,
+
+    /// All names of the certificate
+    pub names: Vec<String>,
+
+    /// The certbot ACME account the lineage was renewed with
+    pub account: Option<String>,
+    pub staging: bool,
+
+    /// certsd dirs the key and certificate were copied into
+    pub domain_dirs: Vec<String>,
+}
+
+
+/// Everything imported from the certbot directory
+#[derive(Debug, Clone, Default)]
+pub struct CertbotImport {
+    pub lineages: Vec<CertbotLineage>,
+    pub contacts: Vec<String>,
+    pub account_key_imported: bool,
+}
+
+
+/// certbot JSON Web Key of the account (private_key.json): the EC or the RSA (default) one
+#[derive(Debug, Deserialize)]
+struct CertbotAccountKey {
+    kty: String,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+    d: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    p: Option<String>,
+    q: Option<String>,
+    dp: Option<String>,
+    dq: Option<String>,
+    qi: Option<String>,
+}
+
+
+/// certbot account registration (regr.json)
+#[derive(Debug, Deserialize)]
+struct CertbotRegistration {
+    body: CertbotRegistrationBody,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct CertbotRegistrationBody {
+    #[serde(default)]
+    contact: Vec<String>,
+}
+
+
+/// Imports the certbot lineages and account key into the certsd data dir.
+/// Files already present in the data dir are never overwritten.
+#[instrument]
+pub async fn import_certbot(letsencrypt_dir: &str, data_dir: &str) -> Result<CertbotImport> {
+    let renewal_dir = format!("{letsencrypt_dir}/renewal");
+    let mut renewal_files = std::fs::read_dir(&renewal_dir)
+        .map_err(|err| anyhow!("Couldn't read the certbot renewal dir: {renewal_dir}: {err}"))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "conf")
+        })
+        .collect::<Vec<PathBuf>>();
+    renewal_files.sort();
+
+    let mut import = CertbotImport::default();
+    for renewal_file in renewal_files {
+        let name = renewal_file
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match import_lineage(letsencrypt_dir, data_dir, &name, &renewal_file).await {
+            Ok(lineage) => import.lineages.push(lineage),
+            Err(err) => error!("Couldn't import the certbot lineage: {name}: {err}"),
+        }
+    }
+
+    // certsd uses a single account; prefer the one of the production lineages
+    let mut accounts = import
+        .lineages
+        .iter()
+        .filter(|lineage| !lineage.staging)
+        .chain(import.lineages.iter().filter(|lineage| lineage.staging))
+        .filter_map(|lineage| lineage.account.to_owned())
+        .collect::<Vec<String>>();
+    accounts.dedup();
+    if let Some(account) = accounts.first() {
+        if accounts.len() > 1 {
+            warn!("Multiple certbot accounts found: {accounts:?}. Importing only: {account}");
+        }
+        let account_key_file = format!("{data_dir}/{DEFAULT_ACCOUNT_KEY_FILE}");
+        match import_account(letsencrypt_dir, account, &account_key_file).await {
+            Ok(contacts) => {
+                import.contacts = contacts;
+                import.account_key_imported = true;
+            }
+            Err(err) => warn!("The certbot account: {account} wasn't imported: {err}"),
+        }
+    }
+    Ok(import)
+}
+
+
+#[instrument(skip(renewal_file))]
+async fn import_lineage(
+    letsencrypt_dir: &str,
+    data_dir: &str,
+    name: &str,
+    renewal_file: &Path,
+) -> Result<CertbotLineage> {
+    let renewal = parse_renewal_conf(&tokio::fs::read_to_string(renewal_file).await?);
+    let fullchain_file = lineage_file(letsencrypt_dir, name, &renewal, "fullchain")?;
+    let privkey_file = lineage_file(letsencrypt_dir, name, &renewal, "privkey")?;
+    let fullchain = tokio::fs::read(&fullchain_file).await?;
+    let privkey = tokio::fs::read(&privkey_file).await?;
+
+    let certificate = X509::from_pem(&fullchain)?;
+    let names = certificate_names(&certificate);
+    let domain = names
+        .iter()
+        .map(|name| name.trim_start_matches("*.").to_string())
+        .min_by_key(|name| name.len())
+        .ok_or_else(|| {
+            anyhow!("The certificate: {} has no names", fullchain_file.display())
+        })?;
+    let private_key = openssl::pkey::PKey::private_key_from_pem(&privkey)?;
+    let public_key = certificate.public_key()?;
+    if !private_key.public_eq(&public_key) {
+        return Err(anyhow!(
+            "The key: {} doesn't match the certificate: {}",
+            privkey_file.display(),
+            fullchain_file.display()
+        ));
+    }
+
+    // certsd keeps the apex and the wildcard in separate dirs: the same certbot
+    // certificate seeds both, certsd renews them separately from now on
+    let mut domain_dirs = vec![];
+    if names.contains(&format!("*.{domain}")) {
+        domain_dirs.push(domain_dir_of(&domain, true));
+    }
+    if names.contains(&domain) {
+        domain_dirs.push(domain_dir_of(&domain, false));
+    }
+    for domain_dir in domain_dirs.iter() {
+        let domain_dir = format!("{data_dir}/{domain_dir}");
+        tokio::fs::create_dir_all(&domain_dir).await?;
+        write_new_file(&format!("{domain_dir}/domain.key"), &privkey, true).await?;
+        write_new_file(&format!("{domain_dir}/chained.pem"), &fullchain, false).await?;
+    }
+    let other_names = names
+        .iter()
+        .filter(|name| **name != domain && **name != format!("*.{domain}"))
+        .collect::<Vec<&String>>();
+    if !other_names.is_empty() {
+        warn!("certsd will not renew these names of the lineage: {name}: {other_names:?}");
+    }
+
+    let server = renewal.get("server").cloned().unwrap_or_default();
+    info!("Imported the certbot lineage: {name} into: {domain_dirs:?}");
+    Ok(CertbotLineage {
+        name: name.to_string(),
+        domain,
+        names,
+        account: renewal.get("account").cloned(),
+        staging: server.contains("staging"),
+        domain_dirs,
+    })
+}
+
+
+/// Imports the account key (the RSA or the EC P-256 one) and returns the contacts
+/// of the account
+#[instrument]
+async fn import_account(
+    letsencrypt_dir: &str,
+    account: &str,
+    account_key_file: &str,
+) -> Result<Vec<String>> {
+    let account_dir = std::fs::read_dir(format!("{letsencrypt_dir}/accounts"))?
+        .filter_map(|entry| entry.ok())
+        .map(|server| server.path().join("directory").join(account))
+        .find(|account_dir| account_dir.exists())
+        .ok_or_else(|| anyhow!("The account: {account} not found in: {letsencrypt_dir}"))?;
+
+    let contacts = match tokio::fs::read(account_dir.join("regr.json")).await {
+        Ok(registration) => {
+            serde_json::from_slice::<CertbotRegistration>(&registration)?
+                .body
+                .contact
+                .iter()
+                .map(|contact| contact.trim_start_matches("mailto:").to_string())
+                .collect()
+        }
+        Err(_) => vec![],
+    };
+
+    let jwk: CertbotAccountKey =
+        serde_json::from_slice(&tokio::fs::read(account_dir.join("private_key.json")).await?)?;
+    write_new_file(account_key_file, &account_key_pem(jwk)?, true).await?;
+    info!("Imported the certbot account: {account} into: {account_key_file}");
+    Ok(contacts)
+}
+
+
+/// The PEM encoded private key of the certbot JWK
+fn account_key_pem(jwk: CertbotAccountKey) -> Result<Vec<u8>> {
+    let decode = |component: Option<String>| -> Result<BigNum> {
+        let component =
+            component.ok_or_else(|| anyhow!("The {} account key is incomplete", jwk.kty))?;
+        Ok(BigNum::from_slice(&URL_SAFE_NO_PAD.decode(component)?)?)
+    };
+    match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+        ("RSA", _) => {
+            let account_key = Rsa::from_private_components(
+                decode(jwk.n.to_owned())?,
+                decode(jwk.e.to_owned())?,
+                decode(jwk.d.to_owned())?,
+                decode(jwk.p.to_owned())?,
+                decode(jwk.q.to_owned())?,
+                decode(jwk.dp.to_owned())?,
+                decode(jwk.dq.to_owned())?,
+                decode(jwk.qi.to_owned())?,
+            )?;
+            if !account_key.check_key()? {
+                return Err(anyhow!("The RSA account key is invalid"));
+            }
+            Ok(account_key.private_key_to_pem()?)
+        }
+        ("EC", Some("P-256")) => {
+            let group = openssl::ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+            let x = decode(jwk.x.to_owned())?;
+            let y = decode(jwk.y.to_owned())?;
+            let d = decode(jwk.d.to_owned())?;
+            let public_key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+            let account_key =
+                EcKey::from_private_components(&group, &d, public_key.public_key())?;
+            account_key.check_key()?;
+            Ok(account_key.private_key_to_pem()?)
+        }
+        (kty, crv) => {
+            Err(anyhow!(
+                "Unsupported account key type: {kty} {crv:?}. certsd supports the RSA and the EC P-256 account keys, a new account will be registered."
+            ))
+        }
+    }
+}
+
+
+/// Renders the account entries of the imported lineages, to be completed with
+/// the CloudFlare credentials and pasted into the configuration
+pub fn render_imported_accounts(import: &CertbotImport) -> String {
+    let contacts = import
+        .contacts
+        .iter()
+        .map(|contact| format!("\"{contact}\""))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let mut domains = import
+        .lineages
+        .iter()
+        .map(|lineage| lineage.domain.to_owned())
+        .collect::<Vec<String>>();
+    domains.dedup();
+    let accounts = domains
+        .iter()
+        .map(|domain| {
+            // the lineages of just the apex or just the wildcard certificate get only that one
+            let includes = |wildcard: bool| {
+                import.lineages.iter().any(|lineage| {
+                    lineage.domain == *domain
+                        && lineage
+                            .domain_dirs
+                            .contains(&domain_dir_of(domain, wildcard))
+                })
+            };
+            let names = match (includes(false), includes(true)) {
+                (true, true) => format!("domain: \"{domain}\","),
+                (true, false) => format!("domains: [\"{domain}\"],"),
+                _ => format!("domains: [\"*.{domain}\"],"),
+            };
+            format!(
+                "        (\n            cloudflare_api_token: \"\",\n            cloudflare_zone_id: \"\",\n            {names}\n            contacts: [{contacts}],\n        ),"
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!("    accounts: [\n{accounts}\n    ],")
+}
+
+
+/// Parses the certbot renewal configuration (flat "key = value" lines, sections ignored)
+fn parse_renewal_conf(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#') && !line.starts_with('['))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+
+/// Finds the "privkey" or "fullchain" file of the lineage: the one from the renewal
+/// configuration, then the live/ link, then the latest archive/ version (the links
+/// point to the original location when importing a copied certbot dir)
+fn lineage_file(
+    letsencrypt_dir: &str,
+    name: &str,
+    renewal: &HashMap<String, String>,
+    kind: &str,
+) -> Result<PathBuf> {
+    let candidates = [
+        renewal.get(kind).map(PathBuf::from),
+        Some(PathBuf::from(format!(
+            "{letsencrypt_dir}/live/{name}/{kind}.pem"
+        ))),
+    ];
+    if let Some(file) = candidates.into_iter().flatten().find(|file| file.exists()) {
+        return Ok(file);
+    }
+
+    let archive_dir = format!("{letsencrypt_dir}/archive/{name}");
+    std::fs::read_dir(&archive_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| {
+            let version = path
+                .file_name()?
+                .to_string_lossy()
+                .strip_prefix(kind)?
+                .strip_suffix(".pem")?
+                .parse::<u32>()
+                .ok()?;
+            Some((version, path))
+        })
+        .max_by_key(|(version, _)| *version)
+        .map(|(_, path)| path)
+        .ok_or_else(|| anyhow!("No {kind} file of the lineage: {name} in: {letsencrypt_dir}"))
+}
+
+
+/// Writes the file unless it's already present
+async fn write_new_file(file_name: &str, content: &[u8], private: bool) -> Result<()> {
+    if Path::new(file_name).exists() {
+        warn!("Keeping the already present: {file_name}");
+        return Ok(());
+    }
+    tokio::fs::write(file_name, content).await?;
+    if private {
+        let mut permissions = tokio::fs::metadata(file_name).await?.permissions();
+        permissions.set_mode(0o600);
+        tokio::fs::set_permissions(file_name, permissions).await?;
+    }
+    Ok(())
+}
+
+
+#[tokio::test]
+async fn test_import_certbot() -> Result<()> {
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNumContext,
+        ec::EcGroup,
+        hash::MessageDigest,
+        pkey::PKey,
+        x509::{X509NameBuilder, extension::SubjectAlternativeName},
+    };
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let domain_key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "the-domain.com")?;
+    let name = name.build();
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&domain_key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(90)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let alt_name = SubjectAlternativeName::new()
+        .dns("*.the-domain.com")
+        .dns("the-domain.com")
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(alt_name)?;
+    builder.sign(&domain_key, MessageDigest::sha256())?;
+
+    let account_key = EcKey::generate(&group)?;
+    let mut context = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    account_key
+        .public_key()
+        .affine_coordinates(&group, &mut x, &mut y, &mut context)?;
+    let jwk = serde_json::json!({
+        "kty": "EC",
+        "crv": "P-256",
+        "x": URL_SAFE_NO_PAD.encode(x.to_vec_padded(32)?),
+        "y": URL_SAFE_NO_PAD.encode(y.to_vec_padded(32)?),
+        "d": URL_SAFE_NO_PAD.encode(account_key.private_key().to_vec_padded(32)?),
+    });
+
+    // live/ links are broken in a copied certbot dir, the archive/ is used then
+    let root = std::env::temp_dir().join(format!("certsd-import-{}", std::process::id()));
+    let letsencrypt_dir = root.join("letsencrypt");
+    let data_dir = root.join("certs");
+    let account_dir =
+        letsencrypt_dir.join("accounts/acme-v02.api.letsencrypt.org/directory/abc123");
+    let archive_dir = letsencrypt_dir.join("archive/the-domain.com");
+    for dir in [
+        &account_dir,
+        &archive_dir,
+        &letsencrypt_dir.join("renewal"),
+        &data_dir,
+    ] {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(archive_dir.join("fullchain1.pem"), b"outdated").await?;
+    tokio::fs::write(archive_dir.join("privkey1.pem"), b"outdated").await?;
+    tokio::fs::write(
+        archive_dir.join("fullchain2.pem"),
+        builder.build().to_pem()?,
+    )
+    .await?;
+    tokio::fs::write(
+        archive_dir.join("privkey2.pem"),
+        domain_key.private_key_to_pem_pkcs8()?,
+    )
+    .await?;
+    tokio::fs::write(
+        letsencrypt_dir.join("renewal/the-domain.com.conf"),
+        "# renew_before_expiry = 30 days\nversion = 2.11.0\narchive_dir = /etc/letsencrypt/archive/the-domain.com\nprivkey = /etc/letsencrypt/live/the-domain.com/privkey.pem\nfullchain = /etc/letsencrypt/live/the-domain.com/fullchain.pem\n\n[renewalparams]\naccount = abc123\nserver = https://acme-v02.api.letsencrypt.org/directory\nauthenticator = dns-cloudflare\n",
+    )
+    .await?;
+    tokio::fs::write(account_dir.join("private_key.json"), jwk.to_string()).await?;
+    tokio::fs::write(
+        account_dir.join("regr.json"),
+        r#"{"body": {"contact": ["mailto:me@example.com"]}, "uri": "https://acme-v02.api.letsencrypt.org/acme/acct/1"}"#,
+    )
+    .await?;
+
+    let import = import_certbot(
+        &letsencrypt_dir.to_string_lossy(),
+        &data_dir.to_string_lossy(),
+    )
+    .await;
+    let imported_account_key = tokio::fs::read(data_dir.join(DEFAULT_ACCOUNT_KEY_FILE)).await;
+    let imported_domain_key =
+        tokio::fs::read(data_dir.join("wild_the-domain.com/domain.key")).await;
+    let apex_certificate_present = data_dir.join("the-domain.com/chained.pem").exists();
+    tokio::fs::remove_dir_all(&root).await?;
+    let import = import?;
+
+    assert_eq!(import.lineages.len(), 1);
+    assert_eq!(import.lineages[0].domain, "the-domain.com");
+    assert!(!import.lineages[0].staging);
+    assert_eq!(
+        import.lineages[0].domain_dirs,
+        ["wild_the-domain.com", "the-domain.com"]
+    );
+    assert!(apex_certificate_present);
+    assert!(PKey::private_key_from_pem(&imported_domain_key?)?.public_eq(&domain_key));
+    assert!(import.account_key_imported);
+    assert_eq!(import.contacts, ["me@example.com"]);
+    let imported_account_key = EcKey::private_key_from_pem(&imported_account_key?)?;
+    assert_eq!(
+        imported_account_key.private_key().to_vec(),
+        account_key.private_key().to_vec()
+    );
+    let accounts = render_imported_accounts(&import);
+    assert!(accounts.contains(r#"domain: "the-domain.com","#));
+    assert!(accounts.contains(r#"contacts: ["me@example.com"],"#));
+    Ok(())
+}
+
+
+#[test]
+fn test_import_rsa_account_key_and_wildcard_only_lineage() -> Result<()> {
+    // certbot registers the RSA account keys by default
+    let account_key = Rsa::generate(2048)?;
+    let encode =
+        |component: &openssl::bn::BigNumRef| URL_SAFE_NO_PAD.encode(component.to_vec());
+    let jwk = serde_json::json!({
+        "kty": "RSA",
+        "n": encode(account_key.n()),
+        "e": encode(account_key.e()),
+        "d": encode(account_key.d()),
+        "p": encode(account_key.p().unwrap()),
+        "q": encode(account_key.q().unwrap()),
+        "dp": encode(account_key.dmp1().unwrap()),
+        "dq": encode(account_key.dmq1().unwrap()),
+        "qi": encode(account_key.iqmp().unwrap()),
+    });
+    let imported_account_key =
+        Rsa::private_key_from_pem(&account_key_pem(serde_json::from_value(jwk)?)?)?;
+    assert_eq!(imported_account_key.n().to_vec(), account_key.n().to_vec());
+    assert_eq!(imported_account_key.d().to_vec(), account_key.d().to_vec());
+
+    let incomplete = serde_json::json!({"kty": "RSA", "n": encode(account_key.n())});
+    assert!(account_key_pem(serde_json::from_value(incomplete)?).is_err());
+
+    let import = CertbotImport {
+        lineages: vec![CertbotLineage {
+            name: "the-domain.com".to_string(),
+            domain: "the-domain.com".to_string(),
+            names: vec!["*.the-domain.com".to_string()],
+            account: None,
+            staging: false,
+            domain_dirs: vec![domain_dir_of("the-domain.com", true)],
+        }],
+        ..CertbotImport::default()
+    };
+    let mut apex_lineage = import.lineages[0].to_owned();
+    apex_lineage.domain = "the-other-domain.com".to_string();
+    apex_lineage.domain_dirs = vec![domain_dir_of("the-other-domain.com", false)];
+    let import = CertbotImport {
+        lineages: vec![import.lineages[0].to_owned(), apex_lineage],
+        ..import
+    };
+    let accounts = render_imported_accounts(&import);
+    assert!(accounts.contains(r#"domains: ["*.the-domain.com"],"#));
+    assert!(accounts.contains(r#"domains: ["the-other-domain.com"],"#));
+    assert!(!accounts.contains("domain: "));
+    Ok(())
+}
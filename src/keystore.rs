@@ -0,0 +1,275 @@
+use crate::*;
+
+use chrono::Utc;
+use openssl::{
+    pkcs12::Pkcs12,
+    pkey::{PKey, Private},
+    rand::rand_bytes,
+    sha::Sha1,
+    stack::Stack,
+    x509::X509,
+};
+use serde::Deserialize;
+use std::os::unix::fs::PermissionsExt;
+use tokio::io::AsyncWriteExt;
+
+
+/// JKS file magic and version 2
+const JKS_MAGIC: u32 = 0xfeed_feed;
+const JKS_VERSION: u32 = 2;
+
+/// JKS PrivateKeyEntry tag
+const JKS_PRIVATE_KEY_ENTRY: u32 = 1;
+
+/// Salt of the JKS integrity digest, as hardcoded in the JDK
+const JKS_INTEGRITY_SALT: &[u8] = b"Mighty Aphrodite";
+
+/// DER encoded OID of the JDK KeyProtector algorithm (1.3.6.1.4.1.42.2.17.1.1)
+const OID_JDK_KEY_PROTECTOR: &[u8] =
+    &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x2a, 0x02, 0x11, 0x01, 0x01];
+
+/// ASN.1 NULL (the KeyProtector algorithm parameters)
+const DER_NULL: &[u8] = &[0x05, 0x00];
+
+/// Length of the SHA-1 digest
+const SHA1_LENGTH: usize = 20;
+
+
+/// Keystore written next to the chained.pem after every renewal
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeystoreOutput {
+    /// The alias of the private key entry
+    pub alias: String,
+    pub password: SecretSource,
+}
+
+
+/// Writes the configured PKCS#12 (keystore.p12) and JKS (keystore.jks) keystores
+/// of the domain certificate (or its wildcard) and returns the written files
+#[instrument(skip(config))]
+pub async fn write_keystores(
+    config: &Config,
+    domain: &str,
+    wildcard: bool,
+) -> Result<Vec<String>> {
+    let (pkcs12, jks) = (config.pkcs12_of(domain).await, config.jks_of(domain).await);
+    if pkcs12.is_none() && jks.is_none() {
+        return Ok(vec![]);
+    }
+    let domain_dir = domain_dir_of(domain, wildcard);
+    let domain_key_file = format!("{domain_dir}/domain.key");
+    if !std::path::Path::new(&domain_key_file).exists() {
+        return Err(anyhow!(
+            "No {domain_key_file} to build the keystores of (the domains with an external CSR have no key)"
+        ));
+    }
+    let domain_key = PKey::private_key_from_pem(&tokio::fs::read(&domain_key_file).await?)?;
+    let chain =
+        X509::stack_from_pem(&tokio::fs::read(format!("{domain_dir}/chained.pem")).await?)?;
+
+    let mut keystores = vec![];
+    if let Some(pkcs12) = pkcs12 {
+        let password = pkcs12.password.resolve().await?;
+        let keystore = pkcs12_keystore(&domain_key, &chain, &pkcs12.alias, &password)?;
+        keystores.push((format!("{domain_dir}/keystore.p12"), keystore));
+    }
+    if let Some(jks) = jks {
+        let password = jks.password.resolve().await?;
+        let keystore = jks_keystore(&domain_key, &chain, &jks.alias, &password)?;
+        keystores.push((format!("{domain_dir}/keystore.jks"), keystore));
+    }
+
+    let mut keystore_files = vec![];
+    for (keystore_file, keystore) in keystores {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&keystore_file)
+            .await?;
+        // the mode applies to the new files only
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+        file.write_all(&keystore).await?;
+        file.flush().await?;
+        info!("Keystore written to: {keystore_file}");
+        keystore_files.push(keystore_file);
+    }
+    Ok(keystore_files)
+}
+
+
+/// Builds the DER encoded PKCS#12 keystore of the key and its certificate chain
+pub fn pkcs12_keystore(
+    domain_key: &PKey<Private>,
+    chain: &[X509],
+    alias: &str,
+    password: &str,
+) -> Result<Vec<u8>> {
+    let (leaf, intermediates) = chain
+        .split_first()
+        .ok_or_else(|| anyhow!("Empty certificate chain"))?;
+    let mut ca = Stack::new()?;
+    for intermediate in intermediates {
+        ca.push(intermediate.to_owned())?;
+    }
+    let mut builder = Pkcs12::builder();
+    builder.name(alias).pkey(domain_key).cert(leaf).ca(ca);
+    Ok(builder.build2(password)?.to_der()?)
+}
+
+
+/// Builds the legacy JKS keystore with the single PrivateKeyEntry.
+/// The key is protected with the JDK KeyProtector scheme (SHA-1 keystream), the
+/// only one the JKS format knows, so prefer the PKCS#12 wherever it's accepted.
+pub fn jks_keystore(
+    domain_key: &PKey<Private>,
+    chain: &[X509],
+    alias: &str,
+    password: &str,
+) -> Result<Vec<u8>> {
+    if chain.is_empty() {
+        return Err(anyhow!("Empty certificate chain"));
+    }
+    let password = jks_password(password);
+    let mut salt = [0u8; SHA1_LENGTH];
+    rand_bytes(&mut salt)?;
+    let protected_key = jks_protect_key(&domain_key.private_key_to_pkcs8()?, &password, &salt);
+    let mut algorithm = der_encode(DER_OID, OID_JDK_KEY_PROTECTOR);
+    algorithm.extend(DER_NULL);
+    let mut encrypted_key_info = der_encode(DER_SEQUENCE, &algorithm);
+    encrypted_key_info.extend(der_encode(DER_OCTET_STRING, &protected_key));
+    let encrypted_key_info = der_encode(DER_SEQUENCE, &encrypted_key_info);
+
+    let mut keystore = vec![];
+    keystore.extend(JKS_MAGIC.to_be_bytes());
+    keystore.extend(JKS_VERSION.to_be_bytes());
+    keystore.extend(1u32.to_be_bytes());
+    keystore.extend(JKS_PRIVATE_KEY_ENTRY.to_be_bytes());
+    // the JDK looks the aliases up in lowercase
+    keystore.extend(jks_utf(&alias.to_lowercase())?);
+    keystore.extend(Utc::now().timestamp_millis().to_be_bytes());
+    keystore.extend((encrypted_key_info.len() as u32).to_be_bytes());
+    keystore.extend(encrypted_key_info);
+    keystore.extend((chain.len() as u32).to_be_bytes());
+    for certificate in chain {
+        let certificate = certificate.to_der()?;
+        keystore.extend(jks_utf("X.509")?);
+        keystore.extend((certificate.len() as u32).to_be_bytes());
+        keystore.extend(certificate);
+    }
+
+    let mut integrity = Sha1::new();
+    integrity.update(&password);
+    integrity.update(JKS_INTEGRITY_SALT);
+    integrity.update(&keystore);
+    keystore.extend(integrity.finish());
+    Ok(keystore)
+}
+
+
+/// The password as the JDK hashes it: UTF-16BE code units
+fn jks_password(password: &str) -> Vec<u8> {
+    password
+        .encode_utf16()
+        .flat_map(|unit| unit.to_be_bytes())
+        .collect()
+}
+
+
+/// DataOutput.writeUTF of the ASCII string
+fn jks_utf(value: &str) -> Result<Vec<u8>> {
+    if !value.is_ascii() || value.len() > u16::MAX as usize {
+        return Err(anyhow!("Unsupported JKS alias: {value}"));
+    }
+    let mut encoded = (value.len() as u16).to_be_bytes().to_vec();
+    encoded.extend(value.as_bytes());
+    Ok(encoded)
+}
+
+
+/// JDK KeyProtector: salt || (key XOR SHA-1 keystream) || SHA-1(password || key)
+fn jks_protect_key(key: &[u8], password: &[u8], salt: &[u8; SHA1_LENGTH]) -> Vec<u8> {
+    let mut protected_key = salt.to_vec();
+    let mut digest = *salt;
+    for chunk in key.chunks(SHA1_LENGTH) {
+        let mut keystream = Sha1::new();
+        keystream.update(password);
+        keystream.update(&digest);
+        digest = keystream.finish();
+        protected_key.extend(chunk.iter().zip(digest).map(|(byte, mask)| byte ^ mask));
+    }
+    let mut check = Sha1::new();
+    check.update(password);
+    check.update(key);
+    protected_key.extend(check.finish());
+    protected_key
+}
+
+
+#[test]
+fn test_keystores() -> Result<()> {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        sha::sha1,
+        x509::X509NameBuilder,
+    };
+
+    let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+    let domain_key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "the-domain.com")?;
+    let name = name.build();
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&domain_key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(90)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.sign(&domain_key, MessageDigest::sha256())?;
+    let chain = [builder.build()];
+
+    let pkcs12 = pkcs12_keystore(&domain_key, &chain, "tomcat", "changeit")?;
+    let parsed = Pkcs12::from_der(&pkcs12)?.parse2("changeit")?;
+    assert!(parsed.pkey.is_some_and(|pkey| pkey.public_eq(&domain_key)));
+    assert_eq!(
+        parsed.cert.map(|cert| cert.to_der()).transpose()?,
+        Some(chain[0].to_der()?)
+    );
+    assert!(Pkcs12::from_der(&pkcs12)?.parse2("wrong").is_err());
+
+    let jks = jks_keystore(&domain_key, &chain, "Tomcat", "changeit")?;
+    assert_eq!(jks[..4], JKS_MAGIC.to_be_bytes());
+    let (content, integrity) = jks.split_at(jks.len() - SHA1_LENGTH);
+    let mut expected_integrity = jks_password("changeit");
+    expected_integrity.extend(JKS_INTEGRITY_SALT);
+    expected_integrity.extend(content);
+    assert_eq!(integrity, sha1(&expected_integrity));
+    assert_eq!(&content[16..24], b"\x00\x06tomcat");
+
+    // the key entry decrypts back to the PKCS#8 key with the same keystream
+    let password = jks_password("changeit");
+    let mut entry = DerReader::new(&content[36..]);
+    let encrypted_key_info = entry.expect(DER_SEQUENCE)?;
+    let mut encrypted_key_info = DerReader::new(encrypted_key_info.content);
+    encrypted_key_info.expect(DER_SEQUENCE)?;
+    let protected_key = encrypted_key_info.expect(DER_OCTET_STRING)?.content;
+    let salt: [u8; SHA1_LENGTH] = protected_key[..SHA1_LENGTH].try_into()?;
+    let key_length = protected_key.len() - 2 * SHA1_LENGTH;
+    let encrypted_key = &protected_key[SHA1_LENGTH..SHA1_LENGTH + key_length];
+    let decrypted = jks_protect_key(encrypted_key, &password, &salt);
+    let decrypted_key = &decrypted[SHA1_LENGTH..SHA1_LENGTH + key_length];
+    assert_eq!(decrypted_key, domain_key.private_key_to_pkcs8()?);
+    assert_eq!(
+        protected_key[SHA1_LENGTH + key_length..],
+        sha1(&[password.as_slice(), decrypted_key].concat())
+    );
+    Ok(())
+}
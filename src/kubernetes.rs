@@ -0,0 +1,164 @@
+use crate::*;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+use std::{collections::BTreeMap, os::unix::fs::PermissionsExt};
+use tokio::io::AsyncWriteExt;
+
+
+/// kubernetes.io/tls Secret manifest written after every renewal of the domain
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KubernetesSecret {
+    pub namespace: String,
+
+    /// The Secret name. Defaults to "example-com-tls" ("wild-example-com-tls" for the wildcard).
+    /// Not supported with the domains (their default names don't overwrite each other)
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    /// Where to write the "{name}.yaml" manifests. Defaults to the domain dir
+    #[serde(default)]
+    pub directory: Option<String>,
+}
+
+
+impl KubernetesSecret {
+    /// The Secret name of the domain certificate (or its wildcard)
+    pub fn name_of(&self, domain: &str, wildcard: bool) -> String {
+        let name = self
+            .name
+            .to_owned()
+            .unwrap_or_else(|| format!("{}-tls", domain.replace('.', "-")));
+        match wildcard {
+            true => format!("wild-{name}"),
+            false => name,
+        }
+    }
+}
+
+
+/// Writes the Secret manifest of the domain certificate (or its wildcard) if configured
+#[instrument(skip(config))]
+pub async fn write_kubernetes_secret(
+    config: &Config,
+    domain: &str,
+    wildcard: bool,
+) -> Result<Option<String>> {
+    let Some(secret) = config.kubernetes_secret_of(domain).await else {
+        return Ok(None);
+    };
+    let domain_dir = domain_dir_of(domain, wildcard);
+    let domain_key_file = format!("{domain_dir}/domain.key");
+    let chained_certificate_file = format!("{domain_dir}/chained.pem");
+    if !std::path::Path::new(&domain_key_file).exists() {
+        return Err(anyhow!(
+            "No {domain_key_file} to build the Secret of (the domains with an external CSR have no key)"
+        ));
+    }
+    let manifest = render_kubernetes_secret(
+        &secret,
+        &secret.name_of(domain, wildcard),
+        &tokio::fs::read(&chained_certificate_file).await?,
+        &tokio::fs::read(&domain_key_file).await?,
+    );
+
+    // the dry run never writes outside of its throwaway dir
+    let directory = match (&secret.directory, config.dry_run().await) {
+        (Some(directory), false) => directory.to_owned(),
+        _ => domain_dir,
+    };
+    tokio::fs::create_dir_all(&directory).await?;
+    let manifest_file = format!("{directory}/{}.yaml", secret.name_of(domain, wildcard));
+
+    // the manifest contains the private key (the mode applies to the new files only)
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&manifest_file)
+        .await?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .await?;
+    file.write_all(manifest.as_bytes()).await?;
+    file.flush().await?;
+    info!("Kubernetes Secret written to: {manifest_file}");
+    Ok(Some(manifest_file))
+}
+
+
+/// Renders the kubernetes.io/tls Secret manifest (YAML)
+pub fn render_kubernetes_secret(
+    secret: &KubernetesSecret,
+    name: &str,
+    chained_certificate: &[u8],
+    domain_key: &[u8],
+) -> String {
+    // JSON strings are valid YAML scalars, and take care of the escaping
+    let quote = |value: &str| serde_json::Value::from(value).to_string();
+    let mut manifest = vec![
+        String::from("apiVersion: v1"),
+        String::from("kind: Secret"),
+        String::from("metadata:"),
+        format!("  name: {}", quote(name)),
+        format!("  namespace: {}", quote(&secret.namespace)),
+    ];
+    if !secret.labels.is_empty() {
+        manifest.push(String::from("  labels:"));
+        for (label, value) in secret.labels.iter() {
+            manifest.push(format!("    {}: {}", quote(label), quote(value)));
+        }
+    }
+    manifest.extend([
+        String::from("type: kubernetes.io/tls"),
+        String::from("data:"),
+        format!("  tls.crt: {}", STANDARD.encode(chained_certificate)),
+        format!("  tls.key: {}", STANDARD.encode(domain_key)),
+    ]);
+    manifest.join("\n") + "\n"
+}
+
+
+#[test]
+fn test_render_kubernetes_secret() {
+    let secret = KubernetesSecret {
+        namespace: String::from("ingress"),
+        labels: BTreeMap::from([
+            (
+                String::from("app.kubernetes.io/managed-by"),
+                String::from("certsd"),
+            ),
+            (String::from("team"), String::from("edge \"ops\"")),
+        ]),
+        ..KubernetesSecret::default()
+    };
+    assert_eq!(
+        secret.name_of("the-domain.com", false),
+        "the-domain-com-tls"
+    );
+    assert_eq!(
+        secret.name_of("the-domain.com", true),
+        "wild-the-domain-com-tls"
+    );
+
+    let manifest = render_kubernetes_secret(&secret, "the-domain-com-tls", b"CERT", b"KEY");
+    assert_eq!(
+        manifest,
+        r#"apiVersion: v1
+kind: Secret
+metadata:
+  name: "the-domain-com-tls"
+  namespace: "ingress"
+  labels:
+    "app.kubernetes.io/managed-by": "certsd"
+    "team": "edge \"ops\""
+type: kubernetes.io/tls
+data:
+  tls.crt: Q0VSVA==
+  tls.key: S0VZ
+"#
+    );
+}
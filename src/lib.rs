@@ -1,8 +1,33 @@
 pub mod acme;
+pub mod acmedns;
 pub mod cf;
+pub mod cf_certificate;
+pub mod challenge;
+pub mod check;
+pub mod cli;
 pub mod config;
 pub mod consts;
+pub mod csr;
+pub mod der;
+pub mod dns;
+pub mod dns_message;
+pub mod endpoint;
+pub mod exec;
+pub mod import;
+pub mod keystore;
+pub mod kubernetes;
+#[cfg(test)]
+pub mod mock_server;
 pub mod notify;
+pub mod ocsp;
+pub mod powerdns;
+pub mod rfc2136;
+pub mod route53;
+pub mod sct;
+pub mod secret;
+pub mod session;
+pub mod status;
+pub mod summary;
 
 use tracing_subscriber::{
     EnvFilter, Registry,
@@ -15,7 +40,14 @@ use tracing_subscriber::{
     reload::*,
 };
 
-pub use crate::{acme::*, cf::*, config::*, consts::*, notify::*};
+#[cfg(test)]
+pub use crate::mock_server::*;
+pub use crate::{
+    acme::*, acmedns::*, cf::*, cf_certificate::*, challenge::*, check::*, cli::*, config::*,
+    consts::*, csr::*, der::*, dns::*, dns_message::*, endpoint::*, exec::*, import::*,
+    keystore::*, kubernetes::*, notify::*, ocsp::*, powerdns::*, rfc2136::*, route53::*,
+    sct::*, secret::*, session::*, status::*, summary::*,
+};
 pub use anyhow::Result;
 pub use anyhow::anyhow;
 pub use tracing::{Level, debug, error, event, info, instrument, span, trace, warn};
@@ -1,39 +1,230 @@
 use certsd::*;
+use chrono::Local;
+use clap::Parser;
 use hyperacme::Error;
 use std::env::set_current_dir;
+use tracing_subscriber::EnvFilter;
 
 
 #[instrument]
 #[tokio::main(flavor = "current_thread")] //(flavor = "multi_thread", worker_threads = 4)]
 async fn main() -> Result<(), Error> {
-    initialize_logger();
+    let cli = Cli::parse();
+    let logger = initialize_logger();
+    if matches!(cli.command, Some(Command::Status { .. })) && std::env::var("LOG").is_err() {
+        // the inventory goes to the standard output, so keep it free of logs
+        logger
+            .modify(|filter| *filter = EnvFilter::new("off"))
+            .unwrap_or_default();
+    }
+
+    // The import prepares the configuration, so it can't require one
+    if let Some(Command::Import {
+        letsencrypt_dir,
+    }) = &cli.command
+    {
+        let config_data_dir = Config::config_data_dir().await.unwrap_or_default();
+        let import = import_certbot(letsencrypt_dir, &config_data_dir)
+            .await
+            .map_err(|err| Error::GeneralError(err.to_string()))?;
+        if import.lineages.iter().any(|lineage| lineage.staging) {
+            warn!(
+                "Some certbot lineages were issued by the ACME staging. Set acme_staging accordingly."
+            );
+        }
+        if !import.account_key_imported {
+            warn!("No certbot account key imported. certsd will register a new ACME account.");
+        }
+        info!(
+            "Imported {} certbot lineages into: {config_data_dir}. Complete the CloudFlare credentials of the accounts below and add them to the configuration:",
+            import.lineages.len()
+        );
+        println!("{}", render_imported_accounts(&import));
+        return Ok(());
+    }
 
-    // Config validation
-    let config = match Config::load().await {
+    // Config validation, reporting all problems of the configuration at once
+    let config_file = Config::config_file().await;
+    let checked_config = check_config(&config_file).await;
+    if let Some(Command::CheckConfig) = cli.command {
+        return match checked_config {
+            Ok(_config) => {
+                println!("The configuration is valid: {config_file}");
+                Ok(())
+            }
+            Err(problems) => {
+                for problem in problems.iter() {
+                    println!("{config_file}:{problem}");
+                }
+                Err(Error::GeneralError(format!(
+                    "Found {} problems in the configuration: {config_file}",
+                    problems.len()
+                )))
+            }
+        };
+    }
+    let mut config = match checked_config {
         Ok(config) => {
             debug!("The configuration is: {config:#?}");
             config
         }
-        Err(e) => {
-            panic!("Unable to load the config: {e:#?}")
+        Err(problems) => {
+            for problem in problems.iter() {
+                error!("{config_file}:{problem}");
+            }
+            return Err(Error::GeneralError(format!(
+                "Found {} problems in the configuration: {config_file}. See: certsd check-config",
+                problems.len()
+            )));
         }
     };
 
-    let domains = config.domains().await;
+    // Only the configured domains can be selected
+    let configured_domains = config.domains().await;
+    let unknown_domains = cli
+        .domains
+        .iter()
+        .filter(|&selected| {
+            !configured_domains.contains(selected)
+                && !config
+                    .ip_addresses
+                    .iter()
+                    .any(|ip_account| &ip_account.ip == selected)
+        })
+        .cloned()
+        .collect::<Vec<String>>();
+    if !unknown_domains.is_empty() {
+        return Err(Error::GeneralError(format!(
+            "Domains not present in the configuration: {unknown_domains:?}"
+        )));
+    }
+    if cli.force {
+        config.enable_force_renewal().await;
+    }
+    if cli.rotate_key {
+        config.enable_key_rotation().await;
+    }
+
+    let domains = configured_domains
+        .into_iter()
+        .filter(|domain| cli.selects(domain))
+        .collect::<Vec<String>>();
     let version = env!("CARGO_PKG_VERSION");
-    let config_dir = Config::config_data_dir().await.unwrap_or_default();
+    let mut config_dir = Config::config_data_dir().await.unwrap_or_default();
+
+    if let Some(Command::Status {
+        json,
+    }) = cli.command
+    {
+        if let Err(_err) = set_current_dir(&config_dir) {
+            panic!("Couldn't change dir to: {config_dir}");
+        }
+        let statuses = certificates_status(&config).await;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&statuses)?);
+        } else {
+            println!("{}", render_status_table(&statuses));
+        }
+        return Ok(());
+    }
+
+    // The paths relative to the config data dir, resolved before leaving it
+    let config_data_dir = std::fs::canonicalize(&config_dir)?
+        .to_string_lossy()
+        .to_string();
+    config.resolve_relative_paths(&config_data_dir).await;
+    if cli.dry_run {
+        // keep the staging account key next to the production one, but write
+        // everything else into the throwaway directory
+        config.enable_dry_run(&config_data_dir).await;
+        config_dir = std::env::temp_dir()
+            .join(format!(
+                "certsd-dry-run-{}",
+                Local::now().format("%Y%m%d%H%M%S")
+            ))
+            .to_string_lossy()
+            .to_string();
+        tokio::fs::create_dir_all(&config_dir).await?;
+        warn!("Dry run. Using the ACME staging and the throwaway directory: {config_dir}");
+    }
     if let Err(_err) = set_current_dir(&config_dir) {
         panic!("Couldn't change dir to: {config_dir}");
     }
 
+    // The DNS backends are shared by the domains of the account, so are their caches
+    let dns_backends = DnsBackends::new(&config).await;
+
+    // A bad or under-scoped CloudFlare token fails here, not in the middle of the orders
+    let mut cloudflare_accounts = vec![];
+    for domain in domains.iter() {
+        if let Some(cloudflare) = dns_backends.cloudflare_of(domain).await {
+            // the unresolved credentials get reported with the rest
+            cloudflare_accounts.push((domain.to_owned(), cloudflare));
+        }
+    }
+    verify_cloudflare_accounts(&cloudflare_accounts)
+        .await
+        .map_err(|err| Error::GeneralError(err.to_string()))?;
+
     info!(
         "{DEFAULT_SLACK_NAME} v{version} will generate certificates for domains: {domains:?}. Certificates destination dir: {config_dir}"
     );
-    for domain in domains {
-        get_cert_wildcard(&config, &domain)
-            .await
-            .and(get_cert(&config, &domain).await)?;
+    let mut summary = RunSummary::default();
+    for domain in domains.iter() {
+        if config.csr_of(domain).await.is_some() {
+            // the external CSR defines all names of its certificate
+            if cli.includes_apex() {
+                summary.record(domain, get_cert(&config, &dns_backends, domain).await);
+            }
+            continue;
+        }
+        if cli.includes_wildcard() && config.manages_wildcard(domain).await {
+            summary.record(
+                &format!("*.{domain}"),
+                get_cert_wildcard(&config, &dns_backends, domain).await,
+            );
+        }
+        if cli.includes_apex() && config.manages_apex(domain).await {
+            summary.record(domain, get_cert(&config, &dns_backends, domain).await);
+        }
+    }
+    for ip_account in config.ip_addresses().await {
+        if cli.includes_apex() && cli.selects(&ip_account.ip) {
+            summary.record(&ip_account.ip, get_cert_ip(&config, &ip_account).await);
+        }
+    }
+
+    // The throwaway certificates of the dry run are never deployed
+    if !config.dry_run().await {
+        for domain in domains.iter() {
+            let stale_endpoints = verify_endpoints(&config, domain)
+                .await
+                .into_iter()
+                .filter(|state| !state.up_to_date)
+                .map(|state| state.endpoint)
+                .collect::<Vec<String>>();
+            if !stale_endpoints.is_empty() {
+                warn!("Stale deployments of the domain: {domain}: {stale_endpoints:?}");
+                notify_stale_endpoints(&config, domain, &stale_endpoints)
+                    .await
+                    .unwrap_or_default();
+                summary
+                    .stale_endpoints
+                    .push((domain.to_owned(), stale_endpoints));
+            }
+        }
     }
 
+    println!("{}", summary.render());
+    if config.dry_run().await {
+        info!("Dry run finished. The outputs are in: {config_dir}");
+    }
+    // The failures of some certificates don't stop the renewal of the others
+    if !summary.failed.is_empty() {
+        return Err(Error::GeneralError(format!(
+            "Failed to renew the certificates: {:?}",
+            summary.failed_names()
+        )));
+    }
     Ok(())
 }
@@ -0,0 +1,128 @@
+use crate::*;
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+
+/// Request received by the mock HTTP/1.1 server of the tests
+#[derive(Debug, Clone, Default)]
+pub struct MockRequest {
+    pub method: String,
+
+    /// The path with the query
+    pub target: String,
+
+    /// Headers by the lowercase name
+    pub headers: BTreeMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+
+impl MockRequest {
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+
+    pub fn query(&self) -> &str {
+        self.target.split_once('?').map_or("", |(_, query)| query)
+    }
+
+
+    /// The header value, empty if missing
+    pub fn header(&self, name: &str) -> &str {
+        self.headers
+            .get(&name.to_lowercase())
+            .map_or("", String::as_str)
+    }
+
+
+    /// The JSON body, null if it's not JSON
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+
+/// Response of the mock HTTP/1.1 server: the status ("200 OK"), the content type and body
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: String,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+
+impl MockResponse {
+    pub fn new(status: &str, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        MockResponse {
+            status: status.to_string(),
+            content_type: content_type.to_string(),
+            body: body.into(),
+        }
+    }
+
+
+    pub fn json(status: &str, body: &Value) -> Self {
+        MockResponse::new(status, "application/json", body.to_string())
+    }
+}
+
+
+/// Spawns the mock HTTP/1.1 server answering every request (one per connection) with
+/// the handler. Returns the "host:port" of the server.
+pub async fn mock_http_server<Handler>(handler: Handler) -> Result<String>
+where
+    Handler: Fn(MockRequest) -> Result<MockResponse> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut stream = BufReader::new(stream);
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await?;
+            let mut request_line = request_line.split(' ');
+            let mut request = MockRequest {
+                method: request_line.next().unwrap_or_default().to_string(),
+                target: request_line.next().unwrap_or_default().to_string(),
+                ..MockRequest::default()
+            };
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await?;
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                let (name, value) = header.split_once(':').unwrap_or_default();
+                request
+                    .headers
+                    .insert(name.to_lowercase(), value.trim().to_string());
+            }
+            let content_length = request.header("content-length").parse().unwrap_or(0);
+            request.body = vec![0u8; content_length];
+            stream.read_exact(&mut request.body).await?;
+
+            let response = handler(request).unwrap_or_else(|err| {
+                MockResponse::new("500 Internal Server Error", "text/plain", err.to_string())
+            });
+            let headers = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.status,
+                response.content_type,
+                response.body.len()
+            );
+            stream.write_all(headers.as_bytes()).await?;
+            stream.write_all(&response.body).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    });
+    Ok(address)
+}
@@ -63,14 +63,68 @@ pub async fn notify_success_telegram(chat_id: &str, token: &str, message: &str)
 }
 
 
-/// Send success notification to Slack/ Telegram
+/// Send success notification to Slack/ Telegram, with the warnings of the deployment
+/// (e.g. the keystores that couldn't be written) in the same message
 #[instrument(skip(config, domain, wildcard))]
-pub async fn notify_success(config: &Config, domain: &str, wildcard: bool) -> Result<()> {
-    let message = if wildcard {
+pub async fn notify_success(
+    config: &Config,
+    domain: &str,
+    wildcard: bool,
+    warnings: &[String],
+) -> Result<()> {
+    if config.dry_run().await {
+        info!("Dry run. Skipping the success notification for the domain: {domain}.");
+        return Ok(());
+    }
+    let mut message = if wildcard {
         format!("Certificate renewal succeeded for the domain: *.{domain}.")
     } else {
         format!("Certificate renewal succeeded for the domain: {domain}.")
     };
+    if !warnings.is_empty() {
+        message = format!("{message} Warnings: {}", warnings.join(" "));
+    }
+    for notification_type in config.notifications.iter() {
+        notification_type
+            .notify(&message)
+            .await
+            .map_err(|e| error!("{e}"))
+            .unwrap_or_default();
+    }
+    Ok(())
+}
+
+
+/// Send revocation notification to Slack/ Telegram
+#[instrument(skip(config, domain, wildcard))]
+pub async fn notify_revoked(config: &Config, domain: &str, wildcard: bool) -> Result<()> {
+    let message = if wildcard {
+        format!("Certificate for the domain: *.{domain} was revoked. Reissuing.")
+    } else {
+        format!("Certificate for the domain: {domain} was revoked. Reissuing.")
+    };
+    for notification_type in config.notifications.iter() {
+        notification_type
+            .notify(&message)
+            .await
+            .map_err(|e| error!("{e}"))
+            .unwrap_or_default();
+    }
+    Ok(())
+}
+
+
+/// Send stale deployment notification to Slack/ Telegram
+#[instrument(skip(config, domain))]
+pub async fn notify_stale_endpoints(
+    config: &Config,
+    domain: &str,
+    endpoints: &[String],
+) -> Result<()> {
+    let message = format!(
+        "Endpoints of the domain: {domain} still serve an old certificate: {}",
+        endpoints.join(", ")
+    );
     for notification_type in config.notifications.iter() {
         notification_type
             .notify(&message)
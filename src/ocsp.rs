@@ -0,0 +1,446 @@
+use crate::*;
+
+use chrono::{NaiveDateTime, prelude::*};
+use openssl::{
+    hash::MessageDigest,
+    ocsp::{
+        OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+    },
+    stack::Stack,
+    x509::{X509, store::X509StoreBuilder, verify::X509VerifyFlags},
+};
+use std::path::Path;
+
+
+/// Certificate status reported by the OCSP responder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OcspState {
+    /// Fresh response verified and stored in the `ocsp.der` file
+    Good,
+
+    /// The CA has revoked the certificate
+    Revoked,
+
+    /// The certificate has no OCSP responder URL in its AIA extension
+    NoResponder,
+}
+
+
+/// Fetches, verifies and stores the OCSP response for the certificate in given domain dir.
+/// The stored response is reused until it gets close to its nextUpdate time.
+#[instrument]
+pub async fn refresh_ocsp_response(domain_dir: &str) -> Result<OcspState> {
+    let chained_certificate_file = format!("{domain_dir}/chained.pem");
+    let ocsp_response_file = format!("{domain_dir}/ocsp.der");
+    let chain = X509::stack_from_pem(&tokio::fs::read(&chained_certificate_file).await?)?;
+    let (certificate, issuer) = match chain.as_slice() {
+        [certificate, issuer, ..] => (certificate, issuer),
+        _ => {
+            return Err(anyhow!(
+                "The {chained_certificate_file} has no issuer certificate in the chain."
+            ));
+        }
+    };
+
+    let responder = match certificate.ocsp_responders()?.iter().next() {
+        Some(url) => url.to_string(),
+        None => {
+            info!("No OCSP responder defined in: {chained_certificate_file}. Skipping.");
+            return Ok(OcspState::NoResponder);
+        }
+    };
+
+    if Path::new(&ocsp_response_file).exists() {
+        let stored_response_der = tokio::fs::read(&ocsp_response_file).await?;
+        match verify_ocsp_response(&stored_response_der, certificate, issuer) {
+            Ok((OcspCertStatus::GOOD, Some(next_update)))
+                if Utc::now()
+                    + chrono::Duration::seconds(DEFAULT_OCSP_REFRESH_MARGIN_SECS)
+                    < next_update =>
+            {
+                info!(
+                    "OCSP response: {ocsp_response_file} is valid until: {next_update}. Reusing it."
+                );
+                return Ok(OcspState::Good);
+            }
+            Ok(_) => debug!("OCSP response: {ocsp_response_file} is close to expiring."),
+            Err(err) => debug!("OCSP response: {ocsp_response_file} can't be reused: {err}"),
+        }
+    }
+
+    let mut request = OcspRequest::new()?;
+    request.add_id(OcspCertId::from_cert(
+        MessageDigest::sha1(),
+        certificate,
+        issuer,
+    )?)?;
+    info!("Requesting the OCSP response from: {responder}");
+    let response_der = reqwest::Client::new()
+        .post(&responder)
+        .header("Content-Type", "application/ocsp-request")
+        .body(request.to_der()?)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .to_vec();
+
+    let (status, next_update) = verify_ocsp_response(&response_der, certificate, issuer)
+        .map_err(|err| anyhow!("OCSP responder: {responder}: {err}"))?;
+    if status == OcspCertStatus::REVOKED {
+        warn!(
+            "OCSP responder reports the certificate: {chained_certificate_file} as revoked!"
+        );
+        return Ok(OcspState::Revoked);
+    }
+    if status != OcspCertStatus::GOOD {
+        return Err(anyhow!(
+            "OCSP responder returned an unknown status for: {chained_certificate_file}"
+        ));
+    }
+
+    let next_update = next_update
+        .map(|time| time.to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+    tokio::fs::write(&ocsp_response_file, &response_der).await?;
+    info!("OCSP response stored in: {ocsp_response_file}. Next update: {next_update}");
+    Ok(OcspState::Good)
+}
+
+
+/// Verifies the DER encoded OCSP response of the certificate and returns the certificate
+/// status with the nextUpdate time of the response
+fn verify_ocsp_response(
+    response_der: &[u8],
+    certificate: &X509,
+    issuer: &X509,
+) -> Result<(OcspCertStatus, Option<DateTime<Utc>>)> {
+    let response = OcspResponse::from_der(response_der)?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(anyhow!(
+            "Unsuccessful OCSP response status: {:?}",
+            response.status().as_raw()
+        ));
+    }
+
+    // The response is signed either by the issuer itself or by a delegated
+    // responder certified by the issuer, so the issuer is our only trust anchor.
+    let basic_response = response.basic()?;
+    let mut issuer_stack = Stack::new()?;
+    issuer_stack.push(issuer.to_owned())?;
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(issuer.to_owned())?;
+    store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+    let store = store.build();
+    basic_response.verify(&issuer_stack, &store, OcspFlag::empty())?;
+
+    let certificate_id = OcspCertId::from_cert(MessageDigest::sha1(), certificate, issuer)?;
+    let status = basic_response
+        .find_status(&certificate_id)
+        .ok_or_else(|| anyhow!("OCSP response has no status of the certificate"))?;
+    status.check_validity(DEFAULT_OCSP_CLOCK_SKEW_SECS, None)?;
+    let next_update = match status.next_update() {
+        Some(time) => Some(parse_asn1_time(&time.to_string())?),
+        None => None,
+    };
+    Ok((status.status, next_update))
+}
+
+
+/// Removes the stored OCSP response if it's already past its nextUpdate time
+#[instrument]
+pub async fn remove_stale_ocsp_response(domain_dir: &str) -> Result<()> {
+    let ocsp_response_file = format!("{domain_dir}/ocsp.der");
+    if !Path::new(&ocsp_response_file).exists() {
+        return Ok(());
+    }
+    let response = OcspResponse::from_der(&tokio::fs::read(&ocsp_response_file).await?)?;
+    let basic_response = response.basic()?;
+    let chain =
+        X509::stack_from_pem(&tokio::fs::read(format!("{domain_dir}/chained.pem")).await?)?;
+    let still_valid = match chain.as_slice() {
+        [certificate, issuer, ..] => {
+            let certificate_id =
+                OcspCertId::from_cert(MessageDigest::sha1(), certificate, issuer)?;
+            basic_response
+                .find_status(&certificate_id)
+                .map(|status| status.check_validity(0, None).is_ok())
+                .unwrap_or(false)
+        }
+        _ => false,
+    };
+    if !still_valid {
+        warn!("Removing the stale OCSP response: {ocsp_response_file}");
+        tokio::fs::remove_file(&ocsp_response_file).await?;
+    }
+    Ok(())
+}
+
+
+/// Moves the revoked certificate and its key aside, so the next request will issue
+/// a new certificate with a freshly generated key (the old one may be compromised)
+#[instrument]
+pub async fn retire_revoked_certificate(domain_dir: &str) -> Result<()> {
+    let chained_certificate_file = format!("{domain_dir}/chained.pem");
+    let today_date = Local::now().date_naive();
+    let revoked_file = format!("{chained_certificate_file}-revoked-{today_date}");
+    info!("Moving the revoked certificate to: {revoked_file}");
+    tokio::fs::rename(&chained_certificate_file, revoked_file).await?;
+    let domain_key_file = format!("{domain_dir}/domain.key");
+    if Path::new(&domain_key_file).exists() {
+        let revoked_key_file = format!("{domain_key_file}-revoked-{today_date}");
+        info!("Moving the key of the revoked certificate to: {revoked_key_file}");
+        tokio::fs::rename(&domain_key_file, revoked_key_file).await?;
+    }
+    let ocsp_response_file = format!("{domain_dir}/ocsp.der");
+    if Path::new(&ocsp_response_file).exists() {
+        tokio::fs::remove_file(ocsp_response_file).await?;
+    }
+    Ok(())
+}
+
+
+/// Parses the OpenSSL ASN.1 time representation, e.g. "Oct 18 12:00:00 2026 GMT"
+pub fn parse_asn1_time(time: &str) -> Result<DateTime<Utc>> {
+    let naive = NaiveDateTime::parse_from_str(time, "%h %e %H:%M:%S %Y GMT")?;
+    Ok(Utc.from_utc_datetime(&naive))
+}
+
+
+#[test]
+fn test_parse_asn1_time() -> Result<()> {
+    let time = parse_asn1_time("Oct  8 12:30:00 2026 GMT")?;
+    assert_eq!(time.to_rfc3339(), "2026-10-08T12:30:00+00:00");
+    assert!(parse_asn1_time("2026-10-08").is_err());
+    Ok(())
+}
+
+
+#[tokio::test]
+async fn test_refresh_ocsp_response() -> Result<()> {
+    use openssl::{
+        asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time},
+        bn::{BigNum, BigNumContext},
+        ec::{EcGroup, EcKey, PointConversionForm},
+        nid::Nid,
+        pkey::PKey,
+        sha::sha1,
+        sign::Signer,
+        x509::{X509Extension, X509NameBuilder},
+    };
+    use std::sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    // the stub OCSP responder, serving the responses built below in turn
+    let ocsp_responses = Arc::new(Mutex::new(Vec::<Vec<u8>>::new()));
+    let requests = Arc::new(AtomicUsize::new(0));
+    let (responses, requested) = (ocsp_responses.clone(), requests.clone());
+    let server = mock_http_server(move |_request| {
+        let responses = responses.lock().unwrap();
+        let index = requested
+            .fetch_add(1, Ordering::SeqCst)
+            .min(responses.len().saturating_sub(1));
+        let ocsp_response = responses
+            .get(index)
+            .ok_or_else(|| anyhow!("No OCSP response to serve"))?;
+        Ok(MockResponse::new(
+            "200 OK",
+            "application/ocsp-response",
+            ocsp_response.to_owned(),
+        ))
+    })
+    .await?;
+    let responder = format!("http://{server}/");
+
+    // the issuer and the certificate pointing to the stub OCSP responder
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let issuer_ec_key = EcKey::generate(&group)?;
+    let issuer_key = PKey::from_ec_key(issuer_ec_key.to_owned())?;
+    let certificate_key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let x509 = |common_name: &str, serial: u32, issuer: Option<&X509>| -> Result<X509> {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        let name = name.build();
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        let serial_number = BigNum::from_u32(serial)?;
+        let serial_number = Asn1Integer::from_bn(&serial_number)?;
+        builder.set_serial_number(&serial_number)?;
+        builder.set_subject_name(&name)?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(1)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        match issuer {
+            Some(issuer) => {
+                builder.set_issuer_name(issuer.subject_name())?;
+                builder.set_pubkey(&certificate_key)?;
+                // AuthorityInfoAccess with the single id-ad-ocsp URI
+                let access_description = der_encode(
+                    DER_SEQUENCE,
+                    &[
+                        der_encode(DER_OID, &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01]),
+                        der_encode(0x86, responder.as_bytes()),
+                    ]
+                    .concat(),
+                );
+                let authority_info_access_oid = Asn1Object::from_str("1.3.6.1.5.5.7.1.1")?;
+                let authority_info_access = Asn1OctetString::new_from_bytes(&der_encode(
+                    DER_SEQUENCE,
+                    &access_description,
+                ))?;
+                builder.append_extension(X509Extension::new_from_der(
+                    &authority_info_access_oid,
+                    false,
+                    &authority_info_access,
+                )?)?;
+            }
+            None => {
+                builder.set_issuer_name(&name)?;
+                builder.set_pubkey(&issuer_key)?;
+            }
+        }
+        builder.sign(&issuer_key, MessageDigest::sha256())?;
+        Ok(builder.build())
+    };
+    let issuer = x509("the-issuer", 1, None)?;
+    let certificate = x509("the-domain.com", 2, Some(&issuer))?;
+
+    // the stub BasicOCSPResponses (RFC 6960) of the certificate
+    let generalized_time = |time: DateTime<Utc>| {
+        der_encode(0x18, time.format("%Y%m%d%H%M%SZ").to_string().as_bytes())
+    };
+    let mut big_num_context = BigNumContext::new()?;
+    let issuer_key_hash = sha1(&issuer_ec_key.public_key().to_bytes(
+        &group,
+        PointConversionForm::UNCOMPRESSED,
+        &mut big_num_context,
+    )?);
+    let certificate_id = der_encode(
+        DER_SEQUENCE,
+        &[
+            der_encode(
+                DER_SEQUENCE,
+                &[
+                    der_encode(DER_OID, &[0x2b, 0x0e, 0x03, 0x02, 0x1a]),
+                    vec![0x05, 0x00],
+                ]
+                .concat(),
+            ),
+            der_encode(DER_OCTET_STRING, &sha1(&issuer.subject_name().to_der()?)),
+            der_encode(DER_OCTET_STRING, &issuer_key_hash),
+            der_encode(0x02, &[2]),
+        ]
+        .concat(),
+    );
+    let now = Utc::now();
+    let ocsp_response = |certificate_status: Vec<u8>, next_update: DateTime<Utc>| {
+        let single_response = der_encode(
+            DER_SEQUENCE,
+            &[
+                certificate_id.to_owned(),
+                certificate_status,
+                generalized_time(now - chrono::Duration::hours(1)),
+                der_encode(0xa0, &generalized_time(next_update)),
+            ]
+            .concat(),
+        );
+        let response_data = der_encode(
+            DER_SEQUENCE,
+            &[
+                der_encode(0xa2, &der_encode(DER_OCTET_STRING, &issuer_key_hash)),
+                generalized_time(now),
+                der_encode(DER_SEQUENCE, &single_response),
+            ]
+            .concat(),
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), &issuer_key)?;
+        signer.update(&response_data)?;
+        let signature = [vec![0], signer.sign_to_vec()?].concat();
+        let basic_response = der_encode(
+            DER_SEQUENCE,
+            &[
+                response_data,
+                der_encode(
+                    DER_SEQUENCE,
+                    &der_encode(DER_OID, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]),
+                ),
+                der_encode(0x03, &signature),
+            ]
+            .concat(),
+        );
+        Ok::<_, anyhow::Error>(der_encode(
+            DER_SEQUENCE,
+            &[
+                der_encode(0x0a, &[0]),
+                der_encode(
+                    0xa0,
+                    &der_encode(
+                        DER_SEQUENCE,
+                        &[
+                            der_encode(
+                                DER_OID,
+                                &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01],
+                            ),
+                            der_encode(DER_OCTET_STRING, &basic_response),
+                        ]
+                        .concat(),
+                    ),
+                ),
+            ]
+            .concat(),
+        ))
+    };
+    // the good response close to expiring, the fresh good one and the revoked one
+    *ocsp_responses.lock().unwrap() = vec![
+        ocsp_response(vec![0x80, 0x00], now + chrono::Duration::days(1))?,
+        ocsp_response(vec![0x80, 0x00], now + chrono::Duration::days(7))?,
+        ocsp_response(
+            der_encode(0xa1, &generalized_time(now - chrono::Duration::hours(1))),
+            now + chrono::Duration::days(7),
+        )?,
+    ];
+
+    let domain_dir = std::env::temp_dir()
+        .join(format!("certsd-ocsp-{}", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    tokio::fs::create_dir_all(&domain_dir).await?;
+    let chain = [certificate.to_pem()?, issuer.to_pem()?].concat();
+    tokio::fs::write(format!("{domain_dir}/chained.pem"), chain).await?;
+    tokio::fs::write(
+        format!("{domain_dir}/domain.key"),
+        certificate_key.private_key_to_pem_pkcs8()?,
+    )
+    .await?;
+
+    let mut states = vec![];
+    let mut request_counts = vec![];
+    for _ in 0..3 {
+        states.push(refresh_ocsp_response(&domain_dir).await?);
+        request_counts.push(requests.load(Ordering::SeqCst));
+    }
+    tokio::fs::remove_file(format!("{domain_dir}/ocsp.der")).await?;
+    let state = refresh_ocsp_response(&domain_dir).await;
+    let retired = retire_revoked_certificate(&domain_dir).await;
+    let today_date = Local::now().date_naive();
+    let certificate_retired =
+        Path::new(&format!("{domain_dir}/chained.pem-revoked-{today_date}")).exists()
+            && !Path::new(&format!("{domain_dir}/chained.pem")).exists();
+    let key_retired = Path::new(&format!("{domain_dir}/domain.key-revoked-{today_date}"))
+        .exists()
+        && !Path::new(&format!("{domain_dir}/domain.key")).exists();
+    tokio::fs::remove_dir_all(&domain_dir).await?;
+
+    // refreshed when close to expiring, then reused until its nextUpdate
+    assert_eq!(states, [OcspState::Good, OcspState::Good, OcspState::Good]);
+    assert_eq!(request_counts, [1, 2, 2]);
+    assert_eq!(state?, OcspState::Revoked);
+    retired?;
+    assert!(certificate_retired);
+    assert!(key_retired);
+    Ok(())
+}
@@ -0,0 +1,453 @@
+use crate::*;
+
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Mutex;
+
+
+/// TTL of the published challenge records
+const CHALLENGE_RECORD_TTL: u32 = 60;
+
+
+/// RRset of the PowerDNS zone
+#[derive(Debug, Clone, Deserialize)]
+pub struct PowerDnsRrset {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    #[serde(default)]
+    pub records: Vec<PowerDnsRecord>,
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PowerDnsRecord {
+    pub content: String,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+struct PowerDnsZone {
+    id: String,
+    name: String,
+    #[serde(default)]
+    rrsets: Vec<PowerDnsRrset>,
+}
+
+
+/// DNS provider managing the challenge records over the PowerDNS Authoritative HTTP API
+#[derive(Debug)]
+pub struct PowerDns {
+    /// The API base, e.g. "http://ns1.example.net:8081"
+    server_url: String,
+    server_id: String,
+    api_key: String,
+
+    /// The zone of the domain, looked up by the domain name if None
+    zone: Option<String>,
+
+    /// Challenge records presented by the provider: (domain, record name, quoted content),
+    /// the only ones the cleanup removes
+    presented_records: Mutex<Vec<(String, String, String)>>,
+    delegations: ChallengeDelegations,
+}
+
+
+impl PowerDns {
+    pub fn new(server_url: &str, server_id: &str, api_key: &str, zone: Option<&str>) -> Self {
+        PowerDns {
+            server_url: server_url.trim_end_matches('/').to_string(),
+            server_id: server_id.to_string(),
+            api_key: api_key.to_string(),
+            zone: zone.map(String::from),
+            presented_records: Mutex::new(vec![]),
+            delegations: ChallengeDelegations::default(),
+        }
+    }
+
+
+    fn zones_url(&self) -> String {
+        format!(
+            "{}/api/v1/servers/{}/zones",
+            self.server_url, self.server_id
+        )
+    }
+
+
+    #[instrument(skip(self, body))]
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<String> {
+        let mut request = reqwest::Client::new()
+            .request(method, url)
+            .header("X-API-Key", &self.api_key);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let response = response.text().await?;
+        if !status.is_success() {
+            // the API errors are {"error": "…"}
+            let error = serde_json::from_str::<serde_json::Value>(&response)
+                .ok()
+                .and_then(|error| error["error"].as_str().map(String::from))
+                .unwrap_or(response);
+            return Err(anyhow!("PowerDNS API returned: {status}: {error}"));
+        }
+        Ok(response)
+    }
+
+
+    /// The zone of the domain (or its closest parent) with its RRsets
+    #[instrument(skip(self))]
+    async fn zone_of(&self, domain: &str) -> Result<PowerDnsZone> {
+        let candidates = match &self.zone {
+            Some(zone) => vec![zone.trim_end_matches('.').to_string()],
+            None => {
+                let labels = domain.trim_end_matches('.').split('.').collect::<Vec<_>>();
+                (0..labels.len().saturating_sub(1))
+                    .map(|index| labels[index..].join("."))
+                    .collect()
+            }
+        };
+        for candidate in candidates {
+            let url = format!("{}?zone={candidate}.", self.zones_url());
+            let zones: Vec<PowerDnsZone> =
+                serde_json::from_str(&self.request(reqwest::Method::GET, &url, None).await?)?;
+            if let Some(zone) = zones
+                .into_iter()
+                .find(|zone| names_equal(&zone.name, &candidate))
+            {
+                // the zone listing has no RRsets
+                let url = format!("{}/{}", self.zones_url(), zone.id);
+                let zone = self.request(reqwest::Method::GET, &url, None).await?;
+                return Ok(serde_json::from_str(&zone)?);
+            }
+        }
+        Err(anyhow!("No PowerDNS zone of: {domain}"))
+    }
+
+
+    /// The zones of the domain and of its delegated challenge records
+    async fn zones_of(&self, domain: &str) -> Result<Vec<PowerDnsZone>> {
+        let mut zones = vec![self.zone_of(domain).await?];
+        for target in self.delegations.targets_of(domain) {
+            let zone = self.zone_of(&target).await?;
+            if !zones.iter().any(|known| known.id == zone.id) {
+                zones.push(zone);
+            }
+        }
+        Ok(zones)
+    }
+
+
+    fn challenge_rrsets(&self, zone: &PowerDnsZone, domain: &str) -> Vec<PowerDnsRrset> {
+        zone.rrsets
+            .iter()
+            .filter(|rrset| {
+                rrset.record_type == "TXT"
+                    && self.delegations.is_challenge_record_of(&rrset.name, domain)
+            })
+            .cloned()
+            .collect()
+    }
+
+
+    #[instrument(skip(self, rrsets))]
+    async fn patch(&self, zone: &PowerDnsZone, rrsets: Vec<serde_json::Value>) -> Result<()> {
+        if rrsets.is_empty() {
+            return Ok(());
+        }
+        let url = format!("{}/{}", self.zones_url(), zone.id);
+        self.request(
+            reqwest::Method::PATCH,
+            &url,
+            Some(json!({ "rrsets": rrsets })),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+
+impl DnsProvider for PowerDns {
+    fn delegations(&self) -> &ChallengeDelegations {
+        &self.delegations
+    }
+
+
+    #[instrument(skip(self))]
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        let record_name = format!("{}.", self.delegations.present_name_of(domain, name));
+        let zone = self.zone_of(&record_name).await?;
+        // REPLACE sets the whole RRset, so the existing values of the name are kept
+        let mut records = self
+            .challenge_rrsets(&zone, domain)
+            .into_iter()
+            .find(|rrset| names_equal(&rrset.name, &record_name))
+            .map(|rrset| rrset.records)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|record| json!({ "content": record.content, "disabled": record.disabled }))
+            .collect::<Vec<_>>();
+        let content = format!("\"{content}\"");
+        records.push(json!({ "content": content, "disabled": false }));
+        self.patch(
+            &zone,
+            vec![json!({
+                "name": record_name,
+                "type": "TXT",
+                "ttl": CHALLENGE_RECORD_TTL,
+                "changetype": "REPLACE",
+                "records": records,
+            })],
+        )
+        .await?;
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .push((domain.to_string(), record_name, content));
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        let presented = self
+            .presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .iter()
+            .filter(|(presented_domain, ..)| presented_domain == domain)
+            .map(|(_, name, content)| (name.to_owned(), content.to_owned()))
+            .collect::<Vec<_>>();
+        if presented.is_empty() {
+            return Ok(());
+        }
+        for zone in self.zones_of(domain).await? {
+            // the RRsets keep the records of the others, just the emptied ones are deleted
+            let rrsets = self
+                .challenge_rrsets(&zone, domain)
+                .into_iter()
+                .filter_map(|rrset| {
+                    let records = rrset
+                        .records
+                        .iter()
+                        .filter(|record| {
+                            !presented.iter().any(|(name, content)| {
+                                names_equal(name, &rrset.name) && *content == record.content
+                            })
+                        })
+                        .map(|record| {
+                            json!({ "content": record.content, "disabled": record.disabled })
+                        })
+                        .collect::<Vec<_>>();
+                    match (records.len() == rrset.records.len(), records.is_empty()) {
+                        (true, _) => None,
+                        (false, true) => {
+                            Some(json!({ "name": rrset.name, "type": "TXT", "changetype": "DELETE" }))
+                        }
+                        (false, false) => {
+                            Some(json!({
+                                "name": rrset.name,
+                                "type": "TXT",
+                                "ttl": CHALLENGE_RECORD_TTL,
+                                "changetype": "REPLACE",
+                                "records": records,
+                            }))
+                        }
+                    }
+                })
+                .collect();
+            self.patch(&zone, rrsets).await?;
+        }
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .retain(|(presented_domain, ..)| presented_domain != domain);
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        let mut rrsets = vec![];
+        for zone in self.zones_of(domain).await? {
+            rrsets.extend(self.challenge_rrsets(&zone, domain));
+        }
+        Ok(rrsets
+            .into_iter()
+            .flat_map(|rrset| {
+                info!("Found previously defined DNS TXT record: {}", rrset.name);
+                rrset.records.into_iter().map(move |record| {
+                    ChallengeRecord {
+                        id: format!("{} {}", rrset.name, record.content),
+                        name: rrset.name.to_owned(),
+                        content: record.content.trim_matches('"').to_string(),
+                    }
+                })
+            })
+            .collect())
+    }
+}
+
+
+#[tokio::test]
+async fn test_powerdns_dns_provider() -> Result<()> {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    // the mock PowerDNS API of the zone the-powerdns-domain.com.: record name => contents
+    let zone_records: Arc<Mutex<BTreeMap<String, Vec<String>>>> =
+        Arc::new(Mutex::new(BTreeMap::new()));
+    let records = zone_records.clone();
+    let server = mock_http_server(move |request| {
+        let zones_url = "/api/v1/servers/localhost/zones";
+        let zone_url = format!("{zones_url}/the-powerdns-domain.com.");
+        let (status, response) = if request.header("x-api-key") != "the-api-key" {
+            ("401 Unauthorized", json!({ "error": "Unauthorized" }))
+        } else if request.path() == zones_url && request.query().starts_with("zone=") {
+            let zones = match request.query().ends_with("=the-powerdns-domain.com.") {
+                true => {
+                    json!([{ "id": "the-powerdns-domain.com.", "name": "the-powerdns-domain.com." }])
+                }
+                false => json!([]),
+            };
+            ("200 OK", zones)
+        } else if request.target == zone_url && request.method == "GET" {
+            let mut rrsets = vec![json!({
+                "name": "the-powerdns-domain.com.",
+                "type": "SOA",
+                "ttl": 3600,
+                "records": [{ "content": "ns1.example. admin.example. 1 10800 3600 604800 3600", "disabled": false }],
+            })];
+            for (name, contents) in records
+                .lock()
+                .map_err(|err| anyhow!("Records poisoned: {err}"))?
+                .iter()
+            {
+                rrsets.push(json!({
+                    "name": name,
+                    "type": "TXT",
+                    "ttl": 60,
+                    "records": contents.iter().map(|content| json!({ "content": content, "disabled": false })).collect::<Vec<_>>(),
+                }));
+            }
+            (
+                "200 OK",
+                json!({ "id": "the-powerdns-domain.com.", "name": "the-powerdns-domain.com.", "rrsets": rrsets }),
+            )
+        } else if request.target == zone_url && request.method == "PATCH" {
+            let mut records = records
+                .lock()
+                .map_err(|err| anyhow!("Records poisoned: {err}"))?;
+            for rrset in request.json()["rrsets"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default()
+            {
+                let name = rrset["name"].as_str().unwrap_or_default().to_string();
+                match rrset["changetype"].as_str() {
+                    Some("REPLACE") => {
+                        let contents = rrset["records"]
+                            .as_array()
+                            .cloned()
+                            .unwrap_or_default()
+                            .iter()
+                            .map(|record| record["content"].as_str().unwrap_or_default().to_string())
+                            .collect();
+                        records.insert(name, contents);
+                    }
+                    _ => {
+                        records.remove(&name);
+                    }
+                }
+            }
+            return Ok(MockResponse::new("204 No Content", "application/json", ""));
+        } else {
+            ("404 Not Found", json!({ "error": "Not Found" }))
+        };
+        Ok(MockResponse::json(status, &response))
+    })
+    .await?;
+    let server_url = format!("http://{server}");
+
+    let provider_config: DnsProviderConfig = ron::from_str(&format!(
+        r#"PowerDns(server_url: "{server_url}", api_key: Env("CERTSD_TEST_POWERDNS_API_KEY"))"#
+    ))?;
+    assert!(matches!(
+        &provider_config,
+        DnsProviderConfig::PowerDns { server_id, zone: None, .. } if server_id == "localhost"
+    ));
+    let provider = PowerDns::new(&server_url, "localhost", "the-api-key", None);
+    let domain = "api.the-powerdns-domain.com";
+    // the challenge record of the other ACME client, sharing the RRset
+    zone_records.lock().unwrap().insert(
+        String::from("_acme-challenge.api.the-powerdns-domain.com."),
+        vec![String::from("\"other-client\"")],
+    );
+    provider.present(domain, domain, "proof-1").await?;
+    provider.present(domain, domain, "proof-2").await?;
+    provider
+        .present(domain, &format!("v2.{domain}"), "proof-3")
+        .await?;
+    assert_eq!(
+        zone_records
+            .lock()
+            .unwrap()
+            .get("_acme-challenge.api.the-powerdns-domain.com."),
+        Some(&vec![
+            String::from("\"other-client\""),
+            String::from("\"proof-1\""),
+            String::from("\"proof-2\"")
+        ])
+    );
+
+    let records = provider.list(domain).await?;
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| (record.name.as_str(), record.content.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            (
+                "_acme-challenge.api.the-powerdns-domain.com.",
+                "other-client"
+            ),
+            ("_acme-challenge.api.the-powerdns-domain.com.", "proof-1"),
+            ("_acme-challenge.api.the-powerdns-domain.com.", "proof-2"),
+            ("_acme-challenge.v2.api.the-powerdns-domain.com.", "proof-3")
+        ]
+    );
+
+    provider.cleanup(domain).await?;
+    assert_eq!(
+        zone_records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, contents)| (name.as_str(), contents.to_owned()))
+            .collect::<Vec<_>>(),
+        [(
+            "_acme-challenge.api.the-powerdns-domain.com.",
+            vec![String::from("\"other-client\"")]
+        )]
+    );
+
+    let wrong_key = PowerDns::new(&server_url, "localhost", "wrong-key", None);
+    let error = wrong_key
+        .list(domain)
+        .await
+        .err()
+        .map(|err| err.to_string());
+    assert_eq!(
+        error.as_deref(),
+        Some("PowerDNS API returned: 401 Unauthorized: Unauthorized")
+    );
+    Ok(())
+}
@@ -0,0 +1,436 @@
+use crate::*;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::Utc;
+use std::sync::Mutex;
+
+
+/// TTL of the published challenge records
+const CHALLENGE_RECORD_TTL: u32 = 60;
+
+
+/// DNS provider publishing the challenge records with the RFC 2136 dynamic updates,
+/// signed with the TSIG key (BIND, Knot, PowerDNS, …)
+#[derive(Debug)]
+pub struct Rfc2136Dns {
+    /// The primary server, "host:port"
+    server: String,
+
+    /// The zone to update, looked up with the SOA query of the record name if None
+    zone: Option<String>,
+    key: TsigKey,
+
+    /// Challenge records presented by the provider: (domain, record name, content),
+    /// the only ones the cleanup removes
+    presented_records: Mutex<Vec<(String, String, String)>>,
+    delegations: ChallengeDelegations,
+}
+
+
+impl Rfc2136Dns {
+    /// Builds the provider with the base64 encoded TSIG secret
+    pub async fn new(
+        server: &str,
+        zone: Option<&str>,
+        key_name: &str,
+        key_algorithm: TsigAlgorithm,
+        key_secret: &SecretSource,
+    ) -> Result<Self> {
+        let secret = STANDARD
+            .decode(key_secret.resolve().await?.trim())
+            .map_err(|err| {
+                anyhow!("Invalid base64 TSIG secret of the key: {key_name}: {err}")
+            })?;
+        Ok(Rfc2136Dns {
+            server: server.to_string(),
+            zone: zone.map(String::from),
+            key: TsigKey {
+                name: key_name.to_string(),
+                algorithm: key_algorithm,
+                secret,
+            },
+            presented_records: Mutex::new(vec![]),
+            delegations: ChallengeDelegations::default(),
+        })
+    }
+
+
+    /// The zone of the record name: the configured one, or the owner of the SOA record
+    /// returned by the server (the answer at the zone apex, the authority below it)
+    #[instrument(skip(self))]
+    async fn zone_of(&self, record_name: &str) -> Result<String> {
+        if let Some(zone) = &self.zone {
+            return Ok(zone.to_owned());
+        }
+        let mut query = DnsMessage::new(random_message_id()?, DNS_OPCODE_QUERY);
+        query
+            .questions
+            .push((record_name.to_string(), DNS_TYPE_SOA, DNS_CLASS_IN));
+        let response = self
+            .exchange(query, &[DNS_RCODE_NOERROR, DNS_RCODE_NXDOMAIN])
+            .await?;
+        response
+            .answers
+            .iter()
+            .chain(response.authorities.iter())
+            .find(|record| record.record_type == DNS_TYPE_SOA)
+            .map(|record| record.name.to_owned())
+            .ok_or_else(|| {
+                anyhow!(
+                    "The DNS server: {} returned no zone of: {record_name}",
+                    self.server
+                )
+            })
+    }
+
+
+    /// The challenge record names of the domain the provider knows about
+    fn challenge_names_of(&self, domain: &str) -> Result<Vec<String>> {
+        let mut names = vec![challenge_record_name(domain)];
+        names.extend(self.delegations.targets_of(domain));
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .iter()
+            .filter(|(presented_domain, ..)| presented_domain == domain)
+            .for_each(|(_, name, _)| {
+                if !names.contains(name) {
+                    names.push(name.to_owned());
+                }
+            });
+        Ok(names)
+    }
+
+
+    /// Sends the signed message and returns the verified response with one of the RCODEs
+    #[instrument(skip(self, message))]
+    async fn exchange(&self, mut message: DnsMessage, rcodes: &[u16]) -> Result<DnsMessage> {
+        let request_mac = self
+            .key
+            .sign(&mut message, None, Utc::now().timestamp() as u64)?;
+        let wire = dns_tcp_exchange(&self.server, &message.to_wire()?).await?;
+        let response = DnsMessage::from_wire(&wire)?;
+        if response.id != message.id {
+            return Err(anyhow!("Unexpected DNS response ID from: {}", self.server));
+        }
+        if !rcodes.contains(&response.rcode()) {
+            return Err(anyhow!(
+                "The DNS server: {} refused the request with RCODE: {}",
+                self.server,
+                response.rcode()
+            ));
+        }
+        self.key.verify(
+            &wire,
+            &response,
+            Some(&request_mac),
+            Utc::now().timestamp() as u64,
+        )?;
+        Ok(response)
+    }
+
+
+    /// Sends the updates, a message per zone of their record names
+    async fn update(&self, updates: Vec<ResourceRecord>) -> Result<()> {
+        let mut zones: Vec<(String, Vec<ResourceRecord>)> = vec![];
+        for update in updates {
+            let zone = self.zone_of(&update.name).await?;
+            match zones
+                .iter_mut()
+                .find(|(listed, _)| names_equal(listed, &zone))
+            {
+                Some((_, zone_updates)) => zone_updates.push(update),
+                None => zones.push((zone, vec![update])),
+            }
+        }
+        for (zone, updates) in zones {
+            let mut message = DnsMessage::new(random_message_id()?, DNS_OPCODE_UPDATE);
+            message.questions.push((zone, DNS_TYPE_SOA, DNS_CLASS_IN));
+            message.authorities = updates;
+            self.exchange(message, &[DNS_RCODE_NOERROR]).await?;
+        }
+        Ok(())
+    }
+}
+
+
+impl DnsProvider for Rfc2136Dns {
+    fn delegations(&self) -> &ChallengeDelegations {
+        &self.delegations
+    }
+
+
+    #[instrument(skip(self))]
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        let record_name = self.delegations.present_name_of(domain, name);
+        self.update(vec![ResourceRecord {
+            name: record_name.to_owned(),
+            record_type: DNS_TYPE_TXT,
+            class: DNS_CLASS_IN,
+            ttl: CHALLENGE_RECORD_TTL,
+            rdata: txt_rdata(content),
+        }])
+        .await?;
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .push((domain.to_string(), record_name, content.to_string()));
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        // deleting just the presented RRs (class NONE), the other values of the names stay
+        let updates = self
+            .presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .iter()
+            .filter(|(presented_domain, ..)| presented_domain == domain)
+            .map(|(_, name, content)| {
+                ResourceRecord {
+                    name: name.to_owned(),
+                    record_type: DNS_TYPE_TXT,
+                    class: DNS_CLASS_NONE,
+                    ttl: 0,
+                    rdata: txt_rdata(content),
+                }
+            })
+            .collect::<Vec<_>>();
+        if !updates.is_empty() {
+            self.update(updates).await?;
+        }
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .retain(|(presented_domain, ..)| presented_domain != domain);
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        let mut records = vec![];
+        for name in self.challenge_names_of(domain)? {
+            let mut query = DnsMessage::new(random_message_id()?, DNS_OPCODE_QUERY);
+            query
+                .questions
+                .push((name.to_owned(), DNS_TYPE_TXT, DNS_CLASS_IN));
+            let response = self.exchange(query, &[DNS_RCODE_NOERROR]).await?;
+            for (record_name, content) in response.txt_answers() {
+                info!("Found previously defined DNS TXT record: {record_name}");
+                records.push(ChallengeRecord {
+                    id: format!("{record_name} {content}"),
+                    name: record_name,
+                    content,
+                });
+            }
+        }
+        Ok(records)
+    }
+}
+
+
+#[tokio::test]
+async fn test_rfc2136_dns_provider() -> Result<()> {
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let server_key = TsigKey {
+        name: String::from("certsd-key"),
+        algorithm: TsigAlgorithm::HmacSha256,
+        secret: b"the-tsig-secret".to_vec(),
+    };
+    let zone_records: Arc<Mutex<Vec<(String, String)>>> = Arc::new(Mutex::new(vec![]));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server = listener.local_addr()?.to_string();
+
+    // a minimal authoritative server of the-rfc2136-domain.com and of its api. subdomain
+    // delegated as the separate zone, applying the signed updates of the zone names only
+    let records = zone_records.clone();
+    tokio::spawn(async move {
+        let zone_of = |name: &str| {
+            ["api.the-rfc2136-domain.com", "the-rfc2136-domain.com"]
+                .into_iter()
+                .find(|zone| name == *zone || name.ends_with(&format!(".{zone}")))
+                .map(String::from)
+        };
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let length = stream.read_u16().await? as usize;
+            let mut wire = vec![0u8; length];
+            stream.read_exact(&mut wire).await?;
+            let request = DnsMessage::from_wire(&wire)?;
+            let mut response = DnsMessage::new(request.id, request.opcode());
+            response.flags |= DNS_FLAG_RESPONSE;
+            response.questions = request.questions.to_owned();
+            let now = Utc::now().timestamp() as u64;
+            match server_key.verify(&wire, &request, None, now) {
+                Err(_) => response.flags |= DNS_RCODE_NOTAUTH,
+                Ok(request_mac) => {
+                    let (name, question_type, _) = request.questions[0].to_owned();
+                    let out_of_zone = request
+                        .authorities
+                        .iter()
+                        .any(|update| zone_of(&update.name) != Some(name.to_owned()));
+                    if request.opcode() == DNS_OPCODE_UPDATE && out_of_zone {
+                        // RCODE NOTZONE
+                        response.flags |= 10;
+                    } else if request.opcode() == DNS_OPCODE_QUERY
+                        && question_type == DNS_TYPE_SOA
+                    {
+                        let zone = zone_of(&name).unwrap_or_default();
+                        let soa = ResourceRecord {
+                            name: zone.to_owned(),
+                            record_type: DNS_TYPE_SOA,
+                            class: DNS_CLASS_IN,
+                            ttl: CHALLENGE_RECORD_TTL,
+                            rdata: [
+                                encode_name(&format!("ns.{zone}"))?,
+                                encode_name(&format!("hostmaster.{zone}"))?,
+                                vec![0; 20],
+                            ]
+                            .concat(),
+                        };
+                        match name == zone {
+                            true => response.answers.push(soa),
+                            false => {
+                                response.flags |= DNS_RCODE_NXDOMAIN;
+                                response.authorities.push(soa);
+                            }
+                        }
+                    } else {
+                        let mut records = records.lock().unwrap();
+                        for update in request.authorities.iter() {
+                            let content = parse_txt_rdata(&update.rdata);
+                            match update.class {
+                                DNS_CLASS_IN => {
+                                    records.push((update.name.to_owned(), content))
+                                }
+                                DNS_CLASS_ANY => {
+                                    records.retain(|(name, _)| name != &update.name)
+                                }
+                                _ => {
+                                    records.retain(|record| {
+                                        record != &(update.name.to_owned(), content.to_owned())
+                                    })
+                                }
+                            }
+                        }
+                        if request.opcode() == DNS_OPCODE_QUERY {
+                            for (name, content) in records
+                                .iter()
+                                .filter(|(name, _)| name == &request.questions[0].0)
+                            {
+                                response.answers.push(ResourceRecord {
+                                    name: name.to_owned(),
+                                    record_type: DNS_TYPE_TXT,
+                                    class: DNS_CLASS_IN,
+                                    ttl: CHALLENGE_RECORD_TTL,
+                                    rdata: txt_rdata(content),
+                                });
+                            }
+                        }
+                    }
+                    server_key.sign(&mut response, Some(&request_mac), now)?;
+                }
+            }
+            let response = response.to_wire()?;
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    });
+
+    let secret_file = std::env::temp_dir().join(format!("certsd-tsig-{}", std::process::id()));
+    let secret_file = secret_file.to_string_lossy().to_string();
+    tokio::fs::write(
+        &secret_file,
+        format!("{}\n", STANDARD.encode(b"the-tsig-secret")),
+    )
+    .await?;
+    let provider_config: DnsProviderConfig = ron::from_str(&format!(
+        r#"Rfc2136(server: "{server}", key_name: "certsd-key", key_algorithm: HmacSha256, key_secret: File("{secret_file}"))"#
+    ))?;
+    let DnsProviderConfig::Rfc2136 {
+        server,
+        zone,
+        key_name,
+        key_algorithm,
+        key_secret,
+    } = provider_config
+    else {
+        panic!("Should be the Rfc2136 provider!");
+    };
+    let provider = Rfc2136Dns::new(
+        &server,
+        zone.as_deref(),
+        &key_name,
+        key_algorithm,
+        &key_secret,
+    )
+    .await;
+    let wrong_secret_file = format!("{secret_file}-wrong");
+    tokio::fs::write(&wrong_secret_file, STANDARD.encode(b"the-wrong-secret")).await?;
+    let wrong_key = Rfc2136Dns::new(
+        &server,
+        None,
+        "certsd-key",
+        TsigAlgorithm::HmacSha256,
+        &SecretSource::File(wrong_secret_file.to_owned()),
+    )
+    .await;
+    tokio::fs::remove_file(&secret_file).await?;
+    tokio::fs::remove_file(&wrong_secret_file).await?;
+
+    let (provider, wrong_key) = (provider?, wrong_key?);
+    let domain = "the-rfc2136-domain.com";
+    // the challenge record of the other ACME client, sharing the name
+    zone_records.lock().unwrap().push((
+        String::from("_acme-challenge.the-rfc2136-domain.com"),
+        String::from("other-client"),
+    ));
+    provider.present(domain, domain, "proof-1").await?;
+    provider
+        .present(domain, &format!("api.{domain}"), "proof-2")
+        .await?;
+    provider.present(domain, domain, "proof-3").await?;
+
+    let records = provider.list(domain).await?;
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| (record.name.as_str(), record.content.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            ("_acme-challenge.the-rfc2136-domain.com", "other-client"),
+            ("_acme-challenge.the-rfc2136-domain.com", "proof-1"),
+            ("_acme-challenge.the-rfc2136-domain.com", "proof-3"),
+            ("_acme-challenge.api.the-rfc2136-domain.com", "proof-2")
+        ]
+    );
+
+    provider.cleanup(domain).await?;
+    let remaining = [(
+        String::from("_acme-challenge.the-rfc2136-domain.com"),
+        String::from("other-client"),
+    )];
+    assert_eq!(*zone_records.lock().unwrap(), remaining);
+    assert_eq!(
+        provider
+            .list(domain)
+            .await?
+            .iter()
+            .map(|record| record.content.as_str())
+            .collect::<Vec<_>>(),
+        ["other-client"]
+    );
+
+    // the server refuses the updates signed with the wrong secret
+    assert!(wrong_key.present(domain, domain, "proof").await.is_err());
+    assert_eq!(*zone_records.lock().unwrap(), remaining);
+    Ok(())
+}
@@ -0,0 +1,1007 @@
+use crate::*;
+
+use chrono::Utc;
+use openssl::{hash::MessageDigest, pkey::PKey, sha::sha256, sign::Signer};
+use std::{sync::Mutex, time::Duration};
+use tokio::time::sleep;
+
+
+/// Route 53 is a global service, signed in the us-east-1 region
+const ROUTE53_REGION: &str = "us-east-1";
+const ROUTE53_SERVICE: &str = "route53";
+const ROUTE53_API_VERSION: &str = "2013-04-01";
+
+/// TTL of the published challenge records
+const CHALLENGE_RECORD_TTL: u32 = 60;
+
+
+/// AWS access key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+
+impl AwsCredentials {
+    /// The credentials from the configuration, then the AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY environment variables, then the shared credentials
+    /// file (~/.aws/credentials or AWS_SHARED_CREDENTIALS_FILE)
+    #[instrument(skip(secret_access_key))]
+    pub async fn resolve(
+        access_key_id: Option<&str>,
+        secret_access_key: Option<&SecretSource>,
+        profile: Option<&str>,
+    ) -> Result<Self> {
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (access_key_id, secret_access_key)
+        {
+            return Ok(AwsCredentials {
+                access_key_id: access_key_id.to_string(),
+                secret_access_key: secret_access_key.resolve().await?,
+                session_token: None,
+            });
+        }
+        if profile.is_none()
+            && let (Ok(access_key_id), Ok(secret_access_key)) = (
+                std::env::var("AWS_ACCESS_KEY_ID"),
+                std::env::var("AWS_SECRET_ACCESS_KEY"),
+            )
+        {
+            return Ok(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+
+        let credentials_file =
+            std::env::var("AWS_SHARED_CREDENTIALS_FILE").unwrap_or_else(|_| {
+                format!(
+                    "{}/.aws/credentials",
+                    std::env::var("HOME").unwrap_or_default()
+                )
+            });
+        let profile = profile
+            .map(String::from)
+            .or_else(|| std::env::var("AWS_PROFILE").ok())
+            .unwrap_or_else(|| String::from("default"));
+        let content = tokio::fs::read_to_string(&credentials_file)
+            .await
+            .map_err(|err| {
+                anyhow!("No AWS credentials configured, and couldn't read: {credentials_file}: {err}")
+            })?;
+        parse_shared_credentials(&content, &profile).ok_or_else(|| {
+            anyhow!("No AWS credentials of the profile: {profile} in: {credentials_file}")
+        })
+    }
+}
+
+
+/// Record set of the hosted zone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route53RecordSet {
+    pub name: String,
+    pub record_type: String,
+    pub ttl: u32,
+    pub values: Vec<String>,
+}
+
+
+/// DNS provider managing the challenge records over the AWS Route 53 API
+#[derive(Debug)]
+pub struct Route53Dns {
+    endpoint: String,
+    credentials: AwsCredentials,
+
+    /// The hosted zone of the domain, looked up by its name if None
+    hosted_zone_id: Option<String>,
+
+    /// Challenge records presented by the provider: (domain, record name, quoted value),
+    /// the only values the cleanup removes
+    presented_records: Mutex<Vec<(String, String, String)>>,
+    delegations: ChallengeDelegations,
+}
+
+
+impl Route53Dns {
+    pub fn new(
+        endpoint: &str,
+        credentials: AwsCredentials,
+        hosted_zone_id: Option<&str>,
+    ) -> Self {
+        Route53Dns {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            credentials,
+            hosted_zone_id: hosted_zone_id.map(String::from),
+            presented_records: Mutex::new(vec![]),
+            delegations: ChallengeDelegations::default(),
+        }
+    }
+
+
+    /// Sends the SigV4 signed request and returns the XML response
+    #[instrument(skip(self, body))]
+    async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<String>,
+    ) -> Result<String> {
+        let mut query = query
+            .iter()
+            .map(|(key, value)| (aws_uri_encode(key), aws_uri_encode(value)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        let url = match canonical_query.is_empty() {
+            true => format!("{}{path}", self.endpoint),
+            false => format!("{}{path}?{canonical_query}", self.endpoint),
+        };
+        let host = reqwest::Url::parse(&url)?
+            .host_str()
+            .map(|host| host.to_string())
+            .ok_or_else(|| anyhow!("No host in the Route 53 endpoint: {}", self.endpoint))?;
+        let host = match reqwest::Url::parse(&url)?.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host,
+        };
+        let body = body.unwrap_or_default();
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let authorization = sigv4_authorization(
+            &self.credentials,
+            method,
+            &host,
+            path,
+            &canonical_query,
+            &amz_date,
+            body.as_bytes(),
+        )?;
+
+        let mut request = reqwest::Client::new()
+            .request(reqwest::Method::from_bytes(method.as_bytes())?, &url)
+            .header("X-Amz-Date", &amz_date)
+            .header("Authorization", authorization);
+        if let Some(session_token) = &self.credentials.session_token {
+            request = request.header("X-Amz-Security-Token", session_token);
+        }
+        if !body.is_empty() {
+            request = request.header("Content-Type", "text/xml").body(body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let response = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "Route 53 API returned: {status}: {}",
+                xml_values(&response, "Message")
+                    .first()
+                    .cloned()
+                    .unwrap_or(response)
+            ));
+        }
+        Ok(response)
+    }
+
+
+    /// The ID of the public hosted zone of the domain (or its closest parent)
+    #[instrument(skip(self))]
+    pub async fn hosted_zone_of(&self, domain: &str) -> Result<String> {
+        if let Some(hosted_zone_id) = &self.hosted_zone_id {
+            return Ok(hosted_zone_id.to_owned());
+        }
+        let labels = domain.trim_end_matches('.').split('.').collect::<Vec<_>>();
+        for index in 0..labels.len().saturating_sub(1) {
+            let candidate = labels[index..].join(".");
+            let response = self
+                .request(
+                    "GET",
+                    &format!("/{ROUTE53_API_VERSION}/hostedzonesbyname"),
+                    &[("dnsname", &candidate), ("maxitems", "1")],
+                    None,
+                )
+                .await?;
+            let hosted_zone =
+                xml_blocks(&response, "HostedZone")
+                    .into_iter()
+                    .find(|hosted_zone| {
+                        xml_values(hosted_zone, "Name")
+                            .first()
+                            .is_some_and(|name| names_equal(name, &candidate))
+                            && xml_values(hosted_zone, "PrivateZone")
+                                .first()
+                                .is_none_or(|private| private != "true")
+                    });
+            if let Some(id) =
+                hosted_zone.and_then(|zone| xml_values(&zone, "Id").into_iter().next())
+            {
+                let id = id.trim_start_matches("/hostedzone/").to_string();
+                debug!("Route 53 hosted zone of: {domain}: {id}");
+                return Ok(id);
+            }
+        }
+        Err(anyhow!("No Route 53 public hosted zone of: {domain}"))
+    }
+
+
+    /// The challenge record names of the domain the provider knows about: its own,
+    /// the delegated and the presented ones (of the subdomains)
+    fn challenge_names_of(&self, domain: &str) -> Result<Vec<String>> {
+        let mut names = vec![format!("{}.", challenge_record_name(domain))];
+        names.extend(
+            self.delegations
+                .targets_of(domain)
+                .iter()
+                .map(|target| format!("{}.", target.trim_end_matches('.'))),
+        );
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .iter()
+            .filter(|(presented_domain, ..)| presented_domain == domain)
+            .for_each(|(_, name, _)| {
+                if !names.iter().any(|listed| names_equal(listed, name)) {
+                    names.push(name.to_owned());
+                }
+            });
+        Ok(names)
+    }
+
+
+    /// The TXT record sets of the record name. Route 53 lists the record sets sorted by
+    /// the name and type, so the listing starts at the name and stops at the first other one.
+    #[instrument(skip(self))]
+    async fn record_sets_of(
+        &self,
+        hosted_zone_id: &str,
+        record_name: &str,
+    ) -> Result<Vec<Route53RecordSet>> {
+        let mut record_sets = vec![];
+        let mut start = (record_name.to_string(), String::from("TXT"));
+        loop {
+            let response = self
+                .request(
+                    "GET",
+                    &format!("/{ROUTE53_API_VERSION}/hostedzone/{hosted_zone_id}/rrset"),
+                    &[("name", &start.0), ("type", &start.1), ("maxitems", "10")],
+                    None,
+                )
+                .await?;
+            let listed = xml_blocks(&response, "ResourceRecordSet")
+                .iter()
+                .map(|record_set| parse_record_set(record_set))
+                .collect::<Vec<_>>();
+            let matching = listed
+                .iter()
+                .take_while(|record_set| {
+                    names_equal(&record_set.name, record_name)
+                        && record_set.record_type == "TXT"
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            let complete = matching.len() < listed.len();
+            record_sets.extend(matching);
+
+            let truncated = xml_values(&response, "IsTruncated")
+                .first()
+                .is_some_and(|truncated| truncated == "true");
+            match (
+                complete || !truncated,
+                xml_values(&response, "NextRecordName").into_iter().next(),
+                xml_values(&response, "NextRecordType").into_iter().next(),
+            ) {
+                (false, Some(name), Some(record_type)) => start = (name, record_type),
+                _ => return Ok(record_sets),
+            }
+        }
+    }
+
+
+    /// Submits the change batch and waits until it reaches all the Route 53 servers
+    #[instrument(skip(self, changes))]
+    async fn change(
+        &self,
+        hosted_zone_id: &str,
+        changes: &[(&str, Route53RecordSet)],
+    ) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let changes = changes
+            .iter()
+            .map(|(action, record_set)| {
+                let values = record_set
+                    .values
+                    .iter()
+                    .map(|value| {
+                        format!(
+                            "<ResourceRecord><Value>{}</Value></ResourceRecord>",
+                            xml_escape(value)
+                        )
+                    })
+                    .collect::<String>();
+                format!(
+                    "<Change><Action>{action}</Action><ResourceRecordSet><Name>{}</Name><Type>{}</Type><TTL>{}</TTL><ResourceRecords>{values}</ResourceRecords></ResourceRecordSet></Change>",
+                    xml_escape(&record_set.name),
+                    record_set.record_type,
+                    record_set.ttl
+                )
+            })
+            .collect::<String>();
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><ChangeResourceRecordSetsRequest xmlns="https://route53.amazonaws.com/doc/{ROUTE53_API_VERSION}/"><ChangeBatch><Comment>certsd DNS-01 challenge</Comment><Changes>{changes}</Changes></ChangeBatch></ChangeResourceRecordSetsRequest>"#
+        );
+        let response = self
+            .request(
+                "POST",
+                &format!("/{ROUTE53_API_VERSION}/hostedzone/{hosted_zone_id}/rrset"),
+                &[],
+                Some(body),
+            )
+            .await?;
+        let change_id = xml_values(&response, "Id")
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Route 53 API didn't return the change ID"))?;
+        self.wait_for_change(change_id.trim_start_matches("/change/"))
+            .await
+    }
+
+
+    #[instrument(skip(self))]
+    async fn wait_for_change(&self, change_id: &str) -> Result<()> {
+        let attempts = DEFAULT_ROUTE53_CHANGE_TIMEOUT_SECS / DEFAULT_ROUTE53_POLL_SECS;
+        for _ in 0..attempts {
+            let response = self
+                .request(
+                    "GET",
+                    &format!("/{ROUTE53_API_VERSION}/change/{change_id}"),
+                    &[],
+                    None,
+                )
+                .await?;
+            match xml_values(&response, "Status").first().map(String::as_str) {
+                Some("INSYNC") => return Ok(()),
+                status => debug!("Route 53 change: {change_id} status: {status:?}"),
+            }
+            sleep(Duration::from_secs(DEFAULT_ROUTE53_POLL_SECS)).await;
+        }
+        Err(anyhow!(
+            "Route 53 change: {change_id} isn't INSYNC after {DEFAULT_ROUTE53_CHANGE_TIMEOUT_SECS}s"
+        ))
+    }
+}
+
+
+impl DnsProvider for Route53Dns {
+    fn delegations(&self) -> &ChallengeDelegations {
+        &self.delegations
+    }
+
+
+    #[instrument(skip(self))]
+    async fn present(&self, domain: &str, name: &str, content: &str) -> Result<()> {
+        let record_name = format!("{}.", self.delegations.present_name_of(domain, name));
+        let hosted_zone_id = self.hosted_zone_of(&record_name).await?;
+        // the TXT record set holds all values of the name, so the UPSERT keeps the existing ones
+        let mut values = self
+            .record_sets_of(&hosted_zone_id, &record_name)
+            .await?
+            .into_iter()
+            .next()
+            .map(|record_set| record_set.values)
+            .unwrap_or_default();
+        let value = format!("\"{content}\"");
+        values.push(value.to_owned());
+        let record_set = Route53RecordSet {
+            name: record_name.to_owned(),
+            record_type: String::from("TXT"),
+            ttl: CHALLENGE_RECORD_TTL,
+            values,
+        };
+        self.change(&hosted_zone_id, &[("UPSERT", record_set)])
+            .await?;
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .push((domain.to_string(), record_name, value));
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn cleanup(&self, domain: &str) -> Result<()> {
+        let presented = self
+            .presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .iter()
+            .filter(|(presented_domain, ..)| presented_domain == domain)
+            .map(|(_, name, value)| (name.to_owned(), value.to_owned()))
+            .collect::<Vec<_>>();
+        if presented.is_empty() {
+            return Ok(());
+        }
+        // a change batch per hosted zone of the presented names
+        let mut changes_of_zones: Vec<(String, Vec<(&str, Route53RecordSet)>)> = vec![];
+        let mut cleaned_names: Vec<&String> = vec![];
+        for (name, _) in presented.iter() {
+            if cleaned_names
+                .iter()
+                .any(|cleaned| names_equal(cleaned, name))
+            {
+                continue;
+            }
+            cleaned_names.push(name);
+            let hosted_zone_id = self.hosted_zone_of(name).await?;
+            // the record sets keep the values of the others, just the emptied ones are deleted
+            let changes = self
+                .record_sets_of(&hosted_zone_id, name)
+                .await?
+                .into_iter()
+                .filter_map(|record_set| {
+                    let values = record_set
+                        .values
+                        .iter()
+                        .filter(|value| {
+                            !presented.iter().any(|(name, presented_value)| {
+                                names_equal(name, &record_set.name)
+                                    && presented_value == *value
+                            })
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    match (values.len() == record_set.values.len(), values.is_empty()) {
+                        (true, _) => None,
+                        (false, true) => Some(("DELETE", record_set)),
+                        (false, false) => {
+                            Some((
+                                "UPSERT",
+                                Route53RecordSet {
+                                    values,
+                                    ..record_set
+                                },
+                            ))
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            match changes_of_zones
+                .iter_mut()
+                .find(|(listed, _)| *listed == hosted_zone_id)
+            {
+                Some((_, zone_changes)) => zone_changes.extend(changes),
+                None => changes_of_zones.push((hosted_zone_id, changes)),
+            }
+        }
+        for (hosted_zone_id, changes) in changes_of_zones {
+            self.change(&hosted_zone_id, &changes).await?;
+        }
+        self.presented_records
+            .lock()
+            .map_err(|err| anyhow!("Presented records poisoned: {err}"))?
+            .retain(|(presented_domain, ..)| presented_domain != domain);
+        Ok(())
+    }
+
+
+    #[instrument(skip(self))]
+    async fn list(&self, domain: &str) -> Result<Vec<ChallengeRecord>> {
+        let mut record_sets = vec![];
+        for name in self.challenge_names_of(domain)? {
+            let hosted_zone_id = self.hosted_zone_of(&name).await?;
+            record_sets.extend(self.record_sets_of(&hosted_zone_id, &name).await?);
+        }
+        Ok(record_sets
+            .into_iter()
+            .flat_map(|record_set| {
+                info!(
+                    "Found previously defined DNS TXT record: {}",
+                    record_set.name
+                );
+                record_set.values.into_iter().map(move |value| {
+                    ChallengeRecord {
+                        id: format!("{} {value}", record_set.name),
+                        name: record_set.name.to_owned(),
+                        content: value.trim_matches('"').to_string(),
+                    }
+                })
+            })
+            .collect())
+    }
+}
+
+
+/// The credentials of the profile in the shared credentials file (INI)
+pub fn parse_shared_credentials(content: &str, profile: &str) -> Option<AwsCredentials> {
+    let mut section = String::new();
+    let (mut access_key_id, mut secret_access_key, mut session_token) = (None, None, None);
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = name.trim().to_string();
+            continue;
+        }
+        if section != profile {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                "aws_access_key_id" => access_key_id = value,
+                "aws_secret_access_key" => secret_access_key = value,
+                "aws_session_token" => session_token = value,
+                _ => {}
+            }
+        }
+    }
+    Some(AwsCredentials {
+        access_key_id: access_key_id?,
+        secret_access_key: secret_access_key?,
+        session_token,
+    })
+}
+
+
+/// The Authorization header of the AWS Signature Version 4. The signed headers
+/// are the host, x-amz-date and x-amz-security-token (of the temporary credentials).
+pub fn sigv4_authorization(
+    credentials: &AwsCredentials,
+    method: &str,
+    host: &str,
+    path: &str,
+    canonical_query: &str,
+    amz_date: &str,
+    body: &[u8],
+) -> Result<String> {
+    let mut headers = vec![("host", host), ("x-amz-date", amz_date)];
+    if let Some(session_token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", session_token));
+    }
+    let canonical_headers = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect::<String>();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{method}\n{path}\n{canonical_query}\n{canonical_headers}\n{signed_headers}\n{}",
+        hex(&sha256(body))
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{date}/{ROUTE53_REGION}/{ROUTE53_SERVICE}/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex(&sha256(canonical_request.as_bytes()))
+    );
+    let signing_key = sigv4_signing_key(
+        &credentials.secret_access_key,
+        date,
+        ROUTE53_REGION,
+        ROUTE53_SERVICE,
+    )?;
+    let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes())?);
+    Ok(format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+        credentials.access_key_id
+    ))
+}
+
+
+/// The SigV4 signing key of the day, region and service
+pub fn sigv4_signing_key(
+    secret_access_key: &str,
+    date: &str,
+    region: &str,
+    service: &str,
+) -> Result<Vec<u8>> {
+    let key = hmac_sha256(
+        format!("AWS4{secret_access_key}").as_bytes(),
+        date.as_bytes(),
+    )?;
+    let key = hmac_sha256(&key, region.as_bytes())?;
+    let key = hmac_sha256(&key, service.as_bytes())?;
+    hmac_sha256(&key, b"aws4_request")
+}
+
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+
+/// URI encoding of the SigV4 canonical query: everything but the unreserved characters
+fn aws_uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("%{byte:02X}"),
+            }
+        })
+        .collect()
+}
+
+
+fn parse_record_set(record_set: &str) -> Route53RecordSet {
+    let first = |tag| {
+        xml_values(record_set, tag)
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    };
+    Route53RecordSet {
+        name: first("Name"),
+        record_type: first("Type"),
+        ttl: first("TTL").parse().unwrap_or(CHALLENGE_RECORD_TTL),
+        values: xml_values(record_set, "Value"),
+    }
+}
+
+
+/// The inner XML of all elements with the tag (the Route 53 responses don't nest the same tags)
+pub fn xml_blocks(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut blocks = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let content = &rest[start + open.len()..];
+        match content.find(&close) {
+            Some(end) => {
+                blocks.push(content[..end].to_string());
+                rest = &content[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    blocks
+}
+
+
+/// The unescaped text content of all elements with the tag
+pub fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    xml_blocks(xml, tag)
+        .iter()
+        .map(|value| xml_unescape(value))
+        .collect()
+}
+
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+
+#[test]
+fn test_route53_signing() -> Result<()> {
+    // the signing key example of the AWS SigV4 documentation
+    assert_eq!(
+        hex(&sigv4_signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam"
+        )?),
+        "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+    );
+    assert_eq!(
+        aws_uri_encode("_acme-challenge.a b/c"),
+        "_acme-challenge.a%20b%2Fc"
+    );
+
+    let credentials = parse_shared_credentials(
+        "[default]\naws_access_key_id = AKIDDEFAULT\naws_secret_access_key = default-secret\n\n\
+         [certsd]\n# the DNS-01 user\naws_access_key_id=AKIDCERTSD\naws_secret_access_key=certsd-secret\naws_session_token=the-token\n",
+        "certsd",
+    );
+    assert_eq!(
+        credentials,
+        Some(AwsCredentials {
+            access_key_id: String::from("AKIDCERTSD"),
+            secret_access_key: String::from("certsd-secret"),
+            session_token: Some(String::from("the-token")),
+        })
+    );
+    assert!(
+        parse_shared_credentials("[default]\naws_access_key_id = AKID\n", "default").is_none()
+    );
+    Ok(())
+}
+
+
+#[tokio::test]
+async fn test_route53_dns_provider() -> Result<()> {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    // the mock Route 53 API: record name => values
+    let zone_records: Arc<Mutex<BTreeMap<String, Vec<String>>>> =
+        Arc::new(Mutex::new(BTreeMap::new()));
+    let records = zone_records.clone();
+    let server = mock_http_server(move |request| {
+        let body = String::from_utf8(request.body.to_owned())?;
+        let (target, method, path, query) = (
+            request.target.as_str(),
+            request.method.as_str(),
+            request.path(),
+            request.query(),
+        );
+        let credentials = AwsCredentials {
+            access_key_id: String::from("AKIDEXAMPLE"),
+            secret_access_key: String::from("the-secret-access-key"),
+            session_token: None,
+        };
+        let expected_authorization = sigv4_authorization(
+            &credentials,
+            method,
+            request.header("host"),
+            path,
+            query,
+            request.header("x-amz-date"),
+            body.as_bytes(),
+        )?;
+
+        let (status, response) = if request.header("authorization") != expected_authorization {
+            (
+                "403 Forbidden",
+                String::from(
+                    "<ErrorResponse><Error><Message>Invalid signature</Message></Error></ErrorResponse>",
+                ),
+            )
+        } else if target.starts_with("/2013-04-01/hostedzonesbyname?dnsname=") {
+            (
+                "200 OK",
+                String::from(
+                    "<ListHostedZonesByNameResponse><HostedZones><HostedZone><Id>/hostedzone/ZTEST</Id><Name>the-route53-domain.com.</Name><Config><PrivateZone>false</PrivateZone></Config></HostedZone></HostedZones></ListHostedZonesByNameResponse>",
+                ),
+            )
+        } else if target.starts_with("/2013-04-01/hostedzone/ZTEST/rrset")
+            && body.is_empty()
+        {
+            // sorted by the reversed labels and the type, listed from the name and type
+            let sort_key = |name: &str, record_type: &str| {
+                let mut labels = name.trim_end_matches('.').split('.').collect::<Vec<_>>();
+                labels.reverse();
+                (labels.join("."), record_type.to_string())
+            };
+            let parameters = query
+                .split('&')
+                .filter_map(|parameter| parameter.split_once('='))
+                .collect::<BTreeMap<_, _>>();
+            let mut record_sets = vec![
+                (
+                    String::from("the-route53-domain.com."),
+                    String::from("SOA"),
+                    vec![String::from(
+                        "ns.example. admin.example. 1 7200 900 1209600 86400",
+                    )],
+                ),
+                (
+                    String::from("www.the-route53-domain.com."),
+                    String::from("A"),
+                    vec![String::from("192.0.2.1")],
+                ),
+            ];
+            let records = records
+                .lock()
+                .map_err(|err| anyhow!("Records poisoned: {err}"))?;
+            record_sets.extend(records.iter().map(|(name, values)| {
+                (name.to_owned(), String::from("TXT"), values.to_owned())
+            }));
+            record_sets.sort_by_key(|(name, record_type, _)| sort_key(name, record_type));
+            match (
+                parameters.get("name"),
+                parameters.get("type"),
+                parameters.get("maxitems"),
+            ) {
+                (Some(name), Some(record_type), Some(max_items)) => {
+                    let start = sort_key(name, record_type);
+                    let mut listed = record_sets
+                        .into_iter()
+                        .filter(|(name, record_type, _)| {
+                            sort_key(name, record_type) >= start
+                        })
+                        .collect::<Vec<_>>();
+                    let rest = listed.split_off(listed.len().min(max_items.parse()?));
+                    let listed = listed
+                        .iter()
+                        .map(|(name, record_type, values)| {
+                            let values = values
+                                .iter()
+                                .map(|value| format!("<ResourceRecord><Value>{}</Value></ResourceRecord>", xml_escape(value)))
+                                .collect::<String>();
+                            format!("<ResourceRecordSet><Name>{name}</Name><Type>{record_type}</Type><TTL>60</TTL><ResourceRecords>{values}</ResourceRecords></ResourceRecordSet>")
+                        })
+                        .collect::<String>();
+                    let next = rest
+                        .first()
+                        .map(|(name, record_type, _)| format!("<IsTruncated>true</IsTruncated><NextRecordName>{name}</NextRecordName><NextRecordType>{record_type}</NextRecordType>"))
+                        .unwrap_or_else(|| String::from("<IsTruncated>false</IsTruncated>"));
+                    (
+                        "200 OK",
+                        format!(
+                            "<ListResourceRecordSetsResponse><ResourceRecordSets>{listed}</ResourceRecordSets>{next}<MaxItems>{max_items}</MaxItems></ListResourceRecordSetsResponse>"
+                        ),
+                    )
+                }
+                // the whole zone is never listed
+                _ => {
+                    (
+                        "400 Bad Request",
+                        String::from(
+                            "<ErrorResponse><Error><Message>Not listed from the name</Message></Error></ErrorResponse>",
+                        ),
+                    )
+                }
+            }
+        } else if target == "/2013-04-01/hostedzone/ZTEST/rrset" {
+            let mut records = records
+                .lock()
+                .map_err(|err| anyhow!("Records poisoned: {err}"))?;
+            for change in xml_blocks(&body, "Change") {
+                let record_set = parse_record_set(&change);
+                match xml_values(&change, "Action")[0].as_str() {
+                    "UPSERT" => {
+                        records.insert(record_set.name, record_set.values);
+                    }
+                    _ => {
+                        records.remove(&record_set.name);
+                    }
+                }
+            }
+            (
+                "200 OK",
+                String::from(
+                    "<ChangeResourceRecordSetsResponse><ChangeInfo><Id>/change/CTEST</Id><Status>PENDING</Status></ChangeInfo></ChangeResourceRecordSetsResponse>",
+                ),
+            )
+        } else if target == "/2013-04-01/change/CTEST" {
+            (
+                "200 OK",
+                String::from(
+                    "<GetChangeResponse><ChangeInfo><Id>/change/CTEST</Id><Status>INSYNC</Status></ChangeInfo></GetChangeResponse>",
+                ),
+            )
+        } else {
+            (
+                "404 Not Found",
+                String::from(
+                    "<ErrorResponse><Error><Message>Not found</Message></Error></ErrorResponse>",
+                ),
+            )
+        };
+        Ok(MockResponse::new(status, "text/xml", response))
+    })
+    .await?;
+    let endpoint = format!("http://{server}");
+
+    let secret_file = std::env::temp_dir().join(format!("certsd-aws-{}", std::process::id()));
+    let secret_file = secret_file.to_string_lossy().to_string();
+    tokio::fs::write(&secret_file, "the-secret-access-key\n").await?;
+    let provider_config: DnsProviderConfig = ron::from_str(&format!(
+        r#"Route53(access_key_id: Some("AKIDEXAMPLE"), secret_access_key: Some(File("{secret_file}")), endpoint: Some("{endpoint}"))"#
+    ))?;
+    let DnsProviderConfig::Route53 {
+        access_key_id,
+        secret_access_key,
+        profile,
+        ..
+    } = provider_config
+    else {
+        panic!("Should be the Route53 provider!");
+    };
+    let credentials = AwsCredentials::resolve(
+        access_key_id.as_deref(),
+        secret_access_key.as_ref(),
+        profile.as_deref(),
+    )
+    .await;
+    tokio::fs::remove_file(&secret_file).await?;
+    let provider = Route53Dns::new(&endpoint, credentials?, None);
+
+    let domain = "the-route53-domain.com";
+    // the challenge record of the other ACME client, sharing the record set
+    zone_records.lock().unwrap().insert(
+        String::from("_acme-challenge.the-route53-domain.com."),
+        vec![String::from("\"other-client\"")],
+    );
+    provider.present(domain, domain, "proof-1").await?;
+    provider.present(domain, domain, "proof-2").await?;
+    provider
+        .present(domain, &format!("api.{domain}"), "proof-3")
+        .await?;
+    assert_eq!(
+        zone_records
+            .lock()
+            .unwrap()
+            .get("_acme-challenge.the-route53-domain.com."),
+        Some(&vec![
+            String::from("\"other-client\""),
+            String::from("\"proof-1\""),
+            String::from("\"proof-2\"")
+        ])
+    );
+
+    let records = provider.list(domain).await?;
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| (record.name.as_str(), record.content.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            ("_acme-challenge.the-route53-domain.com.", "other-client"),
+            ("_acme-challenge.the-route53-domain.com.", "proof-1"),
+            ("_acme-challenge.the-route53-domain.com.", "proof-2"),
+            ("_acme-challenge.api.the-route53-domain.com.", "proof-3")
+        ]
+    );
+
+    provider.cleanup(domain).await?;
+    assert_eq!(
+        *zone_records.lock().unwrap(),
+        BTreeMap::from([(
+            String::from("_acme-challenge.the-route53-domain.com."),
+            vec![String::from("\"other-client\"")]
+        )])
+    );
+
+    // the requests signed with the unexpected key are refused
+    let wrong_key = Route53Dns::new(
+        &endpoint,
+        AwsCredentials {
+            access_key_id: String::from("AKIDOTHER"),
+            secret_access_key: String::from("other-secret"),
+            session_token: None,
+        },
+        None,
+    );
+    let error = wrong_key
+        .list(domain)
+        .await
+        .err()
+        .map(|err| err.to_string());
+    assert_eq!(
+        error.as_deref(),
+        Some("Route 53 API returned: 403 Forbidden: Invalid signature")
+    );
+    Ok(())
+}
@@ -0,0 +1,435 @@
+use crate::*;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Public},
+    sha::sha256,
+    sign::Verifier,
+    x509::X509,
+};
+use serde::Deserialize;
+
+
+/// SCT version v1 (RFC 6962 section 3.2)
+const SCT_VERSION_V1: u8 = 0;
+
+/// SignatureType certificate_timestamp
+const SCT_SIGNATURE_TYPE_CERTIFICATE_TIMESTAMP: u8 = 0;
+
+/// LogEntryType precert_entry, the embedded SCTs were issued for the precertificate
+const SCT_ENTRY_TYPE_PRECERT: u16 = 1;
+
+/// HashAlgorithm sha256 of the TLS DigitallySigned struct
+const SCT_HASH_ALGORITHM_SHA256: u8 = 4;
+
+
+/// Certificate Transparency requirements the issued certificates have to meet.
+/// No log list is bundled: the CT logs get retired and replaced every few months,
+/// so a list built into the binary would soon reject the valid certificates. The log
+/// list has to be kept up to date next to the configuration instead.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CtPolicy {
+    /// CT log list in the v3 JSON format (e.g. the published log_list.json),
+    /// relative to the config data dir
+    pub log_list: String,
+
+    /// How many distinct logs have to sign a valid SCT
+    #[serde(default = "default_min_distinct_logs")]
+    pub min_distinct_logs: usize,
+}
+
+
+fn default_min_distinct_logs() -> usize {
+    DEFAULT_CT_MIN_DISTINCT_LOGS
+}
+
+
+/// CT log we trust the SCTs of
+#[derive(Debug, Clone)]
+pub struct CtLog {
+    pub description: String,
+
+    /// SHA-256 of the log public key
+    pub log_id: Vec<u8>,
+    pub key: PKey<Public>,
+}
+
+
+/// Signed Certificate Timestamp embedded in the certificate
+#[derive(Debug, Clone)]
+pub struct SignedCertificateTimestamp {
+    pub log_id: Vec<u8>,
+    pub timestamp: u64,
+    pub extensions: Vec<u8>,
+    pub hash_algorithm: u8,
+    pub signature: Vec<u8>,
+}
+
+
+/// Result of the embedded SCTs verification
+#[derive(Debug, Clone, Default)]
+pub struct SctReport {
+    /// Descriptions of the distinct logs with a valid SCT
+    pub logs: Vec<String>,
+
+    /// Why the remaining SCTs were rejected
+    pub problems: Vec<String>,
+}
+
+
+impl SctReport {
+    pub fn is_compliant(&self, policy: &CtPolicy) -> bool {
+        self.logs.len() >= policy.min_distinct_logs
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct LogList {
+    operators: Vec<LogOperator>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct LogOperator {
+    logs: Vec<LogListEntry>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct LogListEntry {
+    description: String,
+    log_id: String,
+    key: String,
+}
+
+
+/// Reads the CT log list (v3 JSON format)
+#[instrument]
+pub async fn load_ct_logs(log_list_file: &str) -> Result<Vec<CtLog>> {
+    let log_list: LogList = serde_json::from_slice(&tokio::fs::read(log_list_file).await?)?;
+    let mut logs = vec![];
+    for entry in log_list
+        .operators
+        .into_iter()
+        .flat_map(|operator| operator.logs)
+    {
+        logs.push(CtLog {
+            log_id: STANDARD.decode(&entry.log_id)?,
+            key: PKey::public_key_from_der(&STANDARD.decode(&entry.key)?)?,
+            description: entry.description,
+        });
+    }
+    Ok(logs)
+}
+
+
+/// Verifies the SCTs embedded in the PEM certificate chain against the CT policy
+#[instrument(skip(chain_pem))]
+pub async fn verify_certificate_transparency(
+    policy: &CtPolicy,
+    chain_pem: &[u8],
+) -> Result<SctReport> {
+    let logs = load_ct_logs(&policy.log_list).await?;
+    verify_embedded_scts(chain_pem, &logs)
+}
+
+
+/// Verifies the SCTs embedded in the leaf of the PEM chain. The issuer (second certificate
+/// of the chain) is needed to rebuild the precertificate entry the logs have signed.
+pub fn verify_embedded_scts(chain_pem: &[u8], logs: &[CtLog]) -> Result<SctReport> {
+    let chain = X509::stack_from_pem(chain_pem)?;
+    let (leaf, issuer) = match chain.as_slice() {
+        [leaf, issuer, ..] => (leaf, issuer),
+        _ => return Err(anyhow!("The certificate chain doesn't contain the issuer")),
+    };
+    let issuer_key_hash = sha256(&issuer.public_key()?.public_key_to_der()?);
+    let (tbs_certificate, scts) = split_embedded_scts(&leaf.to_der()?)?;
+    if scts.is_empty() {
+        return Err(anyhow!("The certificate doesn't contain any SCT"));
+    }
+
+    let mut report = SctReport::default();
+    for sct in scts {
+        let log_id = STANDARD.encode(&sct.log_id);
+        let Some(log) = logs.iter().find(|log| log.log_id == sct.log_id) else {
+            report
+                .problems
+                .push(format!("SCT of an unknown log: {log_id}"));
+            continue;
+        };
+        if sct.hash_algorithm != SCT_HASH_ALGORITHM_SHA256 {
+            report.problems.push(format!(
+                "SCT of the log: {} uses an unsupported hash algorithm: {}",
+                log.description, sct.hash_algorithm
+            ));
+            continue;
+        }
+
+        let mut signed_data = vec![SCT_VERSION_V1, SCT_SIGNATURE_TYPE_CERTIFICATE_TIMESTAMP];
+        signed_data.extend(sct.timestamp.to_be_bytes());
+        signed_data.extend(SCT_ENTRY_TYPE_PRECERT.to_be_bytes());
+        signed_data.extend(issuer_key_hash);
+        signed_data.extend(&(tbs_certificate.len() as u32).to_be_bytes()[1..]);
+        signed_data.extend(&tbs_certificate);
+        signed_data.extend((sct.extensions.len() as u16).to_be_bytes());
+        signed_data.extend(&sct.extensions);
+
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &log.key)?;
+        verifier.update(&signed_data)?;
+        if verifier.verify(&sct.signature).unwrap_or(false) {
+            debug!("Valid SCT of the log: {}", log.description);
+            if !report.logs.contains(&log.description) {
+                report.logs.push(log.description.to_owned());
+            }
+        } else {
+            report.problems.push(format!(
+                "Invalid SCT signature of the log: {}",
+                log.description
+            ));
+        }
+    }
+    Ok(report)
+}
+
+
+/// Splits the DER encoded certificate into its TBSCertificate without the SCT list
+/// extension (what the logs have signed, RFC 6962 section 3.2) and the embedded SCTs
+pub fn split_embedded_scts(
+    certificate_der: &[u8],
+) -> Result<(Vec<u8>, Vec<SignedCertificateTimestamp>)> {
+    let certificate = DerReader::new(certificate_der).expect(DER_SEQUENCE)?;
+    let tbs_certificate = DerReader::new(certificate.content).expect(DER_SEQUENCE)?;
+
+    let mut fields = DerReader::new(tbs_certificate.content);
+    let mut tbs_content = vec![];
+    let mut scts = vec![];
+    while !fields.is_empty() {
+        let field = fields.read()?;
+        if field.tag != DER_TBS_EXTENSIONS {
+            tbs_content.extend(field.raw);
+            continue;
+        }
+        let extensions = DerReader::new(field.content).expect(DER_SEQUENCE)?;
+        let mut extensions = DerReader::new(extensions.content);
+        let mut remaining_extensions = vec![];
+        while !extensions.is_empty() {
+            let extension = extensions.read()?;
+            let (oid, value) = extension_value(extension.raw)?;
+            if oid == OID_SCT_LIST {
+                // the extension value wraps the TLS encoded list in another OCTET STRING
+                let sct_list = DerReader::new(value).expect(DER_OCTET_STRING)?;
+                scts.extend(parse_sct_list(sct_list.content)?);
+            } else {
+                remaining_extensions.extend(extension.raw);
+            }
+        }
+        if !remaining_extensions.is_empty() {
+            tbs_content.extend(der_encode(
+                DER_TBS_EXTENSIONS,
+                &der_encode(DER_SEQUENCE, &remaining_extensions),
+            ));
+        }
+    }
+    Ok((der_encode(DER_SEQUENCE, &tbs_content), scts))
+}
+
+
+/// Parses the TLS encoded SignedCertificateTimestampList
+fn parse_sct_list(data: &[u8]) -> Result<Vec<SignedCertificateTimestamp>> {
+    let mut list = TlsReader::new(data);
+    let mut scts_data = TlsReader::new(list.read_vector(2)?);
+    let mut scts = vec![];
+    while !scts_data.is_empty() {
+        let mut sct = TlsReader::new(scts_data.read_vector(2)?);
+        let version = sct.read(1)?[0];
+        if version != SCT_VERSION_V1 {
+            debug!("Skipping the SCT of unknown version: {version}");
+            continue;
+        }
+        let log_id = sct.read(32)?.to_vec();
+        let timestamp = u64::from_be_bytes(sct.read(8)?.try_into()?);
+        let extensions = sct.read_vector(2)?.to_vec();
+        let hash_algorithm = sct.read(1)?[0];
+        let _signature_algorithm = sct.read(1)?[0];
+        let signature = sct.read_vector(2)?.to_vec();
+        scts.push(SignedCertificateTimestamp {
+            log_id,
+            timestamp,
+            extensions,
+            hash_algorithm,
+            signature,
+        });
+    }
+    Ok(scts)
+}
+
+
+/// Minimal reader of the TLS presentation language encoding
+struct TlsReader<'a> {
+    data: &'a [u8],
+}
+
+
+impl<'a> TlsReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        TlsReader {
+            data,
+        }
+    }
+
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+
+    fn read(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.data.len() < length {
+            return Err(anyhow!("Truncated SCT list"));
+        }
+        let (value, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(value)
+    }
+
+
+    /// Reads the variable-length vector prefixed with its length in given octets
+    fn read_vector(&mut self, length_octets: usize) -> Result<&'a [u8]> {
+        let length = self
+            .read(length_octets)?
+            .iter()
+            .fold(0usize, |acc, octet| (acc << 8) | *octet as usize);
+        self.read(length)
+    }
+}
+
+
+#[test]
+fn test_verify_embedded_scts() -> Result<()> {
+    use openssl::{
+        asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time},
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::Private,
+        sign::Signer,
+        x509::{X509Extension, X509NameBuilder, extension::SubjectAlternativeName},
+    };
+
+    let generate_key = || -> Result<PKey<Private>> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+    };
+    let issuer_key = generate_key()?;
+    let leaf_key = generate_key()?;
+    let log_key = generate_key()?;
+    let log = CtLog {
+        description: String::from("Test log"),
+        log_id: sha256(&log_key.public_key_to_der()?).to_vec(),
+        key: PKey::public_key_from_der(&log_key.public_key_to_der()?)?,
+    };
+
+    let mut issuer_name = X509NameBuilder::new()?;
+    issuer_name.append_entry_by_nid(Nid::COMMONNAME, "Test CA")?;
+    let issuer_name = issuer_name.build();
+    let mut issuer = X509::builder()?;
+    issuer.set_version(2)?;
+    issuer.set_subject_name(&issuer_name)?;
+    issuer.set_issuer_name(&issuer_name)?;
+    issuer.set_pubkey(&issuer_key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(1)?;
+    issuer.set_not_before(&not_before)?;
+    issuer.set_not_after(&not_after)?;
+    issuer.sign(&issuer_key, MessageDigest::sha256())?;
+    let issuer = issuer.build();
+
+    // the same leaf is issued twice: as the "precertificate" without and with the SCTs
+    let build_leaf = |sct_list: Option<&[u8]>| -> Result<X509> {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "the-domain.com")?;
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        let serial_number = BigNum::from_u32(7)?;
+        let serial_number = Asn1Integer::from_bn(&serial_number)?;
+        builder.set_serial_number(&serial_number)?;
+        builder.set_subject_name(&name.build())?;
+        builder.set_issuer_name(&issuer_name)?;
+        builder.set_pubkey(&leaf_key)?;
+        let not_before = Asn1Time::from_unix(1_700_000_000)?;
+        let not_after = Asn1Time::from_unix(1_707_776_000)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        let alt_name = SubjectAlternativeName::new()
+            .dns("the-domain.com")
+            .build(&builder.x509v3_context(Some(&issuer), None))?;
+        builder.append_extension(alt_name)?;
+        if let Some(sct_list) = sct_list {
+            let sct_list_oid = Asn1Object::from_str("1.3.6.1.4.1.11129.2.4.2")?;
+            let sct_list =
+                Asn1OctetString::new_from_bytes(&der_encode(DER_OCTET_STRING, sct_list))?;
+            builder.append_extension(X509Extension::new_from_der(
+                &sct_list_oid,
+                false,
+                &sct_list,
+            )?)?;
+        }
+        builder.sign(&issuer_key, MessageDigest::sha256())?;
+        Ok(builder.build())
+    };
+
+    let precertificate = build_leaf(None)?;
+    let (precertificate_tbs, _) = split_embedded_scts(&precertificate.to_der()?)?;
+    let timestamp: u64 = 1_700_000_000_000;
+    let mut signed_data = vec![SCT_VERSION_V1, SCT_SIGNATURE_TYPE_CERTIFICATE_TIMESTAMP];
+    signed_data.extend(timestamp.to_be_bytes());
+    signed_data.extend(SCT_ENTRY_TYPE_PRECERT.to_be_bytes());
+    signed_data.extend(sha256(&issuer_key.public_key_to_der()?));
+    signed_data.extend(&(precertificate_tbs.len() as u32).to_be_bytes()[1..]);
+    signed_data.extend(&precertificate_tbs);
+    signed_data.extend(0u16.to_be_bytes());
+    let mut signer = Signer::new(MessageDigest::sha256(), &log_key)?;
+    signer.update(&signed_data)?;
+    let signature = signer.sign_to_vec()?;
+
+    let mut sct = vec![SCT_VERSION_V1];
+    sct.extend(&log.log_id);
+    sct.extend(timestamp.to_be_bytes());
+    sct.extend(0u16.to_be_bytes());
+    sct.extend([SCT_HASH_ALGORITHM_SHA256, 3]);
+    sct.extend((signature.len() as u16).to_be_bytes());
+    sct.extend(&signature);
+    let mut scts = (sct.len() as u16).to_be_bytes().to_vec();
+    scts.extend(&sct);
+    let mut sct_list = (scts.len() as u16).to_be_bytes().to_vec();
+    sct_list.extend(&scts);
+
+    let certificate = build_leaf(Some(&sct_list))?;
+    let (certificate_tbs, embedded_scts) = split_embedded_scts(&certificate.to_der()?)?;
+    assert_eq!(certificate_tbs, precertificate_tbs);
+    assert_eq!(embedded_scts.len(), 1);
+    assert_eq!(embedded_scts[0].timestamp, timestamp);
+
+    let mut chain_pem = certificate.to_pem()?;
+    chain_pem.extend(issuer.to_pem()?);
+    let report = verify_embedded_scts(&chain_pem, std::slice::from_ref(&log))?;
+    assert_eq!(report.logs, ["Test log"]);
+    assert!(report.problems.is_empty());
+    let policy = CtPolicy {
+        log_list: String::new(),
+        min_distinct_logs: 2,
+    };
+    assert!(!report.is_compliant(&policy));
+
+    let impostor_log = CtLog {
+        key: PKey::public_key_from_der(&generate_key()?.public_key_to_der()?)?,
+        ..log
+    };
+    let report = verify_embedded_scts(&chain_pem, &[impostor_log])?;
+    assert!(report.logs.is_empty());
+    assert_eq!(report.problems.len(), 1);
+    assert!(verify_embedded_scts(&chain_pem, &[]).is_ok_and(|report| report.logs.is_empty()));
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use crate::*;
+
+use serde::Deserialize;
+
+
+/// Where to read a secret (password, token) from, so it doesn't live in the configuration
+#[derive(Debug, Clone, Deserialize)]
+pub enum SecretSource {
+    /// The file content, without the trailing newline
+    File(String),
+
+    /// The environment variable value
+    Env(String),
+
+    /// The standard output of the command, without the trailing newline,
+    /// like: Command(["pass", "show", "cloudflare/api-token"])
+    Command(Vec<String>),
+}
+
+
+impl SecretSource {
+    /// The path of the secret file, None for the other sources
+    pub fn file_mut(&mut self) -> Option<&mut String> {
+        match self {
+            SecretSource::File(file) => Some(file),
+            _ => None,
+        }
+    }
+
+
+    #[instrument(skip(self))]
+    pub async fn resolve(&self) -> Result<String> {
+        match self {
+            SecretSource::File(file) => {
+                let secret = tokio::fs::read_to_string(file)
+                    .await
+                    .map_err(|err| anyhow!("Couldn't read the secret file: {file}: {err}"))?;
+                Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+            }
+            SecretSource::Env(variable) => {
+                std::env::var(variable).map_err(|err| {
+                    anyhow!("Couldn't read the secret environment variable: {variable}: {err}")
+                })
+            }
+            SecretSource::Command(command) => {
+                let Some((program, args)) = command.split_first() else {
+                    return Err(anyhow!("The secret command is empty"));
+                };
+                let output = tokio::process::Command::new(program)
+                    .args(args)
+                    .stdin(std::process::Stdio::null())
+                    .kill_on_drop(true)
+                    .output()
+                    .await
+                    .map_err(|err| {
+                        anyhow!("Couldn't run the secret command: {program}: {err}")
+                    })?;
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "The secret command: {program} failed with: {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+                Ok(String::from_utf8(output.stdout)?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string())
+            }
+        }
+    }
+}
+
+
+#[tokio::test]
+async fn test_resolve_secret() -> Result<()> {
+    let secret_file =
+        std::env::temp_dir().join(format!("certsd-secret-{}", std::process::id()));
+    tokio::fs::write(&secret_file, "the-password\n").await?;
+    let secret = SecretSource::File(secret_file.to_string_lossy().to_string())
+        .resolve()
+        .await;
+    tokio::fs::remove_file(&secret_file).await?;
+    assert_eq!(secret?, "the-password");
+
+    assert_eq!(
+        SecretSource::Env(String::from("PATH")).resolve().await?,
+        std::env::var("PATH")?
+    );
+    assert!(
+        SecretSource::Env(String::from("CERTSD_UNDEFINED_SECRET"))
+            .resolve()
+            .await
+            .is_err()
+    );
+
+    let command = |command: &[&str]| {
+        SecretSource::Command(command.iter().map(|arg| arg.to_string()).collect())
+    };
+    assert_eq!(
+        command(&["printf", "the-token\n"]).resolve().await?,
+        "the-token"
+    );
+    assert!(
+        command(&["sh", "-c", "echo no such secret >&2; exit 1"])
+            .resolve()
+            .await
+            .err()
+            .is_some_and(|err| err.to_string().ends_with("exit status: 1: no such secret"))
+    );
+    assert!(command(&[]).resolve().await.is_err());
+    Ok(())
+}
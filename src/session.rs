@@ -0,0 +1,378 @@
+use crate::*;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hyperacme::{
+    Directory,
+    api::{ApiAuth, ApiDirectory, ApiIdentifier, ApiOrder, ApiProblem},
+};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Private},
+    sha::sha256,
+    sign::Signer,
+};
+use serde_json::{Value, json};
+use tokio::{
+    sync::Mutex,
+    time::{Duration, sleep},
+};
+
+
+/// Size of the P-256 curve coordinates and signature components
+const P256_FIELD_SIZE: i32 = 32;
+
+
+/// Signed (JWS) session with the ACME API. Covers the parts of RFC 8555 the hyperacme
+/// can't express: finalizing orders with a CSR we didn't create, non-DNS identifiers
+/// and the RSA account keys (RS256, e.g. imported from certbot) next to the EC P-256 ones.
+#[derive(Debug)]
+pub struct AcmeSession {
+    client: reqwest::Client,
+    directory: ApiDirectory,
+    key: PKey<Private>,
+    kid: String,
+    nonce: Mutex<Option<String>>,
+}
+
+
+#[derive(Debug)]
+struct AcmeResponse {
+    location: Option<String>,
+    body: String,
+}
+
+
+impl AcmeSession {
+    /// Opens a session for the account of the key (EC P-256 or RSA), registering
+    /// the account with the contacts if the ACME provider doesn't know it yet
+    #[instrument(skip(directory, account_key_pem))]
+    pub async fn new(
+        directory: &Directory,
+        account_key_pem: &str,
+        contacts: &[String],
+    ) -> Result<Self> {
+        let key = PKey::private_key_from_pem(account_key_pem.as_bytes())?;
+        match key.id() {
+            Id::EC if key.ec_key()?.group().curve_name() == Some(Nid::X9_62_PRIME256V1) => {}
+            Id::RSA => {}
+            _ => {
+                return Err(anyhow!(
+                    "Unsupported account key type: {:?}. Only the EC P-256 and RSA account keys are supported.",
+                    key.id()
+                ));
+            }
+        }
+        let mut session = AcmeSession {
+            client: reqwest::Client::new(),
+            directory: directory.api_directory().to_owned(),
+            key,
+            kid: String::new(),
+            nonce: Mutex::new(None),
+        };
+        // returns the already registered account (RFC 8555 section 7.3.1)
+        let new_account_url = session.directory.newAccount.to_owned();
+        let response = session
+            .post(
+                &new_account_url,
+                Some(&json!({"termsOfServiceAgreed": true, "contact": contacts})),
+            )
+            .await?;
+        session.kid = response
+            .location
+            .ok_or_else(|| anyhow!("ACME API didn't return the account URL"))?;
+        debug!("ACME account URL: {}", session.kid);
+        Ok(session)
+    }
+
+
+    /// The key authorization for the challenge token (RFC 8555 section 8.1)
+    pub fn key_authorization(&self, token: &str) -> Result<String> {
+        // the members of the JWK are already in the lexicographic order of RFC 7638
+        let thumbprint = URL_SAFE_NO_PAD.encode(sha256(self.jwk()?.to_string().as_bytes()));
+        Ok(format!("{token}.{thumbprint}"))
+    }
+
+
+    /// The TXT record content of the dns-01 challenge token (RFC 8555 section 8.4)
+    pub fn dns_proof(&self, token: &str) -> Result<String> {
+        let key_authorization = self.key_authorization(token)?;
+        Ok(URL_SAFE_NO_PAD.encode(sha256(key_authorization.as_bytes())))
+    }
+
+
+    /// Creates a new order (of the ACME profile, if set) and returns its URL and the order object
+    #[instrument(skip(self))]
+    pub async fn new_order(
+        &self,
+        identifiers: &[ApiIdentifier],
+        profile: Option<&str>,
+    ) -> Result<(String, ApiOrder)> {
+        let mut payload = json!({ "identifiers": identifiers });
+        if let Some(profile) = profile {
+            payload["profile"] = json!(profile);
+        }
+        let response = self.post(&self.directory.newOrder, Some(&payload)).await?;
+        let order_url = response
+            .location
+            .ok_or_else(|| anyhow!("ACME API didn't return the order URL"))?;
+        Ok((order_url, serde_json::from_str(&response.body)?))
+    }
+
+
+    #[instrument(skip(self))]
+    pub async fn order(&self, order_url: &str) -> Result<ApiOrder> {
+        let response = self.post(order_url, None).await?;
+        Ok(serde_json::from_str(&response.body)?)
+    }
+
+
+    #[instrument(skip(self))]
+    pub async fn authorization(&self, authorization_url: &str) -> Result<ApiAuth> {
+        let response = self.post(authorization_url, None).await?;
+        Ok(serde_json::from_str(&response.body)?)
+    }
+
+
+    /// Tells the ACME API the challenge is ready to be validated
+    #[instrument(skip(self))]
+    pub async fn respond_to_challenge(&self, challenge_url: &str) -> Result<()> {
+        self.post(challenge_url, Some(&json!({}))).await?;
+        Ok(())
+    }
+
+
+    /// Polls the authorization until the ACME provider decides about it
+    #[instrument(skip(self))]
+    pub async fn await_authorization(&self, authorization_url: &str) -> Result<()> {
+        for _ in 0..DEFAULT_MAX_ATTEMPTS {
+            let authorization = self.authorization(authorization_url).await?;
+            if authorization.is_status_valid() {
+                info!(
+                    "Authorization validated: {}",
+                    authorization.identifier.value
+                );
+                return Ok(());
+            }
+            if !authorization.is_status_pending() {
+                let problem = authorization
+                    .challenges
+                    .iter()
+                    .find_map(|challenge| challenge.error.to_owned())
+                    .and_then(|problem| problem.detail);
+                return Err(anyhow!(
+                    "Authorization of: {} failed with status: {:?}. Problem: {problem:?}",
+                    authorization.identifier.value,
+                    authorization.status
+                ));
+            }
+            info!("Awaiting the authorization");
+            sleep(Duration::from_millis(DEFAULT_ACME_POLL_PAUSE_MS)).await;
+        }
+        Err(anyhow!(
+            "The authorization: {authorization_url} wasn't validated within the {DEFAULT_MAX_ATTEMPTS} max attempts."
+        ))
+    }
+
+
+    /// Polls the order until all its authorizations are valid
+    #[instrument(skip(self))]
+    pub async fn await_order_ready(&self, order_url: &str) -> Result<ApiOrder> {
+        for _ in 0..DEFAULT_MAX_ATTEMPTS {
+            let order = self.order(order_url).await?;
+            if order.is_status_ready() {
+                return Ok(order);
+            }
+            if order.is_status_invalid() {
+                return Err(anyhow!(
+                    "The order: {order_url} is invalid. Problem: {:?}",
+                    order.error
+                ));
+            }
+            info!("Awaiting the order");
+            sleep(Duration::from_millis(DEFAULT_ACME_POLL_PAUSE_MS)).await;
+        }
+        Err(anyhow!(
+            "The order: {order_url} wasn't ready within the {DEFAULT_MAX_ATTEMPTS} max attempts."
+        ))
+    }
+
+
+    /// Submits the DER encoded CSR for the ready order, awaits the issuance
+    /// and returns the PEM encoded certificate chain
+    #[instrument(skip(self, order, csr_der))]
+    pub async fn finalize(
+        &self,
+        order_url: &str,
+        order: &ApiOrder,
+        csr_der: &[u8],
+    ) -> Result<String> {
+        if !order.is_status_ready() {
+            return Err(anyhow!(
+                "The order: {order_url} isn't ready for finalization. Status: {:?}",
+                order.status
+            ));
+        }
+        self.post(
+            &order.finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr_der) })),
+        )
+        .await?;
+
+        for _ in 0..DEFAULT_MAX_ATTEMPTS {
+            let order = self.order(order_url).await?;
+            if order.is_status_invalid() {
+                return Err(anyhow!(
+                    "The order: {order_url} is invalid. Problem: {:?}",
+                    order.error
+                ));
+            }
+            if let (true, Some(certificate_url)) = (order.is_status_valid(), order.certificate)
+            {
+                return Ok(self.post(&certificate_url, None).await?.body);
+            }
+            info!("Awaiting the certificate issuance");
+            sleep(Duration::from_millis(DEFAULT_ACME_POLL_PAUSE_MS)).await;
+        }
+        Err(anyhow!(
+            "The order: {order_url} wasn't issued within the {DEFAULT_MAX_ATTEMPTS} max attempts."
+        ))
+    }
+
+
+    /// Sends the JWS signed POST (or POST-as-GET if no payload) request
+    async fn post(&self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse> {
+        for _ in 0..DEFAULT_MAX_ATTEMPTS {
+            let mut protected = json!({
+                "alg": self.algorithm(),
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            if self.kid.is_empty() {
+                protected["jwk"] = self.jwk()?;
+            } else {
+                protected["kid"] = json!(self.kid);
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let payload = payload
+                .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+                .unwrap_or_default();
+            let signature = self.sign(format!("{protected}.{payload}").as_bytes())?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": signature,
+            });
+
+            debug!("Calling ACME endpoint: {url}");
+            let response = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            if let Some(nonce) = response.headers().get("Replay-Nonce") {
+                *self.nonce.lock().await = Some(nonce.to_str()?.to_string());
+            }
+            let location = response
+                .headers()
+                .get("Location")
+                .and_then(|location| location.to_str().ok())
+                .map(String::from);
+            let status = response.status();
+            let body = response.text().await?;
+            if status.is_success() {
+                return Ok(AcmeResponse {
+                    location,
+                    body,
+                });
+            }
+
+            let problem = serde_json::from_str::<ApiProblem>(&body).unwrap_or_default();
+            if problem.is_bad_nonce() {
+                debug!("Retrying on bad nonce");
+                continue;
+            }
+            return Err(anyhow!(
+                "ACME request to: {url} failed with status: {status}. Problem: {}",
+                problem.detail.unwrap_or(body)
+            ));
+        }
+        Err(anyhow!(
+            "ACME request to: {url} failed within the {DEFAULT_MAX_ATTEMPTS} max attempts."
+        ))
+    }
+
+
+    async fn nonce(&self) -> Result<String> {
+        if let Some(nonce) = self.nonce.lock().await.take() {
+            return Ok(nonce);
+        }
+        let response = self.client.head(&self.directory.newNonce).send().await?;
+        Ok(response
+            .headers()
+            .get("Replay-Nonce")
+            .ok_or_else(|| anyhow!("ACME API didn't return a nonce"))?
+            .to_str()?
+            .to_string())
+    }
+
+
+    /// The JWS algorithm of the account key
+    fn algorithm(&self) -> &'static str {
+        match self.key.id() {
+            Id::RSA => "RS256",
+            _ => "ES256",
+        }
+    }
+
+
+    fn sign(&self, data: &[u8]) -> Result<String> {
+        let signature_bytes = match self.key.id() {
+            Id::RSA => {
+                let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+                signer.update(data)?;
+                signer.sign_to_vec()?
+            }
+            _ => {
+                let signature = EcdsaSig::sign(&sha256(data), self.key.ec_key()?.as_ref())?;
+                let mut signature_bytes = signature.r().to_vec_padded(P256_FIELD_SIZE)?;
+                signature_bytes.extend(signature.s().to_vec_padded(P256_FIELD_SIZE)?);
+                signature_bytes
+            }
+        };
+        Ok(URL_SAFE_NO_PAD.encode(signature_bytes))
+    }
+
+
+    /// The public JWK of the account key, its members in the lexicographic order
+    fn jwk(&self) -> Result<Value> {
+        if self.key.id() == Id::RSA {
+            let rsa = self.key.rsa()?;
+            return Ok(json!({
+                "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+                "kty": "RSA",
+                "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            }));
+        }
+        let ec_key = self.key.ec_key()?;
+        let mut context = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec_key.public_key().affine_coordinates(
+            ec_key.group(),
+            &mut x,
+            &mut y,
+            &mut context,
+        )?;
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(x.to_vec_padded(P256_FIELD_SIZE)?),
+            "y": URL_SAFE_NO_PAD.encode(y.to_vec_padded(P256_FIELD_SIZE)?),
+        }))
+    }
+}
@@ -0,0 +1,377 @@
+use crate::*;
+
+use chrono::{Months, prelude::*};
+use openssl::{
+    pkey::{Id, PKey, Public},
+    x509::{X509, X509NameRef},
+};
+use serde::Serialize;
+use std::{net::IpAddr, path::Path};
+
+
+/// State of a single managed certificate
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateStatus {
+    /// The certificate name: domain, *.domain or IP address
+    pub name: String,
+    pub certificate_file: String,
+    pub details: Option<CertificateDetails>,
+
+    /// Why the details couldn't be read (e.g. the certificate wasn't issued yet)
+    pub error: Option<String>,
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateDetails {
+    pub subject: String,
+    pub names: Vec<String>,
+    pub issuer: String,
+    pub serial: String,
+    pub key_type: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub days_remaining: i64,
+
+    /// Whether the certificate matches the domain.key (None if no domain.key is present)
+    pub key_matches: Option<bool>,
+    pub renewal_due: String,
+
+    /// Distinct CT logs with a valid embedded SCT (None if the CT policy isn't configured)
+    pub ct_logs: Option<usize>,
+    pub ct_compliant: Option<bool>,
+}
+
+
+/// Reads the status of all certificates managed with the configuration
+#[instrument(skip(config))]
+pub async fn certificates_status(config: &Config) -> Vec<CertificateStatus> {
+    let mut certificates = vec![];
+    for domain in config.domains().await {
+        if config.csr_of(&domain).await.is_none() && config.manages_wildcard(&domain).await {
+            certificates.push((format!("*.{domain}"), domain_dir_of(&domain, true)));
+        }
+        if config.manages_apex(&domain).await {
+            certificates.push((domain.to_owned(), domain_dir_of(&domain, false)));
+        }
+    }
+    for ip_account in config.ip_addresses().await {
+        certificates.push((ip_account.ip.to_owned(), ip_account.ip.to_owned()));
+    }
+
+    let ct_policy = config.certificate_transparency().await;
+    let ct_logs = match &ct_policy {
+        Some(policy) => {
+            load_ct_logs(&policy.log_list).await.unwrap_or_else(|err| {
+                error!("Couldn't load the CT log list: {}: {err}", policy.log_list);
+                vec![]
+            })
+        }
+        None => vec![],
+    };
+
+    let mut statuses = vec![];
+    for (name, domain_dir) in certificates {
+        let certificate_file = format!("{domain_dir}/chained.pem");
+        let (details, error) = match read_certificate_details(&domain_dir).await {
+            Ok(mut details) => {
+                if let Some(policy) = &ct_policy {
+                    let report = tokio::fs::read(&certificate_file)
+                        .await
+                        .map_err(Into::into)
+                        .and_then(|chain_pem| verify_embedded_scts(&chain_pem, &ct_logs))
+                        .unwrap_or_default();
+                    details.ct_logs = Some(report.logs.len());
+                    details.ct_compliant = Some(report.is_compliant(policy));
+                }
+                (Some(details), None)
+            }
+            Err(err) => (None, Some(err.to_string())),
+        };
+        statuses.push(CertificateStatus {
+            name,
+            certificate_file,
+            details,
+            error,
+        });
+    }
+    statuses
+}
+
+
+/// Reads the details of the chained.pem (and checks it against the domain.key) in given dir
+#[instrument]
+pub async fn read_certificate_details(domain_dir: &str) -> Result<CertificateDetails> {
+    let certificate_file = format!("{domain_dir}/chained.pem");
+    if !Path::new(&certificate_file).exists() {
+        return Err(anyhow!("No certificate issued yet: {certificate_file}"));
+    }
+    let certificate = X509::from_pem(&tokio::fs::read(&certificate_file).await?)?;
+    let public_key = certificate.public_key()?;
+
+    let domain_key_file = format!("{domain_dir}/domain.key");
+    let key_matches = if Path::new(&domain_key_file).exists() {
+        let domain_key =
+            PKey::private_key_from_pem(&tokio::fs::read(&domain_key_file).await?)?;
+        Some(domain_key.public_eq(&public_key))
+    } else {
+        None
+    };
+
+    let not_before = parse_asn1_time(&certificate.not_before().to_string())?;
+    let not_after = parse_asn1_time(&certificate.not_after().to_string())?;
+    let renewal_due = renewal_due_of(not_before, not_after);
+    Ok(CertificateDetails {
+        subject: format_x509_name(certificate.subject_name())?,
+        names: certificate_names(&certificate),
+        issuer: format_x509_name(certificate.issuer_name())?,
+        serial: certificate
+            .serial_number()
+            .to_bn()?
+            .to_hex_str()?
+            .to_string(),
+        key_type: key_type(&public_key),
+        not_before: not_before.to_rfc3339(),
+        not_after: not_after.to_rfc3339(),
+        days_remaining: (not_after - Utc::now()).num_days(),
+        key_matches,
+        renewal_due: renewal_due.to_rfc3339(),
+        ct_logs: None,
+        ct_compliant: None,
+    })
+}
+
+
+/// When the certificate is due for the renewal: DEFAULT_MAX_CERT_VALIDITY_IN_MONTHS before
+/// its expiry, or when a fraction of its lifetime remains if it lives shorter than that
+pub fn renewal_due_of(not_before: DateTime<Utc>, not_after: DateTime<Utc>) -> DateTime<Utc> {
+    let renewal_due = not_after - Months::new(DEFAULT_MAX_CERT_VALIDITY_IN_MONTHS);
+    if renewal_due > not_before {
+        renewal_due
+    } else {
+        not_after - (not_after - not_before) / DEFAULT_SHORT_LIVED_CERT_RENEWAL_FRACTION
+    }
+}
+
+
+/// The DNS names and IP addresses from the subjectAltName extension
+pub fn certificate_names(certificate: &X509) -> Vec<String> {
+    certificate
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| {
+                    match (name.dnsname(), name.ipaddress()) {
+                        (Some(dns_name), _) => Some(dns_name.to_string()),
+                        (_, Some(ip)) => ip_from_bytes(ip).map(|ip| ip.to_string()),
+                        _ => None,
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+
+fn ip_from_bytes(ip: &[u8]) -> Option<IpAddr> {
+    match ip.len() {
+        4 => <[u8; 4]>::try_from(ip).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(ip).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+
+/// Formats the X.509 name as "CN=example.com, O=Example"
+pub fn format_x509_name(name: &X509NameRef) -> Result<String> {
+    let fields = name
+        .entries()
+        .map(|entry| {
+            let field = entry.object().nid().short_name().unwrap_or("?");
+            let value = std::str::from_utf8(entry.data().as_slice()).map_err(|_| {
+                anyhow!("Invalid UTF-8 value of the X.509 name field: {field}")
+            })?;
+            Ok(format!("{field}={value}"))
+        })
+        .collect::<Result<Vec<String>>>()?;
+    Ok(fields.join(", "))
+}
+
+
+fn key_type(public_key: &PKey<Public>) -> String {
+    match public_key.id() {
+        Id::EC => {
+            let curve = public_key
+                .ec_key()
+                .ok()
+                .and_then(|key| key.group().curve_name())
+                .and_then(|nid| nid.short_name().ok())
+                .unwrap_or("unknown curve");
+            format!("EC {curve}")
+        }
+        Id::RSA => format!("RSA {}", public_key.bits()),
+        Id::ED25519 => String::from("Ed25519"),
+        _ => format!("{} bits", public_key.bits()),
+    }
+}
+
+
+/// Renders the statuses as a human-readable table
+pub fn render_status_table(statuses: &[CertificateStatus]) -> String {
+    let header = [
+        "NAME",
+        "SANS",
+        "SUBJECT",
+        "ISSUER",
+        "SERIAL",
+        "KEY",
+        "NOT BEFORE",
+        "NOT AFTER",
+        "DAYS LEFT",
+        "KEY MATCH",
+        "RENEWAL DUE",
+        "CT LOGS",
+    ]
+    .map(String::from);
+    let date = |time: &str| time.get(..10).unwrap_or(time).to_string();
+    let rows = statuses
+        .iter()
+        .map(|status| {
+            match &status.details {
+                Some(details) => {
+                    [
+                        status.name.to_owned(),
+                        details.names.join(","),
+                        details.subject.to_owned(),
+                        details.issuer.to_owned(),
+                        details.serial.to_owned(),
+                        details.key_type.to_owned(),
+                        date(&details.not_before),
+                        date(&details.not_after),
+                        details.days_remaining.to_string(),
+                        match details.key_matches {
+                            Some(true) => String::from("yes"),
+                            Some(false) => String::from("NO"),
+                            None => String::from("-"),
+                        },
+                        date(&details.renewal_due),
+                        match (details.ct_logs, details.ct_compliant) {
+                            (Some(logs), Some(true)) => format!("{logs}"),
+                            (Some(logs), _) => format!("{logs} NOT COMPLIANT"),
+                            _ => String::from("-"),
+                        },
+                    ]
+                }
+                None => {
+                    let mut row = [(); 12].map(|_| String::from("-"));
+                    row[0] = status.name.to_owned();
+                    row[1] = status.error.to_owned().unwrap_or_default();
+                    row
+                }
+            }
+        })
+        .collect::<Vec<[String; 12]>>();
+
+    let mut widths = header.clone().map(|column| column.len());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    std::iter::once(&header)
+        .chain(rows.iter())
+        .map(|row| {
+            row.iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+
+#[tokio::test]
+async fn test_certificates_status() -> Result<()> {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        x509::{X509NameBuilder, extension::SubjectAlternativeName},
+    };
+
+    let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+    let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "the-domain.com")?;
+    let name = name.build();
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&pkey)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(90)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let alt_name = SubjectAlternativeName::new()
+        .dns("the-domain.com")
+        .ip("203.0.113.7")
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(alt_name)?;
+    builder.sign(&pkey, MessageDigest::sha256())?;
+
+    let domain_dir =
+        std::env::temp_dir().join(format!("certsd-status-{}", std::process::id()));
+    tokio::fs::create_dir_all(&domain_dir).await?;
+    tokio::fs::write(domain_dir.join("chained.pem"), builder.build().to_pem()?).await?;
+    tokio::fs::write(
+        domain_dir.join("domain.key"),
+        pkey.private_key_to_pem_pkcs8()?,
+    )
+    .await?;
+    let details = read_certificate_details(&domain_dir.to_string_lossy()).await;
+    tokio::fs::remove_dir_all(&domain_dir).await?;
+    let details = details?;
+
+    assert_eq!(details.subject, "CN=the-domain.com");
+    assert_eq!(details.names, ["the-domain.com", "203.0.113.7"]);
+    assert_eq!(details.key_type, "EC secp384r1");
+    assert_eq!(details.key_matches, Some(true));
+    assert!((88..=90).contains(&details.days_remaining));
+
+    let table = render_status_table(&[
+        CertificateStatus {
+            name: String::from("the-domain.com"),
+            certificate_file: String::from("the-domain.com/chained.pem"),
+            details: Some(details),
+            error: None,
+        },
+        CertificateStatus {
+            name: String::from("*.the-domain.com"),
+            certificate_file: String::from("wild_the-domain.com/chained.pem"),
+            details: None,
+            error: Some(String::from("No certificate issued yet")),
+        },
+    ]);
+    assert_eq!(table.lines().count(), 3);
+    assert!(table.starts_with("NAME "));
+
+    // the 90 days certificates are renewed 2 months before the expiry,
+    // the 6 days (short-lived IP) ones when 2 days remain
+    let not_before = Utc.with_ymd_and_hms(2026, 10, 1, 0, 0, 0).unwrap();
+    assert_eq!(
+        renewal_due_of(not_before, not_before + chrono::TimeDelta::days(90)),
+        Utc.with_ymd_and_hms(2026, 10, 30, 0, 0, 0).unwrap()
+    );
+    assert_eq!(
+        renewal_due_of(not_before, not_before + chrono::TimeDelta::days(6)),
+        Utc.with_ymd_and_hms(2026, 10, 5, 0, 0, 0).unwrap()
+    );
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use crate::*;
+
+use hyperacme::Error;
+
+
+/// What the run did with the certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renewal {
+    /// A new certificate was issued and stored
+    Renewed,
+
+    /// The current certificate is valid long enough
+    Skipped,
+}
+
+
+/// Outcome of the whole run, printed when it finishes
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    pub renewed: Vec<String>,
+    pub skipped: Vec<String>,
+
+    /// The certificate names with the errors
+    pub failed: Vec<(String, String)>,
+
+    /// The domains with the endpoints still serving an outdated certificate
+    pub stale_endpoints: Vec<(String, Vec<String>)>,
+}
+
+
+impl RunSummary {
+    /// Records the renewal result of the certificate name: domain, *.domain or IP address
+    pub fn record(&mut self, name: &str, result: Result<Renewal, Error>) {
+        match result {
+            Ok(Renewal::Renewed) => self.renewed.push(name.to_string()),
+            Ok(Renewal::Skipped) => self.skipped.push(name.to_string()),
+            Err(Error::GeneralError(err) | Error::LetsEncryptError(err)) => {
+                self.failed.push((name.to_string(), err))
+            }
+            // the hyperacme errors aren't Display
+            Err(err) => self.failed.push((name.to_string(), format!("{err:?}"))),
+        }
+    }
+
+
+    /// Names of the certificates that failed to renew
+    pub fn failed_names(&self) -> Vec<String> {
+        self.failed
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+
+    /// Renders the summary as the plain text lines, with the errors and the stale
+    /// endpoints listed under their sections
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "Renewed ({}): {}",
+                self.renewed.len(),
+                self.renewed.join(", ")
+            ),
+            format!(
+                "Skipped ({}): {}",
+                self.skipped.len(),
+                self.skipped.join(", ")
+            ),
+            format!("Failed ({}):", self.failed.len()),
+        ];
+        for (name, err) in self.failed.iter() {
+            lines.push(format!("  {name}: {err}"));
+        }
+        lines.push(format!("Stale endpoints ({}):", self.stale_endpoints.len()));
+        for (domain, endpoints) in self.stale_endpoints.iter() {
+            lines.push(format!("  {domain}: {}", endpoints.join(", ")));
+        }
+        lines
+            .into_iter()
+            .map(|line| line.trim_end().to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+
+#[test]
+fn test_run_summary() {
+    let mut summary = RunSummary::default();
+    summary.record("the-domain.com", Ok(Renewal::Renewed));
+    summary.record("*.the-domain.com", Ok(Renewal::Skipped));
+    summary.record(
+        "203.0.113.7",
+        Err(Error::GeneralError(String::from(
+            "Reached max retry attempts: 3",
+        ))),
+    );
+    summary.stale_endpoints.push((
+        String::from("the-domain.com"),
+        vec![
+            String::from("10.0.0.1:443"),
+            String::from("the-domain.com:8443"),
+        ],
+    ));
+
+    assert_eq!(summary.failed_names(), ["203.0.113.7"]);
+    assert_eq!(
+        summary.render(),
+        "Renewed (1): the-domain.com\n\
+         Skipped (1): *.the-domain.com\n\
+         Failed (1):\n  \
+         203.0.113.7: Reached max retry attempts: 3\n\
+         Stale endpoints (1):\n  \
+         the-domain.com: 10.0.0.1:443, the-domain.com:8443"
+    );
+    assert_eq!(RunSummary::default().render().lines().count(), 4);
+}